@@ -0,0 +1,214 @@
+// Interpreter benchmarks, run with `cargo bench`
+
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use lua51_vm::{bytecode::LuaPrototype, lua_function, types::{LuaResult, function::LuaFunctionArgs, table::LuaTable, value::LuaValue}, vm::VirtualMachine};
+
+const ITERATIONS: f64 = 1_000_000.0;
+const ARRAY_SIZE: f64 = 10_000.0;
+const SORT_SIZE: usize = 200;
+
+fn abc(op: u32, a: u32, b: u32, c: u32) -> u32 {
+    op | a << 6 | c << 14 | b << 23
+}
+
+fn abx(op: u32, a: u32, bx: u32) -> u32 {
+    op | a << 6 | bx << 14
+}
+
+fn asbx(op: u32, a: u32, sbx: i32) -> u32 {
+    op | a << 6 | ((sbx + 131071) as u32) << 14
+}
+
+// local s = 0; for i = 1, N do s = s + i * 2 end; return s
+fn numeric_loop() -> LuaPrototype {
+    LuaPrototype::builder()
+        .max_stack_size(6)
+        .constant(0.0)
+        .constant(1.0)
+        .constant(ITERATIONS)
+        .constant(2.0)
+        .instruction(abx(1, 0, 0))           // LOADK s 0
+        .instruction(abx(1, 1, 1))           // LOADK init 1
+        .instruction(abx(1, 2, 2))           // LOADK limit N
+        .instruction(abx(1, 3, 1))           // LOADK step 1
+        .instruction(asbx(32, 1, 2))         // FORPREP
+        .instruction(abc(14, 5, 4, 256 + 3)) // MUL t i 2
+        .instruction(abc(12, 0, 0, 5))       // ADD s s t
+        .instruction(asbx(31, 1, -3))        // FORLOOP
+        .instruction(abc(30, 0, 2, 0))       // RETURN s
+        .build()
+        .expect("benchmark chunk is valid")
+}
+
+// local t = {}; for i = 1, N do t[i] = i end
+// local s = 0; for _, v in ipairs(t) do s = s + v end; return s, #t
+fn array_ipairs() -> LuaPrototype {
+    LuaPrototype::builder()
+        .max_stack_size(8)
+        .constant(1.0)
+        .constant(ARRAY_SIZE)
+        .constant(0.0)
+        .constant("ipairs")
+        .instruction(abc(10, 0, 0, 0))      // NEWTABLE t
+        .instruction(abx(1, 1, 0))          // LOADK init 1
+        .instruction(abx(1, 2, 1))          // LOADK limit N
+        .instruction(abx(1, 3, 0))          // LOADK step 1
+        .instruction(asbx(32, 1, 1))        // FORPREP
+        .instruction(abc(9, 0, 4, 4))       // SETTABLE t i i
+        .instruction(asbx(31, 1, -2))       // FORLOOP
+        .instruction(abx(1, 1, 2))          // LOADK s 0
+        .instruction(abx(5, 2, 3))          // GETGLOBAL ipairs
+        .instruction(abc(0, 3, 0, 0))       // MOVE t
+        .instruction(abc(28, 2, 2, 4))      // CALL ipairs(t)
+        .instruction(asbx(22, 0, 1))        // JMP
+        .instruction(abc(12, 1, 1, 6))      // ADD s s v
+        .instruction(abc(33, 2, 0, 2))      // TFORLOOP
+        .instruction(asbx(22, 0, -3))       // JMP
+        .instruction(abc(20, 2, 0, 0))      // LEN #t
+        .instruction(abc(30, 1, 3, 0))      // RETURN s, #t
+        .build()
+        .expect("benchmark chunk is valid")
+}
+
+// local function f(x) return x + 1 end
+// local s = 0; for i = 1, N do s = f(s) end; return s
+fn call_loop() -> LuaPrototype {
+    let f = LuaPrototype::builder()
+        .param_count(1)
+        .max_stack_size(2)
+        .constant(1.0)
+        .instruction(abc(12, 1, 0, 256))    // ADD r x 1
+        .instruction(abc(30, 1, 2, 0))      // RETURN r
+        .build()
+        .expect("benchmark chunk is valid");
+
+    LuaPrototype::builder()
+        .max_stack_size(8)
+        .prototype(f)
+        .constant(0.0)
+        .constant(1.0)
+        .constant(ITERATIONS)
+        .instruction(abx(36, 0, 0))         // CLOSURE f
+        .instruction(abx(1, 1, 0))          // LOADK s 0
+        .instruction(abx(1, 2, 1))          // LOADK init 1
+        .instruction(abx(1, 3, 2))          // LOADK limit N
+        .instruction(abx(1, 4, 1))          // LOADK step 1
+        .instruction(asbx(32, 2, 4))        // FORPREP
+        .instruction(abc(0, 6, 0, 0))       // MOVE f
+        .instruction(abc(0, 7, 1, 0))       // MOVE s
+        .instruction(abc(28, 6, 2, 2))      // CALL f(s)
+        .instruction(abc(0, 1, 6, 0))       // MOVE s
+        .instruction(asbx(31, 2, -5))       // FORLOOP
+        .instruction(abc(30, 1, 2, 0))      // RETURN s
+        .build()
+        .expect("benchmark chunk is valid")
+}
+
+fn vm() -> VirtualMachine {
+    let mut vm = VirtualMachine::new();
+    vm.load_std_libraries().expect("standard libraries load");
+    vm
+}
+
+// Runs the chunk once and returns its results, which must all be numbers
+fn run(vm: &mut VirtualMachine, function: &LuaPrototype) -> Vec<f64> {
+    let values = vm.execute(function.clone(), None, None).inner.expect("benchmark chunk runs");
+    values.iter().map(|v| *v.borrow().as_f64().expect("number result")).collect()
+}
+
+fn numeric(c: &mut Criterion) {
+    let (mut vm, function) = (vm(), numeric_loop());
+    assert_eq!(run(&mut vm, &function), [ITERATIONS * (ITERATIONS + 1.0)]);
+    c.bench_function("numeric_loop", |b| b.iter(|| run(&mut vm, &function)));
+}
+
+fn arrays(c: &mut Criterion) {
+    let (mut vm, function) = (vm(), array_ipairs());
+    assert_eq!(run(&mut vm, &function), [ARRAY_SIZE * (ARRAY_SIZE + 1.0) / 2.0, ARRAY_SIZE]);
+    c.bench_function("array_ipairs", |b| b.iter(|| run(&mut vm, &function)));
+}
+
+// With the default frame pool and with pooling turned off, every call then allocates its registers
+fn calls(c: &mut Criterion) {
+    let function = call_loop();
+    let mut group = c.benchmark_group("call_loop");
+    for (name, pool_size) in [("frame_pool", None), ("no_frame_pool", Some(0))] {
+        let mut vm = vm();
+        if let Some(size) = pool_size {
+            vm.set_frame_pool_size(size);
+        }
+        assert_eq!(run(&mut vm, &function), [ITERATIONS]);
+        group.bench_function(name, |b| b.iter(|| run(&mut vm, &function)));
+    }
+    group.finish();
+}
+
+fn field(object: &LuaValue, name: &str) -> Rc<RefCell<LuaValue>> {
+    object.as_table().expect("object is a table").get(&name.into()).expect("field is set")
+}
+
+// { v = n } for n = SORT_SIZE down to 1, the worst case for insertion sort
+fn reversed_objects(metatable: &LuaTable) -> Vec<LuaValue> {
+    (0..SORT_SIZE).map(|i| {
+        let object = LuaTable::new(BTreeMap::new());
+        object.insert(LuaValue::from("v").into(), LuaValue::from((SORT_SIZE - i) as f64).into());
+        object.set_metatable(Some(metatable.clone()));
+        LuaValue::from(object)
+    }).collect()
+}
+
+// a < b through the __lt metamethod, which is looked up on every comparison
+fn less_than(a: &LuaValue, b: &LuaValue) -> bool {
+    let lt = a.as_table().unwrap().metamethod("__lt").expect("objects have __lt");
+    let result = lt.borrow().as_function().unwrap().invoke(&vec![a.clone().into(), b.clone().into()]).unwrap();
+    *result[0].borrow().as_bool().unwrap()
+}
+
+fn insertion_sort(array: &mut [LuaValue]) {
+    for i in 1..array.len() {
+        let mut j = i;
+        while j > 0 && less_than(&array[j], &array[j - 1]) {
+            array.swap(j, j - 1);
+            j -= 1;
+        }
+    }
+}
+
+fn sort(c: &mut Criterion) {
+    let metatable = LuaTable::new(BTreeMap::new());
+    let lt = lua_function!(|args: &LuaFunctionArgs| {
+        let v = |n: usize| -> LuaResult<f64> { LuaResult::Ok(*field(&args[n].borrow(), "v").borrow().as_f64()?) };
+        LuaResult::Ok(vec![LuaValue::from(v(0)? < v(1)?).into()])
+    });
+    metatable.insert(LuaValue::from("__lt").into(), LuaValue::from(lt).into());
+
+    let mut group = c.benchmark_group("sort_lt");
+    // A sort takes a few milliseconds, more samples than the default fit in the same time
+    group.sample_size(50);
+    for (name, cached) in [("metamethod_cache", true), ("no_metamethod_cache", false)] {
+        metatable.set_metamethod_cache(cached);
+        let mut array = reversed_objects(&metatable);
+        insertion_sort(&mut array);
+        assert!(array.iter().enumerate().all(|(i, object)| *field(object, "v").borrow() == LuaValue::from((i + 1) as f64)));
+
+        group.bench_function(name, |b| b.iter_batched(
+            || reversed_objects(&metatable),
+            |mut array| {
+                insertion_sort(&mut array);
+                array
+            },
+            BatchSize::SmallInput
+        ));
+    }
+    group.finish();
+}
+
+// The loops run a million iterations each, fewer samples keep a full run short
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = numeric, arrays, calls, sort
+}
+criterion_main!(benches);
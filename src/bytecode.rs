@@ -1,11 +1,11 @@
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::{Ref, RefCell}, future::Future, io::Write, ops::RangeInclusive, rc::Rc, task::{Context, Poll, Waker}};
 
 use async_recursion::async_recursion;
 use enum_map::{Enum, enum_map};
 use once_cell::sync::Lazy;
 use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
 
-use crate::types::value::LuaValue;
+use crate::{types::value::LuaValue, validate, vm::LoadError};
 
 #[derive(Debug)]
 pub enum DecodeError {
@@ -13,12 +13,16 @@ pub enum DecodeError {
     UnsupportedVersion,
     UnsupportedFormat,
     UnsupportedEndian,
+    // A count or string length is negative or above the DecodeLimits
+    MalformedSizeField,
+    // The low 6 bits of an instruction word aren't a Lua 5.1 opcode
+    InvalidOpCode(u8),
     ReadErr(tokio::io::Error)
 }
 
 impl std::fmt::Display for DecodeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self)
+        write!(f, "{:?}", self)
     }
 }
 
@@ -30,7 +34,23 @@ impl From<tokio::io::Error> for DecodeError {
     }
 }
 
-type DecodeResult<T> = Result<T, DecodeError>;
+pub type DecodeResult<T> = Result<T, DecodeError>;
+
+// Upper bounds on the sizes a chunk may claim. They're checked before anything is allocated, so a
+// corrupt or hostile chunk fails with MalformedSizeField instead of exhausting memory
+#[derive(Debug, Clone, Copy)]
+pub struct DecodeLimits {
+    // Instructions, constants, nested functions, line positions, locals and upvalues of one function
+    pub max_count: usize,
+    // Bytes of a single string
+    pub max_string_length: usize
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self { max_count: 1 << 24, max_string_length: 1 << 28 }
+    }
+}
 
 #[derive(Debug, Enum, Copy, Clone)]
 #[allow(nonstandard_style)]
@@ -80,12 +100,16 @@ pub enum OpCode {
     SetList,
     Close,
     Closure,
-    Vararg
+    Vararg,
+    // Not a Lua 5.1 opcode: the raw word following a SETLIST with C == 0, its batch number, kept in Bx
+    ExtraArg
 }
 
-impl From<u8> for OpCode {
-    fn from(value: u8) -> Self {
-        match value {
+impl TryFrom<u8> for OpCode {
+    type Error = DecodeError;
+
+    fn try_from(value: u8) -> DecodeResult<Self> {
+        DecodeResult::Ok(match value {
             0 => Self::Move,
             1 => Self::LoadK,
             2 => Self::LoadBool,
@@ -124,8 +148,8 @@ impl From<u8> for OpCode {
             35 => Self::Close,
             36 => Self::Closure,
             37 => Self::Vararg,
-            _ => panic!("Unsupported OP code")
-        }
+            _ => return DecodeResult::Err(DecodeError::InvalidOpCode(value))
+        })
     }
 }
 
@@ -167,11 +191,17 @@ static OP_CODE_MODES: Lazy<enum_map::EnumMap<OpCode, OpMode>> = Lazy::new(|| enu
     OpCode::SetList => OpMode::iABC,
     OpCode::Close => OpMode::iABC,
     OpCode::Closure => OpMode::iABx,
-    OpCode::Vararg => OpMode::iABC
+    OpCode::Vararg => OpMode::iABC,
+    OpCode::ExtraArg => OpMode::iABx
 });
 
 pub static FIELDS_PER_FLUSH: usize = 50;
 
+// LuaPrototype::vararg_flags bits
+pub static VARARG_HASARG: u8 = 1;
+pub static VARARG_ISVARARG: u8 = 2;
+pub static VARARG_NEEDSARG: u8 = 4;
+
 #[derive(Debug, Clone)]
 #[allow(nonstandard_style)]
 pub struct Instruction {
@@ -185,14 +215,15 @@ pub struct Instruction {
     pub sBx: i64
 }
 
-impl From<u32> for Instruction {
-    fn from(value: u32) -> Self {
-        let op: u8 = (value & 0b111111) as u8;
-        let code: OpCode = op.into();
+impl TryFrom<u32> for Instruction {
+    type Error = DecodeError;
+
+    fn try_from(value: u32) -> DecodeResult<Self> {
+        let code = OpCode::try_from((value & 0b111111) as u8)?;
         let mode = &OP_CODE_MODES[code];
 
         let mut instruction = Instruction {
-            mode: mode.clone(),
+            mode: *mode,
             code,
             A: (value >> 6 & 0b1111_1111) as usize,
             B: 0,
@@ -214,7 +245,37 @@ impl From<u32> for Instruction {
             }
         };
 
-        instruction
+        DecodeResult::Ok(instruction)
+    }
+}
+
+impl Instruction {
+    // Decode the word following `previous` in a function. The word after a SETLIST with C == 0
+    // is its batch number and may have any value, so it's kept whole as an ExtraArg
+    pub fn decode(word: u32, previous: Option<&Instruction>) -> DecodeResult<Self> {
+        if previous.is_some_and(|p| matches!(p.code, OpCode::SetList) && p.C == 0) {
+            return DecodeResult::Ok(Instruction { mode: OpMode::iABx, code: OpCode::ExtraArg, A: 0, B: 0, C: 0, Bx: word as usize, sBx: 0 });
+        }
+        word.try_into()
+    }
+
+    // Inverse of TryFrom<u32>, only the fields used by the op mode are encoded
+    pub fn encode(&self) -> u32 {
+        if matches!(self.code, OpCode::ExtraArg) {
+            return self.Bx as u32;
+        }
+        let word = self.code as u32 | (self.A as u32 & 0b1111_1111) << 6;
+        match self.mode {
+            OpMode::iABC => word | (self.C as u32 & 0b0001_1111_1111) << 14 | (self.B as u32 & 0b0001_1111_1111) << 23,
+            OpMode::iABx => word | (self.Bx as u32 & 0b0011_1111_1111_1111_1111) << 14,
+            OpMode::iAsBx => word | ((self.sBx + 131071) as u32 & 0b0011_1111_1111_1111_1111) << 14
+        }
+    }
+}
+
+impl From<&Instruction> for u32 {
+    fn from(value: &Instruction) -> Self {
+        value.encode()
     }
 }
 
@@ -242,6 +303,12 @@ pub struct LuaPrototype {
     pub upvalues: Vec<String>
 }
 
+impl Default for LuaPrototype {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl LuaPrototype {
     pub fn new() -> LuaPrototype {
         LuaPrototype {
@@ -260,6 +327,167 @@ impl LuaPrototype {
             upvalues: Vec::new() 
         }
     }
+
+    // Name of the local held in `reg` at `pc`, like luaF_getlocalname. Locals are ordered by
+    // start_pc and active locals occupy consecutive registers from 0
+    pub fn local_name_at(&self, reg: usize, pc: i64) -> Option<&str> {
+        let mut reg = reg;
+        for local in self.locals.iter().take_while(|l| l.start_pc <= pc) {
+            if pc < local.end_pc {
+                if reg == 0 {
+                    return Some(&local.name);
+                }
+                reg -= 1;
+            }
+        }
+        None
+    }
+
+    // Registers written by `inst`, None for instructions that don't write any
+    fn written_registers(inst: &Instruction) -> Option<RangeInclusive<usize>> {
+        match inst.code {
+            OpCode::Move | OpCode::LoadK | OpCode::LoadBool | OpCode::GetUpValue | OpCode::GetGlobal | OpCode::GetTable
+                | OpCode::NewTable | OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::Mod | OpCode::Pow
+                | OpCode::UnaryMinus | OpCode::Not | OpCode::Len | OpCode::Concat | OpCode::TestSet | OpCode::Closure => Some(inst.A..=inst.A),
+            OpCode::LoadNil => Some(inst.A..=inst.B),
+            OpCode::LSelf => Some(inst.A..=inst.A + 1),
+            OpCode::ForPrep | OpCode::ForLoop => Some(inst.A..=inst.A + 3),
+            // The number of results isn't known for multret, assume everything above is clobbered
+            OpCode::Call | OpCode::TailCall | OpCode::Vararg => Some(inst.A..=usize::MAX),
+            OpCode::TForLoop => Some(inst.A + 2..=usize::MAX),
+            _ => None
+        }
+    }
+
+    // Last instruction before `pc` that wrote `reg`, following forward jumps like symbexec does
+    // so writes in skipped branches don't count
+    fn last_write(&self, reg: usize, pc: i64) -> Option<&Instruction> {
+        let mut last = None;
+        let mut i = 0usize;
+        while (i as i64) < pc {
+            let inst = self.instructions.get(i)?;
+            if matches!(inst.code, OpCode::Jmp) {
+                let dest = i as i64 + 1 + inst.sBx;
+                if dest > i as i64 && dest <= pc {
+                    i = dest as usize;
+                    continue;
+                }
+            }
+            if Self::written_registers(inst).is_some_and(|r| r.contains(&reg)) {
+                last = Some(inst);
+            }
+            // Skip the pseudo instructions describing the closure's upvalues
+            if matches!(inst.code, OpCode::Closure) {
+                i += self.prototypes.get(inst.Bx).map_or(0, |p| p.upvalue_count as usize);
+            }
+            i += 1;
+        }
+        last
+    }
+
+    // How the value in `reg` at `pc` was named, e.g. ("global", "foo"), like getobjname
+    pub fn object_name(&self, reg: usize, pc: i64) -> Option<(&'static str, String)> {
+        if let Some(name) = self.local_name_at(reg, pc) {
+            return Some(("local", name.to_owned()));
+        }
+
+        let constant_name = |idx: usize| match self.constant(idx).as_deref() {
+            Some(LuaValue::String(s)) => Some(s.to_str_lossy().into_owned()),
+            _ => None
+        };
+        let inst = self.last_write(reg, pc)?;
+        match inst.code {
+            OpCode::GetGlobal => Some(("global", constant_name(inst.Bx)?)),
+            OpCode::GetTable if inst.C >= 256 => Some(("field", constant_name(inst.C - 256)?)),
+            OpCode::LSelf if inst.C >= 256 => Some(("method", constant_name(inst.C - 256)?)),
+            OpCode::GetUpValue => Some(("upvalue", self.upvalues.get(inst.B)?.clone())),
+            // Copied from another register, name it after the source
+            OpCode::Move if inst.B < inst.A => self.object_name(inst.B, pc),
+            _ => None
+        }
+    }
+
+    pub fn constant(&self, idx: usize) -> Option<Ref<'_, LuaValue>> {
+        self.constants.get(idx).map(|c| c.borrow())
+    }
+
+    pub fn builder() -> LuaPrototypeBuilder {
+        LuaPrototypeBuilder { prototype: LuaPrototype::new(), line: 0, error: None }
+    }
+}
+
+// Assembles a prototype in code instead of decoding a chunk, build() validates the result
+// like VirtualMachine::load_and_validate does for chunks
+pub struct LuaPrototypeBuilder {
+    prototype: LuaPrototype,
+    // Source line recorded for the following instructions
+    line: i64,
+    // First word that failed to decode, reported by build()
+    error: Option<DecodeError>
+}
+
+impl LuaPrototypeBuilder {
+    // A raw 32 bit instruction word, like the ones stored in a chunk
+    pub fn instruction(mut self, word: u32) -> Self {
+        match Instruction::decode(word, self.prototype.instructions.last()) {
+            Ok(instruction) => {
+                self.prototype.instructions.push(instruction);
+                self.prototype.source_line_positions.push(self.line);
+            },
+            Err(e) => {
+                self.error.get_or_insert(e);
+            }
+        }
+        self
+    }
+
+    pub fn constant(mut self, value: impl Into<LuaValue>) -> Self {
+        self.prototype.constants.push(Rc::new(RefCell::new(value.into())));
+        self
+    }
+
+    pub fn prototype(mut self, prototype: LuaPrototype) -> Self {
+        self.prototype.prototypes.push(prototype);
+        self
+    }
+
+    pub fn line(mut self, line: i64) -> Self {
+        self.line = line;
+        self
+    }
+
+    pub fn source_name(mut self, name: &str) -> Self {
+        self.prototype.source_name = Some(name.to_owned());
+        self
+    }
+
+    pub fn param_count(mut self, count: u8) -> Self {
+        self.prototype.param_count = count;
+        self
+    }
+
+    pub fn upvalue_count(mut self, count: u8) -> Self {
+        self.prototype.upvalue_count = count;
+        self
+    }
+
+    pub fn vararg_flags(mut self, flags: u8) -> Self {
+        self.prototype.vararg_flags = flags;
+        self
+    }
+
+    pub fn max_stack_size(mut self, size: u8) -> Self {
+        self.prototype.max_stack_size = size;
+        self
+    }
+
+    pub fn build(self) -> Result<LuaPrototype, LoadError> {
+        if let Some(e) = self.error {
+            return Err(e.into());
+        }
+        validate::validate(&self.prototype)?;
+        Ok(self.prototype)
+    }
 }
 
 #[derive(Debug)]
@@ -288,11 +516,37 @@ async fn read_i64<R: AsyncRead + Unpin>(header: &LuaHeader, size: u8, reader: &m
     })
 }
 
+// Element count of a list in a function, stored as an int
+async fn read_count<R: AsyncRead + Unpin>(header: &LuaHeader, limits: &DecodeLimits, reader: &mut BufReader<R>) -> DecodeResult<usize> {
+    match read_i64(header, header.int_size, reader).await? {
+        n if n < 0 || n as u64 > limits.max_count as u64 => DecodeResult::Err(DecodeError::MalformedSizeField),
+        n => DecodeResult::Ok(n as usize)
+    }
+}
+
+// String length, stored as a size_t
+async fn read_length<R: AsyncRead + Unpin>(header: &LuaHeader, limits: &DecodeLimits, reader: &mut BufReader<R>) -> DecodeResult<usize> {
+    match read_u64(header, header.size_t_size, reader).await? {
+        n if n > limits.max_string_length as u64 => DecodeResult::Err(DecodeError::MalformedSizeField),
+        n => DecodeResult::Ok(n as usize)
+    }
+}
+
+// Strings are stored with their trailing NUL, which is dropped. The bytes are kept as they are
+// The buffer grows with the data actually read, a truncated chunk fails before reaching `length`
+async fn read_bytes<R: AsyncRead + Unpin>(length: usize, reader: &mut BufReader<R>) -> DecodeResult<Vec<u8>> {
+    let mut s = Vec::new();
+    (&mut *reader).take(length as u64).read_to_end(&mut s).await?;
+    if s.len() < length {
+        return DecodeResult::Err(DecodeError::ReadErr(tokio::io::ErrorKind::UnexpectedEof.into()));
+    }
+    s.pop();
+    DecodeResult::Ok(s)
+}
+
+// For names (source, locals, upvalues) which are only used for display
 async fn read_string<R: AsyncRead + Unpin>(length: usize, reader: &mut BufReader<R>) -> DecodeResult<String> {
-    let mut s = vec![0u8; length];
-    reader.read_exact(&mut s).await?;
-    s.remove(length - 1);
-    DecodeResult::Ok(String::from_utf8_lossy(&s).to_string())
+    DecodeResult::Ok(String::from_utf8_lossy(&read_bytes(length, reader).await?).to_string())
 }
 
 async fn read_lua_number<R: AsyncRead + Unpin>(header: &LuaHeader, reader: &mut BufReader<R>) -> DecodeResult<f64> {
@@ -304,14 +558,18 @@ async fn read_lua_number<R: AsyncRead + Unpin>(header: &LuaHeader, reader: &mut
 }
 
 #[async_recursion(?Send)]
-async fn read_function<R: AsyncRead + Unpin>(header: &LuaHeader, reader: &mut BufReader<R>) -> DecodeResult<LuaPrototype> {
+async fn read_function<R>(header: &LuaHeader, limits: &DecodeLimits, parent_source: Option<&String>, reader: &mut BufReader<R>) -> DecodeResult<LuaPrototype>
+where
+    R: AsyncRead + Unpin
+{
     let mut function = LuaPrototype::new();
 
-    function.source_name = match read_u64(header, header.size_t_size, reader).await? as usize {
+    // luac only stores the source name of nested functions if it differs from the parent's
+    function.source_name = match read_length(header, limits, reader).await? {
         n if n > 0 => {
             Some(read_string(n, reader).await?)
         },
-        _ => None
+        _ => parent_source.cloned()
     };
 
     function.line_defined = read_i64(header, header.int_size, reader).await?;
@@ -322,14 +580,15 @@ async fn read_function<R: AsyncRead + Unpin>(header: &LuaHeader, reader: &mut Bu
     function.max_stack_size = reader.read_u8().await?;
 
     // read instructions
-    let instruction_count = read_i64(header, header.int_size, reader).await?;
+    let instruction_count = read_count(header, limits, reader).await?;
     for _i in 0..instruction_count {
         let raw_instruction = read_u64(header, header.instruction_size, reader).await? as u32;
-        function.instructions.push(raw_instruction.into());
+        let instruction = Instruction::decode(raw_instruction, function.instructions.last())?;
+        function.instructions.push(instruction);
     }
 
     // read constants
-    let constants_count = read_i64(header, header.int_size, reader).await?;
+    let constants_count = read_count(header, limits, reader).await?;
     for _i in 0..constants_count {
         let constant_type = reader.read_u8().await?;
 
@@ -338,29 +597,30 @@ async fn read_function<R: AsyncRead + Unpin>(header: &LuaHeader, reader: &mut Bu
             1 => function.constants.push(LuaValue::from(reader.read_u8().await? == 1).into()),
             3 => function.constants.push(LuaValue::from(read_lua_number(header, reader).await?).into()),
             4 => {
-                let length = read_u64(header, header.size_t_size, reader).await? as usize;
-                function.constants.push(LuaValue::from(read_string(length, reader).await?).into());
+                let length = read_length(header, limits, reader).await?;
+                function.constants.push(LuaValue::from(read_bytes(length, reader).await?).into());
             },
             _ => {}
         };
     }
 
     // read function prototypes
-    let function_count = read_i64(header, header.int_size, reader).await?;
+    let function_count = read_count(header, limits, reader).await?;
     for _i in 0..function_count {
-        function.prototypes.push(read_function(header, reader).await?);
+        let sub_func = read_function(header, limits, function.source_name.as_ref(), reader).await?;
+        function.prototypes.push(sub_func);
     }
 
     // read source line positions
-    let slp_count = read_i64(header, header.int_size, reader).await?;
+    let slp_count = read_count(header, limits, reader).await?;
     for _i in 0..slp_count {
         function.source_line_positions.push(read_i64(header, header.int_size, reader).await?);
     }
 
     // read locals
-    let local_count = read_i64(header, header.int_size, reader).await?;
+    let local_count = read_count(header, limits, reader).await?;
     for _i in 0..local_count {
-        let string_len = read_u64(header, header.size_t_size, reader).await? as usize;
+        let string_len = read_length(header, limits, reader).await?;
         let name = read_string(string_len, reader).await?;
         let start_pc = read_i64(header, header.int_size, reader).await?;
         let end_pc = read_i64(header, header.int_size, reader).await?;
@@ -369,9 +629,9 @@ async fn read_function<R: AsyncRead + Unpin>(header: &LuaHeader, reader: &mut Bu
     }
 
     // read upvalues
-    let upvalue_count = read_i64(header, header.int_size, reader).await?;
+    let upvalue_count = read_count(header, limits, reader).await?;
     for _i in 0..upvalue_count {
-        let string_len = read_u64(header, header.size_t_size, reader).await? as usize;
+        let string_len = read_length(header, limits, reader).await?;
         function.upvalues.push(read_string(string_len, reader).await?);
     }
 
@@ -379,6 +639,10 @@ async fn read_function<R: AsyncRead + Unpin>(header: &LuaHeader, reader: &mut Bu
 }
 
 pub async fn read_bytecode<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> DecodeResult<LuaPrototype> {
+    read_bytecode_with_limits(reader, DecodeLimits::default()).await
+}
+
+pub async fn read_bytecode_with_limits<R: AsyncRead + Unpin>(reader: &mut BufReader<R>, limits: DecodeLimits) -> DecodeResult<LuaPrototype> {
     let mut header: [u8; 4] = [0; 4];
     reader.read_exact(&mut header).await?;
 
@@ -417,5 +681,102 @@ pub async fn read_bytecode<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> D
     header.lua_number_size = reader.read_u8().await?;
     header.integral_flag = reader.read_u8().await?;
 
-    read_function(&header, reader).await
+    read_function(&header, &limits, None, reader).await
+}
+
+
+// Strings are written with their trailing NUL, None is the empty (NULL) string
+fn write_string<W: Write>(s: Option<&[u8]>, writer: &mut W) -> std::io::Result<()> {
+    match s {
+        Some(s) => {
+            writer.write_all(&(s.len() as u64 + 1).to_le_bytes())?;
+            writer.write_all(s)?;
+            writer.write_all(&[0])
+        },
+        None => writer.write_all(&0u64.to_le_bytes())
+    }
+}
+
+fn write_int<W: Write>(n: i64, writer: &mut W) -> std::io::Result<()> {
+    writer.write_all(&(n as i32).to_le_bytes())
 }
+
+fn write_function<W: Write>(function: &LuaPrototype, parent_source: Option<&String>, writer: &mut W) -> std::io::Result<()> {
+    // Like luac, nested functions only store their source name if it differs from the parent's
+    let source = function.source_name.as_ref().filter(|s| Some(*s) != parent_source);
+    write_string(source.map(|s| s.as_bytes()), writer)?;
+
+    write_int(function.line_defined, writer)?;
+    write_int(function.last_line_defined, writer)?;
+    writer.write_all(&[function.upvalue_count, function.param_count, function.vararg_flags, function.max_stack_size])?;
+
+    write_int(function.instructions.len() as i64, writer)?;
+    for instruction in &function.instructions {
+        writer.write_all(&instruction.encode().to_le_bytes())?;
+    }
+
+    write_int(function.constants.len() as i64, writer)?;
+    for constant in &function.constants {
+        match &*constant.borrow() {
+            LuaValue::Nil => writer.write_all(&[0])?,
+            LuaValue::Boolean(b) => writer.write_all(&[1, *b as u8])?,
+            LuaValue::Number(n) => {
+                writer.write_all(&[3])?;
+                writer.write_all(&n.0.to_le_bytes())?;
+            },
+            LuaValue::String(s) => {
+                writer.write_all(&[4])?;
+                write_string(Some(s.as_bytes()), writer)?;
+            },
+            v => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("a {} can't be a constant", v.type_name())))
+        }
+    }
+
+    write_int(function.prototypes.len() as i64, writer)?;
+    for prototype in &function.prototypes {
+        write_function(prototype, function.source_name.as_ref(), writer)?;
+    }
+
+    write_int(function.source_line_positions.len() as i64, writer)?;
+    for line in &function.source_line_positions {
+        write_int(*line, writer)?;
+    }
+
+    write_int(function.locals.len() as i64, writer)?;
+    for local in &function.locals {
+        write_string(Some(local.name.as_bytes()), writer)?;
+        write_int(local.start_pc, writer)?;
+        write_int(local.end_pc, writer)?;
+    }
+
+    write_int(function.upvalues.len() as i64, writer)?;
+    for upvalue in &function.upvalues {
+        write_string(Some(upvalue.as_bytes()), writer)?;
+    }
+
+    Ok(())
+}
+
+// Serialize a prototype tree as a Lua 5.1 chunk, readable by read_bytecode and luac's loader.
+// The layout is the one of a 64-bit luac: little endian, 4 byte ints and instructions,
+// 8 byte size_t and numbers
+pub fn write_bytecode<W: Write>(function: &LuaPrototype, writer: &mut W) -> std::io::Result<()> {
+    writer.write_all(&[0x1B, 0x4C, 0x75, 0x61, 0x51, 0, 1, 4, 8, 4, 8, 0])?;
+    write_function(function, None, writer)
+}
+
+// Decode an in-memory chunk without an async runtime
+// Reading from a slice never blocks, so the decoder future completes on the first poll
+pub fn read_bytecode_sync(bytes: &[u8]) -> DecodeResult<LuaPrototype> {
+    read_bytecode_sync_with_limits(bytes, DecodeLimits::default())
+}
+
+pub fn read_bytecode_sync_with_limits(bytes: &[u8], limits: DecodeLimits) -> DecodeResult<LuaPrototype> {
+    let mut reader = BufReader::new(bytes);
+    let mut future = std::pin::pin!(read_bytecode_with_limits(&mut reader, limits));
+
+    match future.as_mut().poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(result) => result,
+        Poll::Pending => unreachable!("in-memory reads never block")
+    }
+}
\ No newline at end of file
@@ -0,0 +1,120 @@
+// Conversions between host types and LuaValue for embedding code
+
+use crate::types::{LuaError, LuaResult, table::{LuaTable, TableEntries}, value::LuaValue};
+
+pub trait IntoLua {
+    fn into_lua(self) -> LuaValue;
+}
+
+pub trait FromLua: Sized {
+    fn from_lua(value: LuaValue) -> LuaResult<Self>;
+}
+
+impl IntoLua for LuaValue {
+    fn into_lua(self) -> LuaValue {
+        self
+    }
+}
+
+impl FromLua for LuaValue {
+    fn from_lua(value: LuaValue) -> LuaResult<Self> {
+        LuaResult::Ok(value)
+    }
+}
+
+// Numbers are f64, integers beyond 2^53 lose precision
+impl IntoLua for i64 {
+    fn into_lua(self) -> LuaValue {
+        LuaValue::from(self as f64)
+    }
+}
+
+// Fractional numbers are truncated like lua_tointeger does
+impl FromLua for i64 {
+    fn from_lua(value: LuaValue) -> LuaResult<Self> {
+        LuaResult::Ok(f64::from_lua(value)? as i64)
+    }
+}
+
+impl IntoLua for f64 {
+    fn into_lua(self) -> LuaValue {
+        LuaValue::from(self)
+    }
+}
+
+// Numeric strings are converted like they are in arithmetic
+impl FromLua for f64 {
+    fn from_lua(value: LuaValue) -> LuaResult<Self> {
+        match value {
+            LuaValue::Number(n) => LuaResult::Ok(n.0),
+            LuaValue::String(s) => s.to_number().ok_or(LuaError::ExpectedNumber),
+            _ => LuaResult::Err(LuaError::ExpectedNumber)
+        }
+    }
+}
+
+impl IntoLua for String {
+    fn into_lua(self) -> LuaValue {
+        LuaValue::from(self)
+    }
+}
+
+// Numbers give their Lua string form, invalid UTF-8 is replaced
+impl FromLua for String {
+    fn from_lua(value: LuaValue) -> LuaResult<Self> {
+        match value {
+            LuaValue::String(s) => LuaResult::Ok(s.to_str_lossy().into_owned()),
+            LuaValue::Number(n) => LuaResult::Ok(n.to_string()),
+            _ => LuaResult::Err(LuaError::ExpectedString)
+        }
+    }
+}
+
+impl IntoLua for bool {
+    fn into_lua(self) -> LuaValue {
+        LuaValue::from(self)
+    }
+}
+
+// Lua truthiness, only nil and false are false
+impl FromLua for bool {
+    fn from_lua(value: LuaValue) -> LuaResult<Self> {
+        LuaResult::Ok(value.is_truthy())
+    }
+}
+
+// An array table with the elements under 1..n
+impl<T: IntoLua> IntoLua for Vec<T> {
+    fn into_lua(self) -> LuaValue {
+        let table = LuaTable::new(TableEntries::new());
+        for (i, v) in self.into_iter().enumerate() {
+            table.insert(LuaValue::from((i + 1) as f64).into(), v.into_lua().into());
+        }
+        LuaValue::from(table)
+    }
+}
+
+// The array part of a table, up to the first nil
+impl<T: FromLua> FromLua for Vec<T> {
+    fn from_lua(value: LuaValue) -> LuaResult<Self> {
+        value.as_table()?.array().into_iter().map(|v| T::from_lua(v.borrow().clone())).collect()
+    }
+}
+
+impl<T: IntoLua> IntoLua for Option<T> {
+    fn into_lua(self) -> LuaValue {
+        match self {
+            Some(v) => v.into_lua(),
+            None => LuaValue::Nil
+        }
+    }
+}
+
+impl<T: FromLua> FromLua for Option<T> {
+    fn from_lua(value: LuaValue) -> LuaResult<Self> {
+        match value {
+            LuaValue::Nil => LuaResult::Ok(None),
+            v => T::from_lua(v).map(Some)
+        }
+    }
+}
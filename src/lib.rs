@@ -1,4 +1,6 @@
 pub mod types;
 pub mod bytecode;
+pub mod validate;
 pub mod vm;
 pub mod libs;
+pub mod convert;
\ No newline at end of file
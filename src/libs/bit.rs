@@ -0,0 +1,80 @@
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use crate::{lua_function, lua_return, lua_string, lua_table, types::{LuaError, function::{LuaFunctionArgs, LuaFunctionReturn}, LuaResult, value::LuaValue}};
+
+// Normalize a number to int32 the way Lua BitOp does: adding 2^52 + 2^51 moves the
+// integral part (rounded, modulo 2^32) into the low bits of the mantissa
+fn normalize(n: f64) -> i32 {
+    (n + 6755399441055744.0).to_bits() as u32 as i32
+}
+
+fn check_bit(args: &LuaFunctionArgs, n: usize) -> LuaResult<i32> {
+    match args.get(n) {
+        Some(v) => LuaResult::Ok(normalize(*v.borrow().as_f64()?)),
+        None => LuaResult::Err(LuaError::ExpectedArgument)
+    }
+}
+
+// Shift counts only use the lower 5 bits
+fn check_shift(args: &LuaFunctionArgs, n: usize) -> LuaResult<u32> {
+    LuaResult::Ok(check_bit(args, n)? as u32 & 31)
+}
+
+fn fold(args: &LuaFunctionArgs, op: fn(i32, i32) -> i32) -> LuaFunctionReturn {
+    let mut res = check_bit(args, 0)?;
+    for arg in &args[1..] {
+        res = op(res, normalize(*arg.borrow().as_f64()?));
+    }
+    lua_return!(LuaValue::from(res as f64).into());
+}
+
+pub fn tobit(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    lua_return!(LuaValue::from(check_bit(args, 0)? as f64).into());
+}
+
+pub fn bnot(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    lua_return!(LuaValue::from(!check_bit(args, 0)? as f64).into());
+}
+
+pub fn band(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    fold(args, |a, b| a & b)
+}
+
+pub fn bor(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    fold(args, |a, b| a | b)
+}
+
+pub fn bxor(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    fold(args, |a, b| a ^ b)
+}
+
+pub fn lshift(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let (x, n) = (check_bit(args, 0)?, check_shift(args, 1)?);
+    lua_return!(LuaValue::from(((x as u32) << n) as i32 as f64).into());
+}
+
+// Logical shift, fills with zeros
+pub fn rshift(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let (x, n) = (check_bit(args, 0)?, check_shift(args, 1)?);
+    lua_return!(LuaValue::from(((x as u32) >> n) as i32 as f64).into());
+}
+
+// Arithmetic shift, keeps the sign bit
+pub fn arshift(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let (x, n) = (check_bit(args, 0)?, check_shift(args, 1)?);
+    lua_return!(LuaValue::from((x >> n) as f64).into());
+}
+
+#[allow(clippy::mutable_key_type)]
+pub fn make() -> BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>> {
+    lua_table! {
+        lua_string!("tobit") => lua_function!(tobit).into(),
+        lua_string!("bnot") => lua_function!(bnot).into(),
+        lua_string!("band") => lua_function!(band).into(),
+        lua_string!("bor") => lua_function!(bor).into(),
+        lua_string!("bxor") => lua_function!(bxor).into(),
+        lua_string!("lshift") => lua_function!(lshift).into(),
+        lua_string!("rshift") => lua_function!(rshift).into(),
+        lua_string!("arshift") => lua_function!(arshift).into()
+    }
+}
@@ -0,0 +1,109 @@
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use crate::{bytecode::LuaPrototype, lua_function, lua_number, lua_return, lua_string, lua_table, types::{LuaError, function::{LuaFunctionArgs, LuaFunctionReturn}, LuaResult, table::TableEntries, value::LuaValue}, vm::{CallInfo, VirtualMachine}};
+
+// Printable form of a chunk name, like luaO_chunkid
+fn short_src(source: &str) -> String {
+    match source.chars().next() {
+        Some('=') | Some('@') => source[1..].to_owned(),
+        _ => format!("[string \"{}\"]", source.lines().next().unwrap_or(""))
+    }
+}
+
+#[allow(clippy::mutable_key_type)]
+fn prototype_info(prototype: &LuaPrototype, current_line: Option<i64>) -> TableEntries {
+    let source = prototype.source_name.clone().unwrap_or("=?".to_owned());
+
+    lua_table! {
+        lua_string!("short_src") => lua_string!(short_src(&source)),
+        lua_string!("source") => lua_string!(source),
+        lua_string!("what") => lua_string!(if prototype.line_defined == 0 { "main" } else { "Lua" }),
+        lua_string!("currentline") => lua_number!(current_line.unwrap_or(-1) as f64),
+        lua_string!("linedefined") => lua_number!(prototype.line_defined as f64),
+        lua_string!("lastlinedefined") => lua_number!(prototype.last_line_defined as f64),
+        lua_string!("nparams") => lua_number!(prototype.param_count as f64)
+    }
+}
+
+#[allow(clippy::mutable_key_type)]
+fn native_info() -> TableEntries {
+    lua_table! {
+        lua_string!("short_src") => lua_string!("[C]"),
+        lua_string!("source") => lua_string!("=[C]"),
+        lua_string!("what") => lua_string!("C"),
+        lua_string!("currentline") => lua_number!(-1.0),
+        lua_string!("linedefined") => lua_number!(-1.0),
+        lua_string!("lastlinedefined") => lua_number!(-1.0),
+        lua_string!("nparams") => lua_number!(0.0)
+    }
+}
+
+// Level 0 is getinfo itself, level 1 the function calling it and so on
+// Only Lua functions are tracked on the call stack, native callers are skipped
+pub fn getinfo(call_stack: &Rc<RefCell<Vec<CallInfo>>>, args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let target = match args.first() {
+        Some(v) => v.borrow().clone(),
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+
+    #[allow(clippy::mutable_key_type)]
+    let info = match target {
+        LuaValue::Number(level) if level.0 == 0.0 => native_info(),
+        LuaValue::Number(level) => {
+            let stack = call_stack.borrow();
+            let level = level.0 as usize;
+            if level < 1 || level > stack.len() {
+                lua_return!(LuaValue::Nil.into());
+            }
+
+            let frame = &stack[stack.len() - level];
+            prototype_info(&frame.prototype, frame.current_line())
+        },
+        LuaValue::Function(f) => match f.closure() {
+            Some(c) => prototype_info(&c.prototype, None),
+            None => native_info()
+        },
+        _ => return LuaResult::Err(LuaError::ExpectedFunction)
+    };
+
+    lua_return!(LuaValue::from(info).into());
+}
+
+// Message followed by the Lua frames from `level` (default 1, the caller) outwards, like db_errorfb
+// Non-string messages are returned untouched. Used as an xpcall handler it runs after the failing
+// frames were left, so the trace starts at the frame that called xpcall
+pub fn traceback(call_stack: &Rc<RefCell<Vec<CallInfo>>>, args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let mut trace = match args.first().map(|v| v.borrow().clone()) {
+        None | Some(LuaValue::Nil) => String::new(),
+        Some(LuaValue::String(s)) => format!("{}\n", s.to_str_lossy()),
+        Some(LuaValue::Number(n)) => format!("{}\n", n),
+        Some(_) => lua_return!(args[0].clone())
+    };
+    let level = match args.get(1).map(|v| v.borrow().clone()) {
+        Some(LuaValue::Number(n)) => (n.0 as usize).max(1),
+        _ => 1
+    };
+
+    trace.push_str("stack traceback:");
+    for frame in call_stack.borrow().iter().rev().skip(level - 1) {
+        let src = short_src(frame.prototype.source_name.as_deref().unwrap_or("=?"));
+        let line = frame.current_line().unwrap_or(-1);
+        if frame.prototype.line_defined == 0 {
+            trace.push_str(&format!("\n\t{}:{}: in main chunk", src, line));
+        } else {
+            trace.push_str(&format!("\n\t{}:{}: in function <{}:{}>", src, line, src, frame.prototype.line_defined));
+        }
+    }
+
+    lua_return!(lua_string!(trace).into());
+}
+
+#[allow(clippy::mutable_key_type)]
+pub fn make(vm: &VirtualMachine) -> BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>> {
+    let (getinfo_stack, traceback_stack) = (vm.call_stack.clone(), vm.call_stack.clone());
+
+    lua_table! {
+        lua_string!("getinfo") => lua_function!(move |args| getinfo(&getinfo_stack, args)).into(),
+        lua_string!("traceback") => lua_function!(move |args| traceback(&traceback_stack, args)).into()
+    }
+}
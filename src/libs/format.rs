@@ -0,0 +1,202 @@
+// printf style conversions of string.format (str_format in lstrlib.c)
+// Works on raw bytes, the spec is the part of a conversion between the % and the conversion character
+
+use crate::types::{LuaError, LuaResult};
+
+const FLAGS: &[u8] = b"-+ #0";
+
+#[derive(Debug, Default)]
+pub struct FormatSpec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alternate: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    pub conversion: u8
+}
+
+// At most 2 digits, like Lua
+fn read_digits(format: &[u8], i: &mut usize) -> LuaResult<usize> {
+    let start = *i;
+    while *i < format.len() && format[*i].is_ascii_digit() {
+        *i += 1;
+    }
+    if *i - start > 2 {
+        return LuaResult::Err(LuaError::InvalidOption("invalid format (width or precision too long)".to_owned()));
+    }
+    LuaResult::Ok(format[start..*i].iter().fold(0, |n, d| n * 10 + (d - b'0') as usize))
+}
+
+// Parse the spec starting right after the %, `i` is left after the conversion character
+pub fn parse_spec(format: &[u8], i: &mut usize) -> LuaResult<FormatSpec> {
+    let mut spec = FormatSpec::default();
+
+    let start = *i;
+    while *i < format.len() && FLAGS.contains(&format[*i]) {
+        match format[*i] {
+            b'-' => spec.left = true,
+            b'+' => spec.plus = true,
+            b' ' => spec.space = true,
+            b'#' => spec.alternate = true,
+            _ => spec.zero = true
+        }
+        *i += 1;
+    }
+    if *i - start > FLAGS.len() {
+        return LuaResult::Err(LuaError::InvalidOption("invalid format (repeated flags)".to_owned()));
+    }
+
+    spec.width = read_digits(format, i)?;
+    if format.get(*i) == Some(&b'.') {
+        *i += 1;
+        spec.precision = Some(read_digits(format, i)?);
+    }
+
+    spec.conversion = match format.get(*i) {
+        Some(c) => *c,
+        None => return LuaResult::Err(LuaError::InvalidOption("invalid option '%' to 'format'".to_owned()))
+    };
+    *i += 1;
+    LuaResult::Ok(spec)
+}
+
+impl FormatSpec {
+    // Pad `prefix` (sign or 0x) and `body` to the width, zeros go between the two
+    fn pad(&self, prefix: &str, body: &[u8], zero: bool) -> Vec<u8> {
+        let len = prefix.len() + body.len();
+        let fill = self.width.saturating_sub(len);
+
+        let mut out = Vec::with_capacity(len + fill);
+        if !self.left && !zero {
+            out.resize(fill, b' ');
+        }
+        out.extend_from_slice(prefix.as_bytes());
+        if !self.left && zero {
+            out.resize(out.len() + fill, b'0');
+        }
+        out.extend_from_slice(body);
+        if self.left {
+            out.resize(out.len() + fill, b' ');
+        }
+        out
+    }
+
+    fn sign(&self, negative: bool) -> &'static str {
+        if negative { "-" } else if self.plus { "+" } else if self.space { " " } else { "" }
+    }
+
+    // %d %i %o %u %x %X, the number is truncated like the cast to long in Lua
+    pub fn integer(&self, n: f64) -> Vec<u8> {
+        let n = n as i64;
+        let (prefix, mut digits) = match self.conversion {
+            b'd' | b'i' => (self.sign(n < 0), n.unsigned_abs().to_string()),
+            b'o' => ("", format!("{:o}", n as u64)),
+            b'x' => (if self.alternate && n != 0 { "0x" } else { "" }, format!("{:x}", n as u64)),
+            b'X' => (if self.alternate && n != 0 { "0X" } else { "" }, format!("{:X}", n as u64)),
+            _ => ("", (n as u64).to_string())
+        };
+
+        // The precision is the minimum number of digits, and a zero precision prints nothing for 0
+        if let Some(p) = self.precision {
+            if p == 0 && n == 0 {
+                digits.clear();
+            } else if digits.len() < p {
+                digits.insert_str(0, &"0".repeat(p - digits.len()));
+            }
+        }
+        if self.conversion == b'o' && self.alternate && !digits.starts_with('0') {
+            digits.insert(0, '0');
+        }
+
+        self.pad(prefix, digits.as_bytes(), self.zero && self.precision.is_none())
+    }
+
+    // %e %E %f %g %G
+    pub fn float(&self, n: f64) -> Vec<u8> {
+        let upper = self.conversion.is_ascii_uppercase();
+        let prefix = self.sign(n.is_sign_negative() && !n.is_nan());
+
+        let body = if n.is_finite() {
+            let precision = self.precision.unwrap_or(6);
+            match self.conversion.to_ascii_lowercase() {
+                b'e' => exponential(n.abs(), precision, self.alternate),
+                b'f' => fixed(n.abs(), precision, self.alternate),
+                _ => general(n.abs(), precision, self.alternate)
+            }
+        } else if n.is_nan() {
+            "nan".to_owned()
+        } else {
+            "inf".to_owned()
+        };
+
+        let body = if upper { body.to_ascii_uppercase() } else { body };
+        self.pad(prefix, body.as_bytes(), self.zero && n.is_finite())
+    }
+
+    // %s, the precision is the maximum number of bytes taken from the string
+    pub fn string(&self, s: &[u8]) -> Vec<u8> {
+        let s = &s[..self.precision.map_or(s.len(), |p| p.min(s.len()))];
+        self.pad("", s, false)
+    }
+
+    // %c
+    pub fn char(&self, n: f64) -> Vec<u8> {
+        self.pad("", &[n as i64 as u8], false)
+    }
+}
+
+fn fixed(n: f64, precision: usize, alternate: bool) -> String {
+    let mut s = format!("{:.*}", precision, n);
+    if alternate && precision == 0 {
+        s.push('.');
+    }
+    s
+}
+
+// C writes at least 2 exponent digits and always a sign, Rust neither
+fn exponential(n: f64, precision: usize, alternate: bool) -> String {
+    let s = format!("{:.*e}", precision, n);
+    let (mantissa, exponent) = s.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    let dot = if alternate && precision == 0 { "." } else { "" };
+    format!("{}{}e{}{:02}", mantissa, dot, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+}
+
+// %f or %e depending on the exponent, trailing zeros are removed unless # is given
+fn general(n: f64, precision: usize, alternate: bool) -> String {
+    let precision = precision.max(1);
+    let scientific = format!("{:.*e}", precision - 1, n);
+    let exponent: i64 = scientific.split_once('e').unwrap().1.parse().unwrap();
+
+    let s = if exponent < -4 || exponent >= precision as i64 {
+        exponential(n, precision - 1, alternate)
+    } else {
+        fixed(n, (precision as i64 - 1 - exponent) as usize, alternate)
+    };
+    if alternate {
+        return s;
+    }
+
+    let (mantissa, exponent) = match s.find('e') {
+        Some(e) => s.split_at(e),
+        None => (s.as_str(), "")
+    };
+    let mantissa = if mantissa.contains('.') { mantissa.trim_end_matches('0').trim_end_matches('.') } else { mantissa };
+    format!("{}{}", mantissa, exponent)
+}
+
+// %q, a string literal that reads back as `s`. Like addquoted only ", \, newline, \r and \0 are escaped
+pub fn add_quoted(out: &mut Vec<u8>, s: &[u8]) {
+    out.push(b'"');
+    for &c in s {
+        match c {
+            b'"' | b'\\' | b'\n' => out.extend_from_slice(&[b'\\', c]),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            0 => out.extend_from_slice(b"\\000"),
+            _ => out.push(c)
+        }
+    }
+    out.push(b'"');
+}
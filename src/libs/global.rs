@@ -1,53 +1,365 @@
 use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
 
-use crate::{lua_function, lua_return, lua_string, lua_table, types::{LuaError, function::{LuaFunctionArgs, LuaFunctionReturn}, LuaResult, value::LuaValue}};
-
-pub fn print(args: &LuaFunctionArgs) -> LuaFunctionReturn {
-    if args.len() > 0 {
-        let mut s = "".to_owned();
-        for arg in args {
-            let x = tostring(&vec![arg.clone()])?[0].borrow().as_string()?.to_owned();
-            s.push_str(&x);
-            s.push_str("\t");
+use crate::{lua_function, lua_return, lua_string, lua_table, types::{LuaError, function::{LuaClosure, LuaFunction, LuaFunctionArgs, LuaFunctionReturn}, LuaResult, string::LuaString, value::LuaValue}, vm::{OutputSink, PrintConfig, VirtualMachine}};
+
+use super::util::{arg_value, opt_number};
+
+pub fn print(output: &Rc<RefCell<OutputSink>>, config: &Rc<RefCell<PrintConfig>>, args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let config = config.borrow();
+    let mut s = LuaString::default();
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            s.push_bytes(config.separator.as_bytes());
         }
-        println!("{}", s);
+        let x = tostring(&vec![arg.clone()])?[0].borrow().as_string()?.clone();
+        s.push_bytes(&x);
     }
+    s.push_bytes(config.terminator.as_bytes());
+
+    let sink = output.borrow().clone();
+    let _ = sink.borrow_mut().write_all(&s);
 
     LuaResult::Ok(vec![])
 }
 
 pub fn error(args: &LuaFunctionArgs) -> LuaFunctionReturn {
-    if args.len() == 0 {
+    if args.is_empty() {
         lua_return!(); // Follow Lua's behavior
     }
 
-    let msg = tostring(&vec![args[0].clone()])?[0].borrow().as_string()?.to_owned();
-    let level = match args.get(1) {
-        Some(l) => Some(*l.borrow().as_f64()?),
-        None => None
-    };
+    let msg = tostring(&vec![args[0].clone()])?[0].borrow().as_string()?.to_string();
+    let level = opt_number(args, 2, "error")?;
     LuaResult::Err(LuaError::TriggeredByUser((msg, level)))
 }
 
+// Value seen by Lua code catching an error, messages raised with error() are passed through
+fn error_value(e: LuaError) -> Rc<RefCell<LuaValue>> {
+    match e {
+        LuaError::TriggeredByUser((msg, _)) => lua_string!(msg).into(),
+        e => lua_string!(e.to_string()).into()
+    }
+}
+
+pub fn pcall(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let f = match args.first() {
+        Some(f) => f.borrow().clone(),
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+
+    match f.call(args[1..].to_vec()) {
+        Ok(results) => {
+            let mut ret = vec![LuaValue::Boolean(true).into()];
+            ret.extend(results);
+            LuaResult::Ok(ret)
+        },
+        Err(e) if !e.is_catchable() => LuaResult::Err(e),
+        Err(e) => lua_return!(LuaValue::Boolean(false).into(), error_value(e))
+    }
+}
+
+// The handler runs after the error was caught, its first result replaces the error value
+pub fn xpcall(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let (f, handler) = match (args.first(), args.get(1)) {
+        (Some(f), Some(h)) => (f.borrow().clone(), h.borrow().clone()),
+        _ => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+
+    match f.call(vec![]) {
+        Ok(results) => {
+            let mut ret = vec![LuaValue::Boolean(true).into()];
+            ret.extend(results);
+            LuaResult::Ok(ret)
+        },
+        Err(e) if !e.is_catchable() => LuaResult::Err(e),
+        Err(e) => {
+            let value = handler.call(vec![error_value(e)])?.first().cloned().unwrap_or(LuaValue::Nil.into());
+            lua_return!(LuaValue::Boolean(false).into(), value)
+        }
+    }
+}
+
 pub fn tostring(args: &LuaFunctionArgs) -> LuaFunctionReturn {
-    if args.len() == 0 {
-        return LuaResult::Err(LuaError::ExpectedArgument);
+    let arg = arg_value(args, 1, "tostring")?;
+
+    // Also used by print, so __tostring applies there as well
+    let metamethod = arg.borrow().metamethod("__tostring");
+    if let Some(tm) = metamethod {
+        let results = tm.borrow().clone().call(vec![arg.clone()])?;
+        lua_return!(results.first().cloned().unwrap_or(LuaValue::Nil.into()));
+    }
+
+    // Strings are returned as they are, to_display_string would lose non UTF-8 bytes
+    let value = match &*arg.borrow() {
+        LuaValue::String(s) => lua_string!(s),
+        v => lua_string!(v.to_display_string())
+    };
+    lua_return!(value.into());
+}
+
+pub fn lua_type(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let value = arg_value(args, 1, "type")?;
+    lua_return!(lua_string!(value.borrow().type_name()).into());
+}
+
+pub fn getmetatable(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let metatable = match args.first() {
+        Some(v) => v.borrow().metatable(),
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+
+    match metatable {
+        // A __metatable field hides the real metatable
+        Some(mt) => match mt.metafield("__metatable") {
+            Some(protected) => lua_return!(protected),
+            None => lua_return!(LuaValue::Table(mt).into())
+        },
+        None => lua_return!(LuaValue::Nil.into())
+    }
+}
+
+pub fn setmetatable(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let table = match args.first() {
+        Some(v) => v.borrow().as_table()?.clone(),
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+
+    let metatable = match args.get(1).map(|v| v.borrow().clone()) {
+        Some(LuaValue::Table(mt)) => Some(mt),
+        Some(LuaValue::Nil) => None,
+        _ => return LuaResult::Err(LuaError::ExpectedTable)
+    };
+
+    if table.metamethod("__metatable").is_some() {
+        return LuaResult::Err(LuaError::ProtectedMetatable);
+    }
+
+    table.set_metatable(metatable);
+    lua_return!(args[0].clone());
+}
+
+// select('#', ...) counts every argument, trailing nils included
+pub fn select(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let n = match args.first() {
+        Some(v) => v.borrow().clone(),
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+
+    let count = args.len() - 1;
+    match n {
+        LuaValue::String(s) if s == "#" => lua_return!(LuaValue::from(count as f64).into()),
+        LuaValue::String(_) => LuaResult::Err(LuaError::ExpectedNumber),
+        n => {
+            let n = *n.as_f64()? as i64;
+            let start = if n < 0 {
+                if -n as usize > count {
+                    return LuaResult::Err(LuaError::IndexOutOfRange);
+                }
+                count as i64 + n + 1
+            } else if n == 0 {
+                return LuaResult::Err(LuaError::IndexOutOfRange);
+            } else {
+                n.min(count as i64 + 1)
+            };
+
+            LuaResult::Ok(args[start as usize..].to_vec())
+        }
     }
+}
+
+// Values are reference counted, there's no collector to control. Memory use isn't tracked,
+// "count" reports 0 KB
+pub fn collectgarbage(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let option = match args.first().map(|o| o.borrow().clone()) {
+        Some(LuaValue::String(o)) => o,
+        Some(LuaValue::Nil) | None => "collect".into(),
+        Some(_) => return LuaResult::Err(LuaError::ExpectedString)
+    };
 
-    lua_return!(match &*args[0].borrow() {
-        LuaValue::String(s) => lua_string!(s).into(),
-        LuaValue::Number(n) => lua_string!(format!("{}", n.0)).into(),
-        LuaValue::Boolean(b) => lua_string!(if *b { "true" } else { "false" }).into(),
-        LuaValue::Nil => lua_string!("nil").into(),
-        LuaValue::Table(_t) => lua_string!(format!("table:{:?}", args[0].as_ptr())).into(),
-        LuaValue::Function(_f) => lua_string!(format!("function:{:?}", args[0].as_ptr())).into()
+    match &*option.to_str_lossy() {
+        "count" | "collect" | "step" | "stop" | "restart" | "setpause" | "setstepmul" => lua_return!(LuaValue::from(0.0).into()),
+        _ => LuaResult::Err(LuaError::InvalidOption(option.to_string()))
+    }
+}
+
+// Iterates in key order like LuaValue::table_ordered_keys, so traversal is deterministic. A key that
+// was removed from the table still works as a cursor, iteration continues with the following key
+pub fn next(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let table = match args.first() {
+        Some(t) => t.borrow().as_table()?.clone(),
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+    let key = match args.get(1) {
+        Some(k) => k.borrow().clone(),
+        None => LuaValue::Nil
+    };
+
+    match table.next(&key) {
+        Some((k, v)) => lua_return!(k, v),
+        None => lua_return!(LuaValue::Nil.into())
+    }
+}
+
+pub fn pairs(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let table = match args.first() {
+        Some(t) => t.clone(),
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+    table.borrow().as_table()?;
+
+    lua_return!(LuaValue::Function(lua_function!(next)).into(), table, LuaValue::Nil.into());
+}
+
+// Raw t[i + 1] like ipairsaux, the loop ends at the first nil
+pub fn inext(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let table = match args.first() {
+        Some(t) => t.borrow().as_table()?.clone(),
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+    let i = match args.get(1) {
+        Some(i) => *i.borrow().as_f64()? + 1.0,
+        None => return LuaResult::Err(LuaError::ExpectedNumber)
+    };
+
+    match table.get(&LuaValue::from(i)) {
+        Some(v) if !matches!(*v.borrow(), LuaValue::Nil) => lua_return!(LuaValue::from(i).into(), v),
+        _ => lua_return!(LuaValue::Nil.into())
+    }
+}
+
+// The iterator steps through the array view taken here instead of looking up every index. Once the
+// loop body writes to the table the view may be stale, from then on it falls back to inext
+pub fn ipairs(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let table = match args.first() {
+        Some(t) => t.clone(),
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+    let viewed = table.borrow().as_table()?.clone();
+    let (array, version) = (viewed.array(), viewed.version());
+
+    let iterator = lua_function!(move |args: &LuaFunctionArgs| {
+        let current = viewed.version() == version
+            && args.first().is_some_and(|t| matches!(&*t.borrow(), LuaValue::Table(t) if *t == viewed));
+        let i = match args.get(1) {
+            Some(i) if current => *i.borrow().as_f64()?,
+            _ => return inext(args)
+        };
+        match array.get(i as usize) {
+            Some(v) if i >= 0.0 && i.fract() == 0.0 => lua_return!(LuaValue::from(i + 1.0).into(), v.clone()),
+            _ => inext(args)
+        }
     });
+    lua_return!(LuaValue::Function(iterator).into(), table, LuaValue::from(0.0).into());
+}
+
+// Turn precompiled bytecode into a function
+// There's no compiler, so source code is rejected the same way as malformed bytecode
+fn compile_chunk(vm: &VirtualMachine, bytes: &[u8]) -> Result<LuaFunction, String> {
+    let chunk = VirtualMachine::load_and_validate(bytes).map_err(|e| e.to_string())?;
+    Ok(LuaFunction::from_closure(LuaClosure {
+        prototype: Rc::new(chunk.prototype),
+        upvalues: vec![],
+        vm: vm.clone()
+    }))
 }
 
-pub fn make() -> BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>> {
+// Returns nil and an error message if the chunk can't be loaded
+fn load_chunk(vm: &VirtualMachine, bytes: &[u8]) -> LuaFunctionReturn {
+    match compile_chunk(vm, bytes) {
+        Ok(f) => lua_return!(LuaValue::Function(f).into()),
+        Err(e) => lua_return!(LuaValue::Nil.into(), lua_string!(e).into())
+    }
+}
+
+fn compile_file(vm: &VirtualMachine, args: &LuaFunctionArgs) -> LuaResult<Result<LuaFunction, String>> {
+    let path = match args.first() {
+        Some(p) => p.borrow().as_string()?.to_string(),
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+
+    LuaResult::Ok(match std::fs::read(&path) {
+        Ok(bytes) => compile_chunk(vm, &bytes),
+        Err(e) => Err(format!("cannot open {}: {}", path, e))
+    })
+}
+
+pub fn loadfile(vm: &VirtualMachine, args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    match compile_file(vm, args)? {
+        Ok(f) => lua_return!(LuaValue::Function(f).into()),
+        Err(e) => lua_return!(LuaValue::Nil.into(), lua_string!(e).into())
+    }
+}
+
+// Unlike loadfile, failing to load raises an error
+pub fn dofile(vm: &VirtualMachine, args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    match compile_file(vm, args)? {
+        Ok(f) => f.invoke(&vec![]),
+        Err(e) => LuaResult::Err(LuaError::ChunkLoadFailed(e))
+    }
+}
+
+pub fn loadstring(vm: &VirtualMachine, args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let chunk = match args.first() {
+        Some(s) => s.borrow().as_string()?.clone(),
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+
+    load_chunk(vm, chunk.as_bytes())
+}
+
+// Accepts a string or a function returning the chunk in pieces, an empty string or nil ends it
+pub fn load(vm: &VirtualMachine, args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let source = match args.first() {
+        Some(v) => v.borrow().clone(),
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+
+    let chunk = match source {
+        LuaValue::String(s) => s,
+        LuaValue::Function(f) => {
+            let mut chunk = LuaString::default();
+            loop {
+                let piece = match f.invoke(&vec![])?.first() {
+                    Some(p) => p.borrow().clone(),
+                    None => break
+                };
+
+                match piece {
+                    LuaValue::String(p) if !p.is_empty() => chunk.push_bytes(&p),
+                    LuaValue::String(_) | LuaValue::Nil => break,
+                    _ => lua_return!(LuaValue::Nil.into(), lua_string!("reader function must return a string").into())
+                }
+            }
+            chunk
+        },
+        _ => return LuaResult::Err(LuaError::ExpectedFunction)
+    };
+
+    load_chunk(vm, chunk.as_bytes())
+}
+
+#[allow(clippy::mutable_key_type)]
+pub fn make(vm: &VirtualMachine) -> BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>> {
+    let (output, print_config) = (vm.output.clone(), vm.print_config.clone());
+    let (loadstring_vm, load_vm) = (vm.clone(), vm.clone());
+    let (loadfile_vm, dofile_vm) = (vm.clone(), vm.clone());
+
     lua_table! {
-        lua_string!("print") => lua_function!(print).into(),
+        lua_string!("print") => lua_function!(move |args| print(&output, &print_config, args)).into(),
         lua_string!("error") => lua_function!(error).into(),
-        lua_string!("tostring") => lua_function!(tostring).into()
+        lua_string!("pcall") => lua_function!(pcall).into(),
+        lua_string!("xpcall") => lua_function!(xpcall).into(),
+        lua_string!("tostring") => lua_function!(tostring).into(),
+        lua_string!("type") => lua_function!(lua_type).into(),
+        lua_string!("getmetatable") => lua_function!(getmetatable).into(),
+        lua_string!("setmetatable") => lua_function!(setmetatable).into(),
+        lua_string!("select") => lua_function!(select).into(),
+        lua_string!("collectgarbage") => lua_function!(collectgarbage).into(),
+        lua_string!("next") => lua_function!(next).into(),
+        lua_string!("pairs") => lua_function!(pairs).into(),
+        lua_string!("ipairs") => lua_function!(ipairs).into(),
+        lua_string!("loadstring") => lua_function!(move |args| loadstring(&loadstring_vm, args)).into(),
+        lua_string!("load") => lua_function!(move |args| load(&load_vm, args)).into(),
+        lua_string!("loadfile") => lua_function!(move |args| loadfile(&loadfile_vm, args)).into(),
+        lua_string!("dofile") => lua_function!(move |args| dofile(&dofile_vm, args)).into()
     }
 }
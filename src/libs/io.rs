@@ -0,0 +1,80 @@
+use std::{cell::RefCell, collections::BTreeMap, io::{BufRead, Read}, rc::Rc};
+
+use crate::{lua_function, lua_return, lua_string, lua_table, types::{LuaError, function::{LuaFunctionArgs, LuaFunctionReturn}, LuaResult, string::LuaString, value::LuaValue}, vm::{OutputSink, VirtualMachine}};
+
+// Unlike print, no separators or trailing newline are written
+pub fn write(output: &Rc<RefCell<OutputSink>>, args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let mut s = LuaString::default();
+    for arg in args {
+        match &*arg.borrow() {
+            LuaValue::String(x) => s.push_bytes(x),
+            LuaValue::Number(n) => s.push_bytes(n.to_string().as_bytes()),
+            _ => return LuaResult::Err(LuaError::ExpectedString)
+        }
+    }
+
+    let sink = output.borrow().clone();
+    let mut sink = sink.borrow_mut();
+    if sink.write_all(&s).and_then(|_| sink.flush()).is_err() {
+        lua_return!(LuaValue::Nil.into(), lua_string!("failed to write output").into());
+    }
+
+    LuaResult::Ok(vec![])
+}
+
+// Supports the "*l" (default), "*n", "*a" formats and a byte count
+pub fn read(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let format = match args.first() {
+        Some(f) => f.borrow().clone(),
+        None => lua_string!("*l")
+    };
+
+    let mut stdin = std::io::stdin().lock();
+    let value = match format {
+        LuaValue::Number(n) => {
+            let mut buf = vec![0u8; n.0.max(0.0) as usize];
+            let read = stdin.read(&mut buf).unwrap_or(0);
+            if read == 0 && !buf.is_empty() {
+                LuaValue::Nil
+            } else {
+                lua_string!(&buf[..read])
+            }
+        },
+        LuaValue::String(f) if f.starts_with(b"*n") => {
+            let mut line = "".to_owned();
+            stdin.read_line(&mut line).unwrap_or(0);
+            match line.trim().parse::<f64>() {
+                Ok(n) => LuaValue::from(n),
+                Err(_) => LuaValue::Nil
+            }
+        },
+        LuaValue::String(f) if f.starts_with(b"*a") => {
+            let mut s = vec![];
+            stdin.read_to_end(&mut s).unwrap_or(0);
+            lua_string!(s)
+        },
+        LuaValue::String(f) if f.starts_with(b"*l") => {
+            let mut line = vec![];
+            match stdin.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => LuaValue::Nil,
+                Ok(_) => {
+                    let end = line.strip_suffix(b"\n").unwrap_or(&line);
+                    lua_string!(end.strip_suffix(b"\r").unwrap_or(end))
+                }
+            }
+        },
+        _ => return LuaResult::Err(LuaError::ExpectedString)
+    };
+
+    lua_return!(value.into());
+}
+
+#[allow(clippy::mutable_key_type)]
+pub fn make(vm: &VirtualMachine) -> BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>> {
+    let output = vm.output.clone();
+
+    lua_table! {
+        lua_string!("write") => lua_function!(move |args| write(&output, args)).into(),
+        lua_string!("read") => lua_function!(read).into()
+    }
+}
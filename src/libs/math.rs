@@ -0,0 +1,94 @@
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use crate::{libs::util::arg_number, lua_function, lua_return, lua_string, lua_table, types::{LuaError, function::{LuaFunctionArgs, LuaFunctionReturn}, LuaResult, value::LuaValue}, vm::VirtualMachine};
+
+fn unary(args: &LuaFunctionArgs, function: &'static str, op: fn(f64) -> f64) -> LuaFunctionReturn {
+    lua_return!(LuaValue::from(op(arg_number(args, 1, function)?)).into());
+}
+
+// Adding 0 turns -0 into 0, so math.ceil(-0.5) prints "0" and results are plain integer keys
+pub fn floor(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    unary(args, "floor", |x| x.floor() + 0.0)
+}
+
+pub fn ceil(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    unary(args, "ceil", |x| x.ceil() + 0.0)
+}
+
+// Integral and fractional part, both keep the sign of x
+pub fn modf(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let x = arg_number(args, 1, "modf")?;
+    let fractional = if x.is_infinite() { 0.0 } else { x - x.trunc() };
+    lua_return!(LuaValue::from(x.trunc()).into(), LuaValue::from(fractional).into());
+}
+
+// C fmod, the result has the sign of the dividend unlike the % operator
+pub fn fmod(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let (a, b) = (arg_number(args, 1, "fmod")?, arg_number(args, 2, "fmod")?);
+    lua_return!(LuaValue::from(a % b).into());
+}
+
+// Like math_min/math_max, later arguments replace the result only when the comparison holds. A NaN
+// first argument is therefore returned as is and NaNs after it are skipped
+fn extreme(args: &LuaFunctionArgs, function: &'static str, replaces: fn(f64, f64) -> bool) -> LuaFunctionReturn {
+    let mut result = arg_number(args, 1, function)?;
+    for n in 2..=args.len() {
+        let x = arg_number(args, n, function)?;
+        if replaces(x, result) {
+            result = x;
+        }
+    }
+    lua_return!(LuaValue::from(result).into());
+}
+
+pub fn min(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    extreme(args, "min", |x, result| x < result)
+}
+
+pub fn max(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    extreme(args, "max", |x, result| x > result)
+}
+
+// No arguments gives a float in [0, 1), otherwise an integer in [1, m] or [m, n]
+// Bounds are truncated like luaL_checkint, random_range samples without modulo bias
+pub fn random(rng: &Rc<RefCell<StdRng>>, args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let mut rng = rng.borrow_mut();
+    let (m, n) = match args.len() {
+        0 => lua_return!(LuaValue::from(rng.random::<f64>()).into()),
+        1 => (1, arg_number(args, 1, "random")? as i64),
+        2 => (arg_number(args, 1, "random")? as i64, arg_number(args, 2, "random")? as i64),
+        _ => return LuaResult::Err(LuaError::WrongNumberOfArguments)
+    };
+
+    if m > n {
+        return LuaResult::Err(LuaError::EmptyInterval);
+    }
+    lua_return!(LuaValue::from(rng.random_range(m..=n) as f64).into());
+}
+
+// The same seed always produces the same sequence
+pub fn randomseed(rng: &Rc<RefCell<StdRng>>, args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    *rng.borrow_mut() = StdRng::seed_from_u64(arg_number(args, 1, "randomseed")? as i64 as u64);
+    LuaResult::Ok(vec![])
+}
+
+#[allow(clippy::mutable_key_type)]
+pub fn make(vm: &VirtualMachine) -> BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>> {
+    let (random_rng, seed_rng) = (vm.rng.clone(), vm.rng.clone());
+
+    lua_table! {
+        lua_string!("random") => lua_function!(move |args| random(&random_rng, args)).into(),
+        lua_string!("randomseed") => lua_function!(move |args| randomseed(&seed_rng, args)).into(),
+        lua_string!("floor") => lua_function!(floor).into(),
+        lua_string!("ceil") => lua_function!(ceil).into(),
+        lua_string!("modf") => lua_function!(modf).into(),
+        lua_string!("fmod") => lua_function!(fmod).into(),
+        lua_string!("min") => lua_function!(min).into(),
+        lua_string!("max") => lua_function!(max).into(),
+        lua_string!("log") => lua_function!(|args| unary(args, "log", f64::ln)).into(),
+        lua_string!("log10") => lua_function!(|args| unary(args, "log10", f64::log10)).into(),
+        lua_string!("exp") => lua_function!(|args| unary(args, "exp", f64::exp)).into()
+    }
+}
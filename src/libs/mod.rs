@@ -1 +1,11 @@
+pub mod bit;
+pub mod debug;
+pub mod format;
 pub mod global;
+pub mod io;
+pub mod math;
+pub mod os;
+pub mod package;
+pub mod pattern;
+pub mod string;
+pub mod util;
\ No newline at end of file
@@ -0,0 +1,41 @@
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use crate::{lua_function, lua_return, lua_string, lua_table, types::{LuaError, function::{LuaFunctionArgs, LuaFunctionReturn}, LuaResult, value::LuaValue}, vm::{ExitHandler, VirtualMachine}};
+
+pub fn getenv(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let name = match args.first().map(|v| v.borrow().clone()) {
+        Some(LuaValue::String(s)) => s,
+        Some(LuaValue::Number(n)) => n.to_string().into(),
+        _ => return LuaResult::Err(LuaError::ExpectedString)
+    };
+
+    match std::env::var_os(name.to_str_lossy().as_ref()) {
+        Some(v) => lua_return!(lua_string!(v.to_string_lossy().as_ref()).into()),
+        None => lua_return!(LuaValue::Nil.into())
+    }
+}
+
+// true exits with 0 and false with 1, like EXIT_SUCCESS / EXIT_FAILURE
+// Returns only if the exit handler does, the script is then stopped with ExitRequested
+pub fn exit(handler: &Rc<RefCell<ExitHandler>>, args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let code = match args.first().map(|v| v.borrow().clone()) {
+        None | Some(LuaValue::Nil) | Some(LuaValue::Boolean(true)) => 0,
+        Some(LuaValue::Boolean(false)) => 1,
+        Some(LuaValue::Number(n)) => n.0 as i32,
+        _ => return LuaResult::Err(LuaError::ExpectedNumber)
+    };
+
+    let handler = handler.borrow().clone();
+    handler(code);
+    LuaResult::Err(LuaError::ExitRequested(code))
+}
+
+#[allow(clippy::mutable_key_type)]
+pub fn make(vm: &VirtualMachine) -> BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>> {
+    let exit_handler = vm.exit_handler.clone();
+
+    lua_table! {
+        lua_string!("getenv") => lua_function!(getenv).into(),
+        lua_string!("exit") => lua_function!(move |args| exit(&exit_handler, args)).into()
+    }
+}
@@ -0,0 +1,53 @@
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use crate::{lua_return, lua_string, lua_table, types::{LuaError, function::{LuaFunctionArgs, LuaFunctionReturn}, LuaResult, table::LuaTable, value::LuaValue}};
+
+// Field of the package table, the script may have replaced it with a non-table
+fn field(package: &LuaTable, name: &str) -> LuaResult<LuaTable> {
+    match package.get(&lua_string!(name)).map(|v| v.borrow().clone()) {
+        Some(LuaValue::Table(t)) => LuaResult::Ok(t),
+        _ => LuaResult::Err(LuaError::ExpectedTable)
+    }
+}
+
+// Only package.preload is searched, there are no file loaders
+pub fn require(package: &LuaTable, args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let name = match args.first().map(|v| v.borrow().clone()) {
+        Some(LuaValue::String(s)) => s,
+        Some(LuaValue::Number(n)) => n.to_string().into(),
+        _ => return LuaResult::Err(LuaError::ExpectedString)
+    };
+    let key = LuaValue::from(name.clone());
+
+    let loaded = field(package, "loaded")?;
+    if let Some(module) = loaded.get(&key) && !matches!(*module.borrow(), LuaValue::Nil | LuaValue::Boolean(false)) {
+        lua_return!(module);
+    }
+
+    let loader = match field(package, "preload")?.get(&key).map(|v| v.borrow().clone()) {
+        Some(LuaValue::Nil) | None => return LuaResult::Err(LuaError::ModuleNotFound(name.to_string())),
+        Some(loader) => loader
+    };
+
+    // A non-nil result becomes the module. Otherwise the loader may have set package.loaded[name] itself,
+    // and only if it didn't is the module marked as loaded with true, like Lua does
+    if let Some(module) = loader.call(vec![key.clone().into()])?.first()
+        && !matches!(*module.borrow(), LuaValue::Nil) {
+        loaded.insert(key.clone().into(), module.borrow().clone().into());
+    }
+    let module = match loaded.get(&key).map(|v| v.borrow().clone()) {
+        Some(LuaValue::Nil) | None => LuaValue::Boolean(true),
+        Some(module) => module
+    };
+    loaded.insert(key.into(), module.clone().into());
+    lua_return!(module.into());
+}
+
+#[allow(clippy::mutable_key_type)]
+pub fn make() -> BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>> {
+    lua_table! {
+        lua_string!("loaded") => LuaValue::from(LuaTable::new(BTreeMap::new())),
+        lua_string!("preload") => LuaValue::from(LuaTable::new(BTreeMap::new()))
+    }
+}
+
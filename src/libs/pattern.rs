@@ -0,0 +1,353 @@
+// Port of the Lua 5.1 pattern matcher (lstrlib.c)
+// Works on raw bytes, indices are 0-based byte offsets into the source string
+
+use crate::types::{LuaError, LuaResult};
+
+pub const MAX_CAPTURES: usize = 32;
+
+const CAP_UNFINISHED: isize = -1;
+const CAP_POSITION: isize = -2;
+const L_ESC: u8 = b'%';
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Capture {
+    // Byte range [start, end) of the source string
+    Substring(usize, usize),
+    // Position capture `()`, 1-based like Lua
+    Position(usize)
+}
+
+pub struct MatchState<'a> {
+    src: &'a [u8],
+    pattern: &'a [u8],
+    level: usize,
+    captures: [(usize, isize); MAX_CAPTURES]
+}
+
+impl<'a> MatchState<'a> {
+    pub fn new(src: &'a [u8], pattern: &'a [u8]) -> Self {
+        Self {
+            src,
+            pattern,
+            level: 0,
+            captures: [(0, 0); MAX_CAPTURES]
+        }
+    }
+
+    // Try to match the pattern (starting at pattern offset p) at source offset s
+    // Returns the end offset of the match
+    pub fn try_match(&mut self, s: usize, p: usize) -> LuaResult<Option<usize>> {
+        self.level = 0;
+        self.do_match(s, p)
+    }
+
+    // Collect the captures of the last successful match spanning [s, e)
+    // If the pattern has no captures and whole_if_none is set, the whole match is returned
+    pub fn captures(&self, s: usize, e: usize, whole_if_none: bool) -> LuaResult<Vec<Capture>> {
+        let count = if self.level == 0 && whole_if_none { 1 } else { self.level };
+        let mut captures = Vec::with_capacity(count);
+        for i in 0..count {
+            captures.push(self.capture(i, s, e)?);
+        }
+        LuaResult::Ok(captures)
+    }
+
+    pub fn capture(&self, i: usize, s: usize, e: usize) -> LuaResult<Capture> {
+        if i >= self.level {
+            if i == 0 {
+                return LuaResult::Ok(Capture::Substring(s, e));
+            }
+            return LuaResult::Err(LuaError::MalformedPattern("invalid capture index"));
+        }
+
+        let (start, len) = self.captures[i];
+        match len {
+            CAP_UNFINISHED => LuaResult::Err(LuaError::MalformedPattern("unfinished capture")),
+            CAP_POSITION => LuaResult::Ok(Capture::Position(start + 1)),
+            l => LuaResult::Ok(Capture::Substring(start, start + l as usize))
+        }
+    }
+
+    fn class_end(&self, mut p: usize) -> LuaResult<usize> {
+        let c = self.pattern[p];
+        p += 1;
+
+        if c == L_ESC {
+            if p >= self.pattern.len() {
+                return LuaResult::Err(LuaError::MalformedPattern("malformed pattern (ends with '%')"));
+            }
+            return LuaResult::Ok(p + 1);
+        }
+
+        if c == b'[' {
+            if self.pattern.get(p) == Some(&b'^') {
+                p += 1;
+            }
+
+            // look for a ']', the first character is always part of the set
+            loop {
+                if p >= self.pattern.len() {
+                    return LuaResult::Err(LuaError::MalformedPattern("malformed pattern (missing ']')"));
+                }
+                let c = self.pattern[p];
+                p += 1;
+                if c == L_ESC && p < self.pattern.len() {
+                    p += 1;
+                }
+                if self.pattern.get(p) == Some(&b']') {
+                    break;
+                }
+            }
+
+            return LuaResult::Ok(p + 1);
+        }
+
+        LuaResult::Ok(p)
+    }
+
+    // p points at the '[' and ec at the closing ']'
+    fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+        let mut sig = true;
+        if self.pattern[p + 1] == b'^' {
+            sig = false;
+            p += 1;
+        }
+
+        p += 1;
+        while p < ec {
+            if self.pattern[p] == L_ESC {
+                p += 1;
+                if match_class(c, self.pattern[p]) {
+                    return sig;
+                }
+            } else if self.pattern[p + 1] == b'-' && p + 2 < ec {
+                p += 2;
+                if self.pattern[p - 2] <= c && c <= self.pattern[p] {
+                    return sig;
+                }
+            } else if self.pattern[p] == c {
+                return sig;
+            }
+            p += 1;
+        }
+
+        !sig
+    }
+
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let c = match self.src.get(s) {
+            Some(c) => *c,
+            None => return false
+        };
+
+        match self.pattern[p] {
+            b'.' => true,
+            L_ESC => match_class(c, self.pattern[p + 1]),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c
+        }
+    }
+
+    fn do_match(&mut self, mut s: usize, mut p: usize) -> LuaResult<Option<usize>> {
+        loop {
+            if p == self.pattern.len() {
+                return LuaResult::Ok(Some(s));
+            }
+
+            match self.pattern[p] {
+                b'(' => {
+                    return if self.pattern.get(p + 1) == Some(&b')') {
+                        self.start_capture(s, p + 2, CAP_POSITION)
+                    } else {
+                        self.start_capture(s, p + 1, CAP_UNFINISHED)
+                    };
+                },
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == self.pattern.len() => {
+                    return LuaResult::Ok(if s == self.src.len() { Some(s) } else { None });
+                },
+                L_ESC if self.pattern.get(p + 1) == Some(&b'b') => {
+                    match self.match_balance(s, p + 2)? {
+                        Some(e) => {
+                            s = e;
+                            p += 4;
+                            continue;
+                        },
+                        None => return LuaResult::Ok(None)
+                    }
+                },
+                L_ESC if self.pattern.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pattern.get(p) != Some(&b'[') {
+                        return LuaResult::Err(LuaError::MalformedPattern("missing '[' after '%f' in pattern"));
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).copied().unwrap_or(0);
+                    if !self.match_bracket_class(previous, p, ep - 1) && self.match_bracket_class(current, p, ep - 1) {
+                        p = ep;
+                        continue;
+                    }
+                    return LuaResult::Ok(None);
+                },
+                L_ESC if self.pattern.get(p + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                    match self.match_capture(s, self.pattern[p + 1])? {
+                        Some(e) => {
+                            s = e;
+                            p += 2;
+                            continue;
+                        },
+                        None => return LuaResult::Ok(None)
+                    }
+                },
+                _ => {}
+            }
+
+            // single character class, possibly followed by a repetition suffix
+            let ep = self.class_end(p)?;
+            let m = self.single_match(s, p, ep);
+            match self.pattern.get(ep) {
+                Some(b'?') => {
+                    if m && let Some(e) = self.do_match(s + 1, ep + 1)? {
+                        return LuaResult::Ok(Some(e));
+                    }
+                    p = ep + 1;
+                },
+                Some(b'*') => return self.max_expand(s, p, ep),
+                Some(b'+') => return if m { self.max_expand(s + 1, p, ep) } else { LuaResult::Ok(None) },
+                Some(b'-') => return self.min_expand(s, p, ep),
+                _ => {
+                    if !m {
+                        return LuaResult::Ok(None);
+                    }
+                    s += 1;
+                    p = ep;
+                }
+            }
+        }
+    }
+
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> LuaResult<Option<usize>> {
+        let mut i = 0usize;
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+
+        // try with the maximum repetitions, then backtrack one at a time
+        loop {
+            if let Some(e) = self.do_match(s + i, ep + 1)? {
+                return LuaResult::Ok(Some(e));
+            }
+            if i == 0 {
+                return LuaResult::Ok(None);
+            }
+            i -= 1;
+        }
+    }
+
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> LuaResult<Option<usize>> {
+        loop {
+            if let Some(e) = self.do_match(s, ep + 1)? {
+                return LuaResult::Ok(Some(e));
+            }
+            if self.single_match(s, p, ep) {
+                s += 1;
+            } else {
+                return LuaResult::Ok(None);
+            }
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: isize) -> LuaResult<Option<usize>> {
+        if self.level >= MAX_CAPTURES {
+            return LuaResult::Err(LuaError::MalformedPattern("too many captures"));
+        }
+
+        self.captures[self.level] = (s, what);
+        self.level += 1;
+
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.level -= 1;
+        }
+        LuaResult::Ok(res)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> LuaResult<Option<usize>> {
+        let l = self.capture_to_close()?;
+        self.captures[l].1 = (s - self.captures[l].0) as isize;
+
+        let res = self.do_match(s, p)?;
+        if res.is_none() {
+            self.captures[l].1 = CAP_UNFINISHED;
+        }
+        LuaResult::Ok(res)
+    }
+
+    fn capture_to_close(&self) -> LuaResult<usize> {
+        for l in (0..self.level).rev() {
+            if self.captures[l].1 == CAP_UNFINISHED {
+                return LuaResult::Ok(l);
+            }
+        }
+        LuaResult::Err(LuaError::MalformedPattern("invalid pattern capture"))
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> LuaResult<Option<usize>> {
+        if p + 1 >= self.pattern.len() {
+            return LuaResult::Err(LuaError::MalformedPattern("unbalanced pattern"));
+        }
+
+        let (open, close) = (self.pattern[p], self.pattern[p + 1]);
+        if self.src.get(s) != Some(&open) {
+            return LuaResult::Ok(None);
+        }
+
+        let mut depth = 1;
+        for i in s + 1..self.src.len() {
+            if self.src[i] == close {
+                depth -= 1;
+                if depth == 0 {
+                    return LuaResult::Ok(Some(i + 1));
+                }
+            } else if self.src[i] == open {
+                depth += 1;
+            }
+        }
+
+        LuaResult::Ok(None)
+    }
+
+    fn match_capture(&self, s: usize, l: u8) -> LuaResult<Option<usize>> {
+        let l = (l as usize).wrapping_sub(b'1' as usize);
+        if l >= self.level || self.captures[l].1 == CAP_UNFINISHED {
+            return LuaResult::Err(LuaError::MalformedPattern("invalid capture index"));
+        }
+
+        let (start, len) = self.captures[l];
+        let len = len as usize;
+        if self.src.len() - s >= len && self.src[start..start + len] == self.src[s..s + len] {
+            LuaResult::Ok(Some(s + len))
+        } else {
+            LuaResult::Ok(None)
+        }
+    }
+}
+
+fn match_class(c: u8, class: u8) -> bool {
+    let res = match class.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        b's' => c.is_ascii_whitespace() || c == 0x0B,
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        b'z' => c == 0,
+        _ => return class == c
+    };
+
+    if class.is_ascii_uppercase() { !res } else { res }
+}
@@ -0,0 +1,217 @@
+use std::{cell::{Cell, RefCell}, collections::BTreeMap, rc::Rc};
+
+use crate::{libs::{format::{self, parse_spec}, global, pattern::{Capture, MatchState}, util::{arg_number, arg_string, opt_number}}, lua_function, lua_return, lua_string, lua_table, types::{LuaError, function::{LuaFunctionArgs, LuaFunctionReturn}, LuaResult, string::LuaString, value::LuaValue}};
+
+// Numbers are accepted wherever a string is expected, like in Lua
+fn check_string(args: &LuaFunctionArgs, n: usize) -> LuaResult<LuaString> {
+    let arg = match args.get(n) {
+        Some(a) => a,
+        None => return LuaResult::Err(LuaError::ExpectedArgument)
+    };
+
+    let s = match &*arg.borrow() {
+        LuaValue::String(s) => s.clone(),
+        LuaValue::Number(_) => global::tostring(&vec![arg.clone()])?[0].borrow().as_string()?.clone(),
+        _ => return LuaResult::Err(LuaError::ExpectedString)
+    };
+    LuaResult::Ok(s)
+}
+
+fn capture_to_value(src: &[u8], capture: Capture) -> Rc<RefCell<LuaValue>> {
+    match capture {
+        Capture::Substring(start, end) => lua_string!(&src[start..end]).into(),
+        Capture::Position(p) => LuaValue::Number((p as f64).into()).into()
+    }
+}
+
+// Characters that make a pattern need the matcher
+const SPECIALS: &[u8] = b"^$*+?.([%-";
+
+// Translate a relative (possibly negative) string position to an absolute one
+fn posrelat(pos: f64, len: usize) -> f64 {
+    if pos < 0.0 { len as f64 + pos + 1.0 } else { pos }
+}
+
+pub fn len(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let s = check_string(args, 0)?;
+    lua_return!(LuaValue::Number((s.len() as f64).into()).into());
+}
+
+pub fn upper(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let s = check_string(args, 0)?;
+    lua_return!(lua_string!(s.to_ascii_uppercase()).into());
+}
+
+pub fn lower(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let s = check_string(args, 0)?;
+    lua_return!(lua_string!(s.to_ascii_lowercase()).into());
+}
+
+// Reverses bytes, not characters
+pub fn reverse(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let mut bytes = check_string(args, 0)?.into_bytes();
+    bytes.reverse();
+    lua_return!(lua_string!(bytes).into());
+}
+
+// Byte codes of s[i..j], i defaults to 1 and j to i. Out of range positions are clamped like str_byte,
+// an empty range returns nothing
+pub fn byte(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let source = check_string(args, 0)?;
+    let src = source.as_bytes();
+
+    let i = posrelat(opt_number(args, 2, "byte")?.unwrap_or(1.0).trunc(), src.len());
+    let j = posrelat(opt_number(args, 3, "byte")?.map_or(i, f64::trunc), src.len());
+    let (i, j) = (i.max(1.0), j.min(src.len() as f64));
+    if i > j {
+        lua_return!();
+    }
+
+    LuaResult::Ok(src[i as usize - 1..j as usize].iter().map(|b| LuaValue::from(*b as f64).into()).collect())
+}
+
+pub fn find(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let source = check_string(args, 0)?;
+    let pattern = check_string(args, 1)?;
+    let (src, pat) = (source.as_bytes(), pattern.as_bytes());
+
+    let init = match args.get(2).map(|v| v.borrow().clone()) {
+        Some(LuaValue::Nil) | None => 1.0,
+        Some(v) => posrelat(*v.as_f64()?, src.len())
+    };
+    let init = (init - 1.0).clamp(0.0, src.len() as f64) as usize;
+
+    // Plain search, magic characters are taken literally
+    if args.get(3).is_some_and(|v| v.borrow().is_truthy()) || !pat.iter().any(|c| SPECIALS.contains(c)) {
+        let found = if pat.is_empty() {
+            Some(init)
+        } else {
+            src[init..].windows(pat.len()).position(|w| w == pat).map(|p| p + init)
+        };
+
+        return match found {
+            Some(start) => LuaResult::Ok(vec![LuaValue::from((start + 1) as f64).into(), LuaValue::from((start + pat.len()) as f64).into()]),
+            None => LuaResult::Ok(vec![LuaValue::Nil.into()])
+        };
+    }
+
+    let anchor = pat.first() == Some(&b'^');
+    let p = if anchor { 1 } else { 0 };
+    let mut ms = MatchState::new(src, pat);
+    let mut start = init;
+
+    loop {
+        if let Some(end) = ms.try_match(start, p)? {
+            let mut results: Vec<Rc<RefCell<LuaValue>>> = vec![LuaValue::from((start + 1) as f64).into(), LuaValue::from(end as f64).into()];
+            results.extend(ms.captures(start, end, false)?.into_iter().map(|c| capture_to_value(src, c)));
+            return LuaResult::Ok(results);
+        }
+
+        start += 1;
+        if anchor || start > src.len() {
+            break;
+        }
+    }
+
+    lua_return!(LuaValue::Nil.into());
+}
+
+// Captures of the first match at or after `position`, which is moved past it. Once the source is
+// exhausted `position` stays past the end, so later calls keep returning None instead of restarting
+fn next_match(src: &[u8], pattern: &[u8], position: &mut usize) -> LuaResult<Option<Vec<Rc<RefCell<LuaValue>>>>> {
+    let mut ms = MatchState::new(src, pattern);
+
+    while *position <= src.len() {
+        let start = *position;
+        if let Some(end) = ms.try_match(start, 0)? {
+            // Empty match, advance at least one position to avoid matching it again
+            *position = if end == start { end + 1 } else { end };
+            return LuaResult::Ok(Some(ms.captures(start, end, true)?.into_iter().map(|c| capture_to_value(src, c)).collect()));
+        }
+        *position += 1;
+    }
+
+    LuaResult::Ok(None)
+}
+
+// Every match gmatch would produce, for embedders splitting strings without driving the iterator
+pub fn match_all(source: &LuaString, pattern: &LuaString) -> LuaResult<Vec<Vec<Rc<RefCell<LuaValue>>>>> {
+    let mut position = 0usize;
+    let mut matches = vec![];
+    while let Some(captures) = next_match(source.as_bytes(), pattern.as_bytes(), &mut position)? {
+        matches.push(captures);
+    }
+    LuaResult::Ok(matches)
+}
+
+pub fn gmatch(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let source = check_string(args, 0)?;
+    let pattern = check_string(args, 1)?;
+
+    // The iterator only keeps the byte offset where the next search starts,
+    // matches are produced lazily on every call
+    let position = Cell::new(0usize);
+    let iterator = lua_function!(move |_args| {
+        let mut next = position.get();
+        let captures = next_match(source.as_bytes(), pattern.as_bytes(), &mut next)?;
+        position.set(next);
+        match captures {
+            Some(captures) => LuaResult::Ok(captures),
+            // Exhausted, TForLoop sees nil and stops
+            None => lua_return!()
+        }
+    });
+
+    lua_return!(LuaValue::Function(iterator).into())
+}
+
+// Conversions take the arguments after the format string in order, %q writes a string literal that
+// reads back as the same string
+pub fn format(args: &LuaFunctionArgs) -> LuaFunctionReturn {
+    let format = arg_string(args, 1, "format")?;
+    let format = format.as_bytes();
+
+    let mut out = vec![];
+    let mut arg = 1;
+    let mut i = 0;
+    while i < format.len() {
+        if format[i] != b'%' {
+            out.push(format[i]);
+            i += 1;
+            continue;
+        }
+        if format.get(i + 1) == Some(&b'%') {
+            out.push(b'%');
+            i += 2;
+            continue;
+        }
+
+        i += 1;
+        let spec = parse_spec(format, &mut i)?;
+        arg += 1;
+        match spec.conversion {
+            b'c' => out.extend(spec.char(arg_number(args, arg, "format")?)),
+            b'd' | b'i' | b'o' | b'u' | b'x' | b'X' => out.extend(spec.integer(arg_number(args, arg, "format")?)),
+            b'e' | b'E' | b'f' | b'g' | b'G' => out.extend(spec.float(arg_number(args, arg, "format")?)),
+            b'q' => format::add_quoted(&mut out, arg_string(args, arg, "format")?.as_bytes()),
+            b's' => out.extend(spec.string(arg_string(args, arg, "format")?.as_bytes())),
+            c => return LuaResult::Err(LuaError::InvalidOption(format!("invalid option '%{}' to 'format'", c as char)))
+        }
+    }
+
+    lua_return!(lua_string!(out).into());
+}
+
+#[allow(clippy::mutable_key_type)]
+pub fn make() -> BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>> {
+    lua_table! {
+        lua_string!("len") => lua_function!(len).into(),
+        lua_string!("upper") => lua_function!(upper).into(),
+        lua_string!("lower") => lua_function!(lower).into(),
+        lua_string!("reverse") => lua_function!(reverse).into(),
+        lua_string!("byte") => lua_function!(byte).into(),
+        lua_string!("find") => lua_function!(find).into(),
+        lua_string!("gmatch") => lua_function!(gmatch).into(),
+        lua_string!("format") => lua_function!(format).into()
+    }
+}
@@ -0,0 +1,55 @@
+// Argument checks for native functions, `n` is the 1-based position used in the error message
+// like luaL_checknumber and friends
+
+use std::{cell::RefCell, rc::Rc};
+
+use crate::types::{LuaError, function::LuaFunctionArgs, LuaResult, string::LuaString, table::LuaTable, value::LuaValue};
+
+fn bad_argument(n: usize, function: &'static str, expected: &str, got: Option<&LuaValue>) -> LuaError {
+    let got = got.map(|v| v.type_name()).unwrap_or("no value");
+    LuaError::BadArgument(n, function, format!("{} expected, got {}", expected, got))
+}
+
+// Any value, nil included, as long as it was passed
+pub fn arg_value(args: &LuaFunctionArgs, n: usize, function: &'static str) -> LuaResult<Rc<RefCell<LuaValue>>> {
+    match args.get(n - 1) {
+        Some(v) => LuaResult::Ok(v.clone()),
+        None => LuaResult::Err(LuaError::BadArgument(n, function, "value expected".to_owned()))
+    }
+}
+
+// Numeric strings are converted like they are in arithmetic
+pub fn arg_number(args: &LuaFunctionArgs, n: usize, function: &'static str) -> LuaResult<f64> {
+    let arg = args.get(n - 1).map(|v| v.borrow().clone());
+    match &arg {
+        Some(LuaValue::Number(x)) => LuaResult::Ok(x.0),
+        Some(LuaValue::String(s)) => s.to_number().ok_or_else(|| bad_argument(n, function, "number", arg.as_ref())),
+        _ => LuaResult::Err(bad_argument(n, function, "number", arg.as_ref()))
+    }
+}
+
+// Numbers are converted to their string form
+pub fn arg_string(args: &LuaFunctionArgs, n: usize, function: &'static str) -> LuaResult<LuaString> {
+    let arg = args.get(n - 1).map(|v| v.borrow().clone());
+    match arg {
+        Some(LuaValue::String(s)) => LuaResult::Ok(s),
+        Some(LuaValue::Number(x)) => LuaResult::Ok(x.to_string().into()),
+        arg => LuaResult::Err(bad_argument(n, function, "string", arg.as_ref()))
+    }
+}
+
+pub fn arg_table(args: &LuaFunctionArgs, n: usize, function: &'static str) -> LuaResult<LuaTable> {
+    let arg = args.get(n - 1).map(|v| v.borrow().clone());
+    match arg {
+        Some(LuaValue::Table(t)) => LuaResult::Ok(t),
+        arg => LuaResult::Err(bad_argument(n, function, "table", arg.as_ref()))
+    }
+}
+
+// None when the argument is missing or nil
+pub fn opt_number(args: &LuaFunctionArgs, n: usize, function: &'static str) -> LuaResult<Option<f64>> {
+    match args.get(n - 1).map(|v| v.borrow().clone()) {
+        None | Some(LuaValue::Nil) => LuaResult::Ok(None),
+        Some(_) => arg_number(args, n, function).map(Some)
+    }
+}
@@ -1,14 +1,15 @@
-use lua51_vm::{bytecode, vm::VirtualMachine};
-use tokio::{fs::File, io::BufReader};
+use lua51_vm::{types::value::LuaValue, vm::VirtualMachine};
 
 #[tokio::main]
 async fn main() {
-    let file = File::open("luac.out").await.unwrap();
-    let mut reader = BufReader::new(file);
-    let f = bytecode::read_bytecode(&mut reader).await;
+    let bytes = tokio::fs::read("luac.out").await.unwrap();
+    let chunk = VirtualMachine::load_and_validate(&bytes).unwrap();
+
+    // Command line arguments are passed to the chunk as `...`
+    let args: Vec<LuaValue> = std::env::args().skip(1).map(LuaValue::from).collect();
 
     let mut vm = VirtualMachine::new();
     vm.load_std_libraries().unwrap();
-    let r = vm.execute(f.unwrap(), None, None);
+    let r = vm.run_main(chunk, &args);
     print!("{}", r);
 }
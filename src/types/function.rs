@@ -1,29 +1,68 @@
-use std::{cell::RefCell, rc::Rc, sync::{Arc, Mutex}};
+use std::{cell::RefCell, rc::Rc};
 
 use rand::RngCore;
 
+use crate::{bytecode::LuaPrototype, vm::VirtualMachine};
+
 use super::{LuaResult, value::LuaValue};
 
-// Rust:tm:
-type HandlerFn = Arc<Mutex<Box<dyn FnMut(&Vec<Rc<RefCell<LuaValue>>>) -> LuaResult<Vec<Rc<RefCell<LuaValue>>>>>>>;
+// Shared rather than locked, so a native can be re-entered while it runs (pcall(pcall, f), a
+// __tostring calling tostring). Natives with state keep it in a Cell or RefCell of their own
+type HandlerFn = Rc<dyn Fn(&Vec<Rc<RefCell<LuaValue>>>) -> LuaResult<Vec<Rc<RefCell<LuaValue>>>>>;
+
+// A function compiled from bytecode, executed by the VM it was created in
+#[derive(Clone)]
+pub struct LuaClosure {
+    pub prototype: Rc<LuaPrototype>,
+    pub upvalues: Vec<Rc<RefCell<LuaValue>>>,
+    pub vm: VirtualMachine
+}
+
+#[derive(Clone)]
+enum FunctionKind {
+    Native(HandlerFn),
+    // Kept separate from native handlers so the VM can inspect it (tail calls)
+    Lua(LuaClosure)
+}
 
 #[derive(Clone)]
 pub struct LuaFunction {
     // Unique id for every function - allows us to implement Eq
     id: u64,
-    handler: HandlerFn
+    kind: FunctionKind
 }
 
 impl LuaFunction {
     pub fn new(handler: HandlerFn) -> Self {
         Self {
             id: rand::rng().next_u64(),
-            handler
+            kind: FunctionKind::Native(handler)
+        }
+    }
+
+    pub fn from_closure(closure: LuaClosure) -> Self {
+        Self {
+            id: rand::rng().next_u64(),
+            kind: FunctionKind::Lua(closure)
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn closure(&self) -> Option<&LuaClosure> {
+        match &self.kind {
+            FunctionKind::Lua(c) => Some(c),
+            FunctionKind::Native(_) => None
         }
     }
 
     pub fn invoke(&self, args: &Vec<Rc<RefCell<LuaValue>>>) -> LuaResult<Vec<Rc<RefCell<LuaValue>>>> {
-        (self.handler.lock().unwrap())(args)
+        match &self.kind {
+            FunctionKind::Native(handler) => handler(args),
+            FunctionKind::Lua(c) => c.vm.clone().execute_closure(c.prototype.clone(), args.to_vec(), c.upvalues.clone()).inner
+        }
     }
 }
 
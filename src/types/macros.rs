@@ -1,13 +1,14 @@
 #[macro_export]
 macro_rules! lua_function {
     ( $func:expr ) => {
-        crate::types::function::LuaFunction::new(std::sync::Arc::new(std::sync::Mutex::new(Box::new($func))))
+        $crate::types::function::LuaFunction::new(std::rc::Rc::new($func))
     };
 }
 
 #[macro_export]
 macro_rules! lua_table {
     ( $( $key:expr => $value:expr ),* $(,)? ) => {{
+        #[allow(clippy::mutable_key_type)]
         let mut map = std::collections::BTreeMap::new();
 
         $(
@@ -1,36 +1,95 @@
 pub mod value;
 pub mod number;
+pub mod string;
 pub mod function;
+pub mod table;
+pub mod userdata;
 pub mod macros;
 
+use crate::bytecode::OpCode;
+
 #[derive(Debug)]
 pub enum LuaError {
-    UnsupportedArithmeticOperation,
+    // Type name of the offending value and its operand position (1 or 2)
+    UnsupportedArithmeticOperation(&'static str, usize),
     AttemptedNullCall,
     AttemptedTableCall,
     AttemptedBooleanConcatenation,
     AttemptedFunctionConcatenation,
     AttemptedTableConcatenation,
+    AttemptedUserdataConcatenation,
     AttemptedNilConcatenation,
-    AttemptedIndexOfNonTable,
+    // Type name of the indexed value
+    AttemptedIndexOfNonTable(&'static str),
     AttemptedNotOperationOnNonBoolean,
-    UnsupportedLengthOperation,
+    // Type name of the operand
+    UnsupportedLengthOperation(&'static str),
     ParseFloatError(std::num::ParseFloatError),
     ConstantNotFound(usize),
     UpValueNotFound(usize),
     AttemptedCallOnUnsupportedType,
+    // Type name of the called value and how it was named if known, e.g. ("global", "foo")
+    AttemptedCall(&'static str, Option<(&'static str, String)>),
     ExpectedArgument,
     ExpectedNumber,
     ExpectedString,
     ExpectedBoolean,
     ExpectedTable,
     ExpectedFunction,
+    MalformedPattern(&'static str),
+    ProtectedMetatable,
+    IndexOutOfRange,
+    // math.random bounds with m > n
+    EmptyInterval,
+    WrongNumberOfArguments,
+    // "nil" or "NaN", neither can be used as a table key
+    InvalidTableIndex(&'static str),
+    StackOverflow,
+    CallDepthExceeded,
+    AttemptedInvalidComparison,
+    ChunkLoadFailed(String),
+    InvalidOption(String),
+    // Read in strict globals mode of a global that was never assigned
+    UndefinedGlobal(String),
+    // Name passed to require, with no entry in package.preload
+    ModuleNotFound(String),
+    // Argument position, function name and the problem, e.g. "number expected, got string"
+    BadArgument(usize, &'static str, String),
+    // "initial value", "limit" or "step"
+    ForLoopExpectedNumber(&'static str),
+    // A zero step would never reach the limit, like "'for' step is zero" in later Lua versions
+    ForLoopZeroStep,
+    // More than MAX_TAG_LOOP __index or __newindex tables in a row, usually a cycle
+    IndexChainTooLong,
+    // Pc of an instruction whose operands can't be run, only possible in bytecode that skipped validation
+    MalformedInstruction(usize),
+    // Reason given by the instruction hook
+    AbortedByHook(String),
+    // os.exit was intercepted by the VM's exit handler
+    ExitRequested(i32),
     TriggeredByUser((String, Option<f64>))
 }
 
+impl LuaError {
+    // Errors that stop the script rather than report a problem in it, pcall and xpcall pass them on
+    pub fn is_catchable(&self) -> bool {
+        !matches!(self, LuaError::ExitRequested(_) | LuaError::AbortedByHook(_))
+    }
+}
+
 impl std::fmt::Display for LuaError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self)
+        match self {
+            LuaError::BadArgument(n, function, msg) => write!(f, "bad argument #{} to '{}' ({})", n, function, msg),
+            LuaError::AttemptedCall(type_name, Some((kind, name))) => write!(f, "attempt to call a {} value ({} '{}')", type_name, kind, name),
+            LuaError::AttemptedCall(type_name, None) => write!(f, "attempt to call a {} value", type_name),
+            LuaError::UnsupportedArithmeticOperation(type_name, _) => write!(f, "attempt to perform arithmetic on a {} value", type_name),
+            LuaError::AttemptedIndexOfNonTable(type_name) => write!(f, "attempt to index a {} value", type_name),
+            LuaError::UnsupportedLengthOperation(type_name) => write!(f, "attempt to get length of a {} value", type_name),
+            LuaError::ForLoopExpectedNumber(what) => write!(f, "'for' {} must be a number", what),
+            LuaError::ForLoopZeroStep => write!(f, "'for' step is zero"),
+            e => write!(f, "{:?}", e)
+        }
     }
 }
 
@@ -48,14 +107,25 @@ pub type LuaResult<T> = Result<T, LuaError>;
 pub struct LuaRuntimeResult<T> {
     pub inner: LuaResult<T>,
     pub source_line: Option<i64>,
-    pub source_name: Option<String>
+    pub source_name: Option<String>,
+    // Only captured on errors when diagnostics are enabled on the VM
+    pub snapshot: Option<VmSnapshot>
+}
+
+// Machine state of the frame an error surfaced in
+#[derive(Debug, Clone)]
+pub struct VmSnapshot {
+    pub pc: i64,
+    pub opcode: OpCode,
+    // Non-nil registers of the frame with their index
+    pub registers: Vec<(usize, value::LuaValue)>
 }
 
 impl<T: std::fmt::Debug> std::fmt::Display for LuaRuntimeResult<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match &self.inner {
             LuaResult::Ok(r) => write!(f, "{:?}", r),
-            LuaResult::Err(e) => write!(f, "{:?} at line {} in {}", e, self.source_line.unwrap_or(-1), self.source_name.clone().unwrap_or("unknown".to_owned()))
+            LuaResult::Err(e) => write!(f, "{} at line {} in {}", e, self.source_line.unwrap_or(-1), self.source_name.clone().unwrap_or("unknown".to_owned()))
         }
     }
 }
\ No newline at end of file
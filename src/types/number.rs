@@ -1,4 +1,6 @@
 // Wraps an f64 to provide the Eq trait
+// Like Lua 5.1 there is no integer subtype: integral values format without a fraction (3 and 3.0
+// both print "3") and index the same table slot, so a separate integer flag wouldn't be observable
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct LuaNumber(pub f64);
 
@@ -8,14 +10,56 @@ impl From<f64> for LuaNumber {
     }
 }
 
+// Formats like Lua's LUAI_NUMFMT ("%.14g")
+impl std::fmt::Display for LuaNumber {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let n = self.0;
+        if n.is_nan() {
+            return write!(f, "{}", if n.is_sign_negative() { "-nan" } else { "nan" });
+        }
+        if n.is_infinite() {
+            return write!(f, "{}", if n < 0.0 { "-inf" } else { "inf" });
+        }
+        if n == 0.0 {
+            return write!(f, "{}", if n.is_sign_negative() { "-0" } else { "0" });
+        }
+
+        // Round to 14 significant digits first, the exponent decides between %e and %f style
+        let scientific = format!("{:.13e}", n);
+        let (mantissa, exponent) = scientific.split_once('e').unwrap();
+        let exponent: i32 = exponent.parse().unwrap();
+
+        if !(-4..14).contains(&exponent) {
+            let mantissa = strip_trailing_zeros(mantissa);
+            write!(f, "{}e{}{:02}", mantissa, if exponent < 0 { '-' } else { '+' }, exponent.abs())
+        } else {
+            let fixed = format!("{:.*}", (13 - exponent) as usize, n);
+            write!(f, "{}", strip_trailing_zeros(&fixed))
+        }
+    }
+}
+
+fn strip_trailing_zeros(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
 impl PartialOrd for LuaNumber {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
+// Consistent with PartialEq so equal numbers are the same table key, total_cmp alone
+// would keep 0 and -0 apart
 impl Ord for LuaNumber {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        if self.0 == other.0 {
+            return std::cmp::Ordering::Equal;
+        }
         self.0.total_cmp(&other.0)
     }
 }
@@ -52,4 +96,9 @@ impl std::ops::Div for LuaNumber {
     fn div(self, rhs: Self) -> Self::Output {
         LuaNumber(self.0 / rhs.0)
     }
-}
\ No newline at end of file
+}
+
+// luai_nummod, the result takes the sign of the divisor unlike Rust's % (C fmod)
+pub fn modulo(a: f64, b: f64) -> f64 {
+    a - (a / b).floor() * b
+}
@@ -0,0 +1,129 @@
+use std::{borrow::Cow, ops::Deref, str::FromStr};
+
+// Lua strings are byte arrays and may hold anything, including NULs and invalid UTF-8
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LuaString(Vec<u8>);
+
+impl LuaString {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+
+    // Length in bytes, not characters. Shared by the # operator and string.len so they can't disagree
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // Invalid UTF-8 sequences are replaced, only use this for display and host interop
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.0.extend_from_slice(bytes);
+    }
+
+    // Numeric value of the string like luaO_str2d: decimal or 0x hex, surrounding whitespace is allowed
+    pub fn to_number(&self) -> Option<f64> {
+        let s = std::str::from_utf8(&self.0).ok()?.trim_matches(|c: char| c.is_ascii_whitespace() || c == '\x0b');
+        let (negative, unsigned) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s))
+        };
+
+        if let Some(hex) = unsigned.strip_prefix("0x").or_else(|| unsigned.strip_prefix("0X")) {
+            if hex.is_empty() || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            let n = hex.bytes().fold(0f64, |n, c| n * 16.0 + (c as char).to_digit(16).unwrap() as f64);
+            return Some(if negative { -n } else { n });
+        }
+
+        // Rust also accepts inf and nan spellings, which strtod does too
+        if unsigned.starts_with(['+', '-']) {
+            return None;
+        }
+        s.parse::<f64>().ok()
+    }
+
+    pub fn parse<F: FromStr>(&self) -> Result<F, F::Err> {
+        self.to_str_lossy().parse::<F>()
+    }
+}
+
+impl Deref for LuaString {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for LuaString {
+    fn from(value: Vec<u8>) -> Self {
+        Self(value)
+    }
+}
+
+impl From<&[u8]> for LuaString {
+    fn from(value: &[u8]) -> Self {
+        Self(value.to_vec())
+    }
+}
+
+impl From<String> for LuaString {
+    fn from(value: String) -> Self {
+        Self(value.into_bytes())
+    }
+}
+
+impl From<&String> for LuaString {
+    fn from(value: &String) -> Self {
+        Self(value.as_bytes().to_vec())
+    }
+}
+
+impl From<&str> for LuaString {
+    fn from(value: &str) -> Self {
+        Self(value.as_bytes().to_vec())
+    }
+}
+
+impl From<&LuaString> for LuaString {
+    fn from(value: &LuaString) -> Self {
+        value.clone()
+    }
+}
+
+impl PartialEq<str> for LuaString {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for LuaString {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl std::fmt::Display for LuaString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_str_lossy())
+    }
+}
+
+// Quoted, bytes that aren't printable ASCII are escaped so binary strings stay readable
+impl std::fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "\"{}\"", self.0.escape_ascii())
+    }
+}
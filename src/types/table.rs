@@ -0,0 +1,190 @@
+use std::{cell::{Cell, Ref, RefCell, RefMut}, collections::BTreeMap, ops::Bound, rc::Rc};
+
+use rand::RngCore;
+
+use super::value::LuaValue;
+
+// Keys sit behind a RefCell like values do, but a key is never mutated once inserted, so the map's
+// order can't change under it. Code holding this type allows clippy::mutable_key_type for that reason
+pub type TableEntries = BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>>;
+
+// Keys are stored in canonical form, -0 is stored as 0 so both index the same slot
+// Integral floats need no conversion since all numbers are f64, t[1] and t[1.0] are the same key
+fn normalize_key(key: Rc<RefCell<LuaValue>>) -> Rc<RefCell<LuaValue>> {
+    let negative_zero = matches!(&*key.borrow(), LuaValue::Number(n) if n.0 == 0.0 && n.0.is_sign_negative());
+    if negative_zero {
+        return LuaValue::from(0.0).into();
+    }
+    key
+}
+
+// A key and its value as stored in the table
+pub type TableEntry = (Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>);
+
+type MetamethodCache = BTreeMap<&'static str, Option<Rc<RefCell<LuaValue>>>>;
+
+// Tables are reference types, cloning a LuaTable yields a handle to the same table
+#[derive(Clone)]
+pub struct LuaTable {
+    // Unique id for every table - tables compare by identity like in Lua
+    id: u64,
+    entries: Rc<RefCell<TableEntries>>,
+    metatable: Rc<RefCell<Option<LuaTable>>>,
+    // Fields resolved while this table is used as a metatable, cleared on every write
+    metamethods: Rc<RefCell<MetamethodCache>>,
+    // Off only to measure what the cache saves
+    cache_metamethods: Rc<Cell<bool>>,
+    // Bumped on every write, tells whether a view taken with array() is still current
+    version: Rc<Cell<u64>>
+}
+
+impl LuaTable {
+    #[allow(clippy::mutable_key_type)]
+    pub fn new(entries: TableEntries) -> Self {
+        Self {
+            id: rand::rng().next_u64(),
+            entries: Rc::new(RefCell::new(entries)),
+            metatable: Rc::new(RefCell::new(None)),
+            metamethods: Rc::new(RefCell::new(BTreeMap::new())),
+            cache_metamethods: Rc::new(Cell::new(true)),
+            version: Rc::new(Cell::new(0))
+        }
+    }
+
+    pub fn entries(&self) -> Ref<'_, TableEntries> {
+        self.entries.borrow()
+    }
+
+    pub fn entries_mut(&self) -> RefMut<'_, TableEntries> {
+        self.metamethods.borrow_mut().clear();
+        self.version.set(self.version.get() + 1);
+        self.entries.borrow_mut()
+    }
+
+    // Raw lookup, does not consult the metatable
+    pub fn get(&self, key: &LuaValue) -> Option<Rc<RefCell<LuaValue>>> {
+        self.entries.borrow().get(&RefCell::new(key.clone())).cloned()
+    }
+
+    // Entry following `key` in key order, the first one for nil. Entries holding nil are skipped and
+    // `key` doesn't need to be present, so a removed key still works as a cursor
+    pub fn next(&self, key: &LuaValue) -> Option<TableEntry> {
+        let lower = match key {
+            LuaValue::Nil => Bound::Unbounded,
+            k => Bound::Excluded(Rc::new(RefCell::new(k.clone())))
+        };
+        self.entries.borrow().range((lower, Bound::Unbounded))
+            .find(|(_, v)| !matches!(*v.borrow(), LuaValue::Nil))
+            .map(|(k, v)| (k.clone(), v.clone()))
+    }
+
+    // Raw assignment, does not consult the metatable
+    pub fn insert(&self, key: Rc<RefCell<LuaValue>>, value: Rc<RefCell<LuaValue>>) {
+        self.entries_mut().insert(normalize_key(key), value);
+    }
+
+    // Values under the keys 1..n in order, n being the first border (t[n + 1] is nil)
+    pub fn array(&self) -> Vec<Rc<RefCell<LuaValue>>> {
+        let mut values = vec![];
+        self.scan_array(|v| values.push(v.clone()));
+        values
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.get()
+    }
+
+    // Length as returned by the # operator
+    pub fn border(&self) -> usize {
+        self.scan_array(|_| {})
+    }
+
+    // Walks the number keys from 1 in a single range scan rather than looking up every index,
+    // non-integral keys in between are skipped
+    fn scan_array(&self, mut visit: impl FnMut(&Rc<RefCell<LuaValue>>)) -> usize {
+        let entries = self.entries.borrow();
+        let start = Rc::new(RefCell::new(LuaValue::from(1.0)));
+        let mut n = 0;
+        for (k, v) in entries.range(start..) {
+            let next = (n + 1) as f64;
+            match &*k.borrow() {
+                LuaValue::Number(key) if key.0 < next => continue,
+                LuaValue::Number(key) if key.0 == next && !matches!(*v.borrow(), LuaValue::Nil) => {
+                    visit(v);
+                    n += 1;
+                },
+                _ => break
+            }
+        }
+        n
+    }
+
+    pub fn metatable(&self) -> Option<LuaTable> {
+        self.metatable.borrow().clone()
+    }
+
+    pub fn set_metatable(&self, metatable: Option<LuaTable>) {
+        *self.metatable.borrow_mut() = metatable;
+    }
+
+    // Look up a metamethod (e.g. "__eq") in this table's metatable, nil fields count as absent
+    pub fn metamethod(&self, event: &'static str) -> Option<Rc<RefCell<LuaValue>>> {
+        self.metatable()?.metafield(event)
+    }
+
+    // Resolve a field of this table while it's used as a metatable, repeated lookups hit the cache
+    pub(crate) fn metafield(&self, event: &'static str) -> Option<Rc<RefCell<LuaValue>>> {
+        if let Some(cached) = self.metamethods.borrow().get(event) {
+            return cached.clone();
+        }
+
+        let field = self.get(&LuaValue::from(event)).filter(|v| !matches!(*v.borrow(), LuaValue::Nil));
+        if self.cache_metamethods.get() {
+            self.metamethods.borrow_mut().insert(event, field.clone());
+        }
+        field
+    }
+
+    // Turning the cache off makes every metamethod lookup through this table read the field again
+    pub fn set_metamethod_cache(&self, enabled: bool) {
+        self.cache_metamethods.set(enabled);
+        self.metamethods.borrow_mut().clear();
+    }
+
+    pub fn as_ptr(&self) -> *const TableEntries {
+        self.entries.as_ptr()
+    }
+}
+
+impl From<TableEntries> for LuaTable {
+    fn from(value: TableEntries) -> Self {
+        Self::new(value)
+    }
+}
+
+// Tables may contain themselves, so the entries are not printed
+impl std::fmt::Debug for LuaTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "LuaTable {{ id: {0}, entries: <{1}> }}", self.id, self.entries.borrow().len())
+    }
+}
+
+impl PartialOrd for LuaTable {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for LuaTable {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
+impl PartialEq for LuaTable {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for LuaTable {}
@@ -0,0 +1,80 @@
+use std::{any::Any, cell::{Ref, RefCell, RefMut}, rc::Rc};
+
+use rand::RngCore;
+
+use super::{table::LuaTable, value::LuaValue};
+
+// Opaque host object, cloning a LuaUserdata yields a handle to the same object
+#[derive(Clone)]
+pub struct LuaUserdata {
+    // Unique id for every userdata - compared by identity like tables
+    id: u64,
+    data: Rc<RefCell<dyn Any>>,
+    // Only set by host code, setmetatable works on tables only like in Lua
+    metatable: Rc<RefCell<Option<LuaTable>>>
+}
+
+impl LuaUserdata {
+    pub fn new<T: Any>(data: T) -> Self {
+        Self {
+            id: rand::rng().next_u64(),
+            data: Rc::new(RefCell::new(data)),
+            metatable: Rc::new(RefCell::new(None))
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn data(&self) -> &Rc<RefCell<dyn Any>> {
+        &self.data
+    }
+
+    // None if the object isn't a T
+    pub fn borrow<T: Any>(&self) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.data.borrow(), |d| d.downcast_ref::<T>()).ok()
+    }
+
+    pub fn borrow_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.data.borrow_mut(), |d| d.downcast_mut::<T>()).ok()
+    }
+
+    pub fn metatable(&self) -> Option<LuaTable> {
+        self.metatable.borrow().clone()
+    }
+
+    pub fn set_metatable(&self, metatable: Option<LuaTable>) {
+        *self.metatable.borrow_mut() = metatable;
+    }
+
+    pub fn metamethod(&self, event: &'static str) -> Option<Rc<RefCell<LuaValue>>> {
+        self.metatable()?.metafield(event)
+    }
+}
+
+impl std::fmt::Debug for LuaUserdata {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "LuaUserdata {{ id: {0} }}", self.id)
+    }
+}
+
+impl PartialOrd for LuaUserdata {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for LuaUserdata {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.id.cmp(&other.id)
+    }
+}
+
+impl PartialEq for LuaUserdata {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for LuaUserdata {}
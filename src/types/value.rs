@@ -1,16 +1,17 @@
-use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+use std::{cell::RefCell, rc::Rc};
 
 use crate::libs;
 
-use super::{LuaResult, LuaError, number::LuaNumber, function::LuaFunction};
+use super::{LuaResult, LuaError, number::{self, LuaNumber}, string::LuaString, function::LuaFunction, table::{LuaTable, TableEntries}, userdata::LuaUserdata};
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum LuaValue {
     Number(LuaNumber),
-    String(String),
+    String(LuaString),
     Boolean(bool),
-    Table(BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>>),
+    Table(LuaTable),
     Function(LuaFunction),
+    Userdata(LuaUserdata),
     Nil
 }
 
@@ -22,10 +23,22 @@ impl From<bool> for LuaValue {
 
 impl From<String> for LuaValue {
     fn from(value: String) -> Self {
+        Self::String(value.into())
+    }
+}
+
+impl From<LuaString> for LuaValue {
+    fn from(value: LuaString) -> Self {
         Self::String(value)
     }
 }
 
+impl From<Vec<u8>> for LuaValue {
+    fn from(value: Vec<u8>) -> Self {
+        Self::String(value.into())
+    }
+}
+
 impl From<&str> for LuaValue {
     fn from(value: &str) -> Self {
         Self::String(value.into())
@@ -44,32 +57,42 @@ impl From<LuaFunction> for LuaValue {
     }
 }
 
-impl From<BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>>> for LuaValue {
-    fn from(value: BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>>) -> Self {
+impl From<LuaUserdata> for LuaValue {
+    fn from(value: LuaUserdata) -> Self {
+        Self::Userdata(value)
+    }
+}
+
+impl From<TableEntries> for LuaValue {
+    fn from(value: TableEntries) -> Self {
+        Self::Table(value.into())
+    }
+}
+
+impl From<LuaTable> for LuaValue {
+    fn from(value: LuaTable) -> Self {
         Self::Table(value)
     }
 }
 
-impl Into<Rc<RefCell<LuaValue>>> for LuaValue {
-    fn into(self) -> Rc<RefCell<LuaValue>> {
-        Rc::new(RefCell::new(self))
+impl From<LuaValue> for Rc<RefCell<LuaValue>> {
+    fn from(value: LuaValue) -> Self {
+        Rc::new(RefCell::new(value))
     }
 }
 
-// TODO: Reduice boilerplate
+// Numeric strings are converted first, a non-convertible rhs is blamed as operand 2
+fn arith(lhs: LuaValue, rhs: LuaValue, op: fn(f64, f64) -> f64) -> LuaResult<LuaValue> {
+    let a = lhs.coerce_number()?;
+    let b = rhs.coerce_number().map_err(|_| LuaError::UnsupportedArithmeticOperation(rhs.type_name(), 2))?;
+    LuaResult::Ok(LuaValue::from(op(a, b)))
+}
+
 impl std::ops::Add for LuaValue {
     type Output = LuaResult<Self>;
 
     fn add(self, rhs: Self) -> Self::Output {
-        LuaResult::Ok(match (self, rhs) {
-            (LuaValue::Number(a), LuaValue::Number(b)) => LuaValue::Number(a + b),
-
-            (LuaValue::String(a), LuaValue::String(b)) => LuaValue::Number((a.parse::<f64>()? + b.parse::<f64>()?).into()),
-            (LuaValue::String(a), LuaValue::Number(b)) => LuaValue::Number((a.parse::<f64>()? + b.0).into()),
-            (LuaValue::Number(a), LuaValue::String(b)) => LuaValue::Number((a.0 + b.parse::<f64>()?).into()),
-
-            _ => return LuaResult::Err(LuaError::UnsupportedArithmeticOperation)
-        })
+        arith(self, rhs, |a, b| a + b)
     }
 }
 
@@ -77,15 +100,7 @@ impl std::ops::Sub for LuaValue {
     type Output = LuaResult<Self>;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        LuaResult::Ok(match (self, rhs) {
-            (LuaValue::Number(a), LuaValue::Number(b)) => LuaValue::Number(a - b),
-
-            (LuaValue::String(a), LuaValue::String(b)) => LuaValue::Number((a.parse::<f64>()? - b.parse::<f64>()?).into()),
-            (LuaValue::String(a), LuaValue::Number(b)) => LuaValue::Number((a.parse::<f64>()? - b.0).into()),
-            (LuaValue::Number(a), LuaValue::String(b)) => LuaValue::Number((a.0 - b.parse::<f64>()?).into()),
-
-            _ => return LuaResult::Err(LuaError::UnsupportedArithmeticOperation)
-        })
+        arith(self, rhs, |a, b| a - b)
     }
 }
 
@@ -93,15 +108,7 @@ impl std::ops::Mul for LuaValue {
     type Output = LuaResult<Self>;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        LuaResult::Ok(match (self, rhs) {
-            (LuaValue::Number(a), LuaValue::Number(b)) => LuaValue::Number(a * b),
-
-            (LuaValue::String(a), LuaValue::String(b)) => LuaValue::Number((a.parse::<f64>()? * b.parse::<f64>()?).into()),
-            (LuaValue::String(a), LuaValue::Number(b)) => LuaValue::Number((a.parse::<f64>()? * b.0).into()),
-            (LuaValue::Number(a), LuaValue::String(b)) => LuaValue::Number((a.0 * b.parse::<f64>()?).into()),
-
-            _ => return LuaResult::Err(LuaError::UnsupportedArithmeticOperation)
-        })
+        arith(self, rhs, |a, b| a * b)
     }
 }
 
@@ -109,123 +116,277 @@ impl std::ops::Div for LuaValue {
     type Output = LuaResult<Self>;
 
     fn div(self, rhs: Self) -> Self::Output {
-        LuaResult::Ok(match (self, rhs) {
-            (LuaValue::Number(a), LuaValue::Number(b)) => LuaValue::Number(a / b),
-
-            (LuaValue::String(a), LuaValue::String(b)) => LuaValue::Number((a.parse::<f64>()? / b.parse::<f64>()?).into()),
-            (LuaValue::String(a), LuaValue::Number(b)) => LuaValue::Number((a.parse::<f64>()? / b.0).into()),
-            (LuaValue::Number(a), LuaValue::String(b)) => LuaValue::Number((a.0 / b.parse::<f64>()?).into()),
-
-            _ => return LuaResult::Err(LuaError::UnsupportedArithmeticOperation)
-        })
+        arith(self, rhs, |a, b| a / b)
     }
 }
 
 impl LuaValue {
-    pub fn modulo(self, rhs: Self) -> LuaResult<Self> {
-        LuaResult::Ok(match (self, rhs) {
-            (LuaValue::Number(a), LuaValue::Number(b)) => LuaValue::Number((a.0 % b.0).into()),
-
-            (LuaValue::String(a), LuaValue::String(b)) => LuaValue::Number((a.parse::<f64>()? % b.parse::<f64>()?).into()),
-            (LuaValue::String(a), LuaValue::Number(b)) => LuaValue::Number((a.parse::<f64>()? % b.0).into()),
-            (LuaValue::Number(a), LuaValue::String(b)) => LuaValue::Number((a.0 % b.parse::<f64>()?).into()),
+    // Operand of arithmetic, numeric strings (hex and surrounding whitespace included) are converted
+    // like luaV_tonumber. Anything else is blamed as the first operand
+    pub fn coerce_number(&self) -> LuaResult<f64> {
+        match self {
+            LuaValue::Number(n) => LuaResult::Ok(n.0),
+            LuaValue::String(s) => s.to_number().ok_or(LuaError::UnsupportedArithmeticOperation("string", 1)),
+            v => LuaResult::Err(LuaError::UnsupportedArithmeticOperation(v.type_name(), 1))
+        }
+    }
 
-            _ => return LuaResult::Err(LuaError::UnsupportedArithmeticOperation)
-        })
+    pub fn modulo(self, rhs: Self) -> LuaResult<Self> {
+        arith(self, rhs, number::modulo)
     }
 
     pub fn pow(self, rhs: Self) -> LuaResult<Self> {
-        LuaResult::Ok(match (self, rhs) {
-            (LuaValue::Number(a), LuaValue::Number(b)) => LuaValue::Number((a.0.powf(b.0)).into()),
-
-            (LuaValue::String(a), LuaValue::String(b)) => LuaValue::Number((a.parse::<f64>()?.powf(b.parse::<f64>()?)).into()),
-            (LuaValue::String(a), LuaValue::Number(b)) => LuaValue::Number((a.parse::<f64>()?.powf(b.0)).into()),
-            (LuaValue::Number(a), LuaValue::String(b)) => LuaValue::Number((a.0.powf(b.parse::<f64>()?)).into()),
-
-            _ => return LuaResult::Err(LuaError::UnsupportedArithmeticOperation)
-        })
+        arith(self, rhs, f64::powf)
     }
 
     pub fn unm(self) -> LuaResult<Self> {
-        match self {
-            LuaValue::Number(n) => LuaResult::Ok((-n.0).into()),
-            _ => LuaResult::Err(LuaError::UnsupportedArithmeticOperation)
-        }
+        LuaResult::Ok(LuaValue::from(-self.coerce_number()?))
     }
 
     pub fn concat(self, rhs: Self) -> LuaResult<Self> {
         match self {
             LuaValue::String(s) => {
                 let mut lhs = s.clone();
-                let rhs = match libs::global::tostring(&vec![rhs.into()])?[0].borrow().clone() {
-                    LuaValue::String(s) => s,
-                    _ => panic!()
-                };
-                lhs.push_str(&rhs);
+                lhs.push_bytes(&rhs.concat_string()?);
                 LuaResult::Ok(LuaValue::from(lhs))
             },
             LuaValue::Number(_n) => {
-                let lhs = match libs::global::tostring(&vec![self.into()])?[0].borrow().clone() {
-                    LuaValue::String(s) => s,
-                    _ => panic!()
-                };
-                let rhs = match libs::global::tostring(&vec![rhs.into()])?[0].borrow().clone() {
-                    LuaValue::String(s) => s,
-                    _ => panic!()
-                };
-                LuaResult::Ok(LuaValue::String(format!("{lhs}{rhs}")))
+                let mut lhs = self.concat_string()?;
+                lhs.push_bytes(&rhs.concat_string()?);
+                LuaResult::Ok(LuaValue::String(lhs))
             },
-            LuaValue::Boolean(_) => LuaResult::Err(LuaError::AttemptedBooleanConcatenation),
-            LuaValue::Function(_) => LuaResult::Err(LuaError::AttemptedFunctionConcatenation),
-            LuaValue::Table(_) => LuaResult::Err(LuaError::AttemptedTableConcatenation),
-            LuaValue::Nil => LuaResult::Err(LuaError::AttemptedNilConcatenation)
+            _ => LuaResult::Err(self.concat_error())
+        }
+    }
+
+    // String form of a concatenation operand, a __tostring returning anything else can't be concatenated
+    fn concat_string(self) -> LuaResult<LuaString> {
+        let error = self.concat_error();
+        match libs::global::tostring(&vec![self.into()])?[0].borrow().clone() {
+            LuaValue::String(s) => LuaResult::Ok(s),
+            _ => LuaResult::Err(error)
+        }
+    }
+
+    // Error raised when this value takes part in a concatenation without a __concat metamethod
+    pub fn concat_error(&self) -> LuaError {
+        match self {
+            LuaValue::Boolean(_) => LuaError::AttemptedBooleanConcatenation,
+            LuaValue::Function(_) => LuaError::AttemptedFunctionConcatenation,
+            LuaValue::Table(_) => LuaError::AttemptedTableConcatenation,
+            LuaValue::Userdata(_) => LuaError::AttemptedUserdataConcatenation,
+            _ => LuaError::AttemptedNilConcatenation
         }
     }
 
     pub fn call(self, args: Vec<Rc<RefCell<LuaValue>>>) -> LuaResult<Vec<Rc<RefCell<LuaValue>>>> {
         match self {
             LuaValue::Function(f) => f.invoke(&args),
-            LuaValue::Table(_) => LuaResult::Err(LuaError::AttemptedTableCall),
+            // Callable tables and userdata receive themselves as the first argument
+            LuaValue::Table(_) | LuaValue::Userdata(_) => match self.metamethod("__call").map(|tm| tm.borrow().clone()) {
+                Some(LuaValue::Function(f)) => {
+                    let mut call_args = vec![self.clone().into()];
+                    call_args.extend(args);
+                    f.invoke(&call_args)
+                },
+                _ if matches!(self, LuaValue::Table(_)) => LuaResult::Err(LuaError::AttemptedTableCall),
+                _ => LuaResult::Err(LuaError::AttemptedCallOnUnsupportedType)
+            },
             _ => LuaResult::Err(LuaError::AttemptedCallOnUnsupportedType)
         }
     }
 
-    pub fn as_f64<'a>(&'a self) -> LuaResult<&'a f64> {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            LuaValue::Number(_) => "number",
+            LuaValue::String(_) => "string",
+            LuaValue::Boolean(_) => "boolean",
+            LuaValue::Table(_) => "table",
+            LuaValue::Function(_) => "function",
+            LuaValue::Userdata(_) => "userdata",
+            LuaValue::Nil => "nil"
+        }
+    }
+
+    // Snapshot of a table's pairs in key order, nil values are skipped. Non-tables yield nothing
+    pub fn table_iter(&self) -> impl Iterator<Item = (LuaValue, LuaValue)> {
+        let pairs: Vec<(LuaValue, LuaValue)> = match self {
+            LuaValue::Table(t) => t.entries().iter()
+                .map(|(k, v)| (k.borrow().clone(), v.borrow().clone()))
+                .filter(|(_, v)| !matches!(v, LuaValue::Nil))
+                .collect(),
+            _ => vec![]
+        };
+        pairs.into_iter()
+    }
+
+    // Keys in the order next and pairs visit them, which is the Ord of LuaValue: numbers ascending
+    // (so the array part comes first and in order), then strings, booleans, tables, functions and
+    // userdata. Keys holding nil are skipped, non-tables have no keys
+    pub fn table_ordered_keys(&self) -> Vec<LuaValue> {
+        let mut keys = vec![];
+        if let LuaValue::Table(t) = self {
+            let mut key = LuaValue::Nil;
+            while let Some((k, _)) = t.next(&key) {
+                key = k.borrow().clone();
+                keys.push(key.clone());
+            }
+        }
+        keys
+    }
+
+    // Structural equality, tables are equal if they hold deep_eq values under the same keys
+    // Keys and everything else compare like rawequal. Metatables are ignored
+    pub fn deep_eq(&self, other: &LuaValue) -> bool {
+        self.deep_eq_visited(other, &mut vec![])
+    }
+
+    // Pairs already under comparison count as equal, which ends the recursion on cycles
+    fn deep_eq_visited(&self, other: &LuaValue, visited: &mut Vec<(LuaTable, LuaTable)>) -> bool {
+        match (self, other) {
+            (LuaValue::Table(a), LuaValue::Table(b)) => {
+                if a == b || visited.iter().any(|(x, y)| x == a && y == b) {
+                    return true;
+                }
+                visited.push((a.clone(), b.clone()));
+
+                let (lhs, rhs): (Vec<_>, Vec<_>) = (self.table_iter().collect(), other.table_iter().collect());
+                lhs.len() == rhs.len() && lhs.iter().zip(rhs.iter())
+                    .all(|((ka, va), (kb, vb))| ka == kb && va.deep_eq_visited(vb, visited))
+            },
+            (a, b) => a == b
+        }
+    }
+
+    // Renders the value the way print and tostring do, ignoring __tostring
+    pub fn to_display_string(&self) -> String {
+        match self {
+            LuaValue::String(s) => s.to_string(),
+            LuaValue::Number(n) => n.to_string(),
+            LuaValue::Boolean(b) => b.to_string(),
+            LuaValue::Nil => "nil".to_owned(),
+            LuaValue::Table(t) => format!("table: {:?}", t.as_ptr()),
+            LuaValue::Function(f) => format!("function: {:#010x}", f.id() as u32),
+            LuaValue::Userdata(u) => format!("userdata: {:#010x}", u.id() as u32)
+        }
+    }
+
+    // Recursively expands tables, meant for test assertions and debugging
+    // Keys are listed in table order, a table nested inside itself is shown as <cycle>
+    pub fn to_debug_tree(&self) -> String {
+        let mut out = "".to_owned();
+        self.write_debug_tree(&mut out, 0, &mut vec![]);
+        out
+    }
+
+    fn write_debug_tree(&self, out: &mut String, depth: usize, path: &mut Vec<LuaTable>) {
+        match self {
+            LuaValue::String(s) => out.push_str(&format!("{:?}", s)),
+            LuaValue::Table(t) if path.contains(t) => out.push_str("<cycle>"),
+            LuaValue::Table(t) if t.entries().is_empty() => out.push_str("{}"),
+            LuaValue::Table(t) => {
+                path.push(t.clone());
+                out.push_str("{\n");
+
+                let entries: Vec<_> = t.entries().iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+                for (i, (k, v)) in entries.iter().enumerate() {
+                    out.push_str(&"  ".repeat(depth + 1));
+                    out.push('[');
+                    k.borrow().write_debug_tree(out, depth + 1, path);
+                    out.push_str("] = ");
+                    v.borrow().write_debug_tree(out, depth + 1, path);
+                    if i + 1 < entries.len() {
+                        out.push(',');
+                    }
+                    out.push('\n');
+                }
+
+                out.push_str(&"  ".repeat(depth));
+                out.push('}');
+                path.pop();
+            },
+            v => out.push_str(&v.to_display_string())
+        }
+    }
+
+    // Empty table for host code, filled with table_set
+    pub fn new_table() -> LuaValue {
+        LuaValue::Table(LuaTable::new(TableEntries::new()))
+    }
+
+    // Raw t[key] = value, metamethods are not consulted
+    pub fn table_set(&mut self, key: LuaValue, value: LuaValue) -> LuaResult<()> {
+        let table = self.as_table()?;
+        key.check_key()?;
+        table.insert(key.into(), value.into());
+        LuaResult::Ok(())
+    }
+
+    // Neither nil nor NaN can be used as a table key
+    pub(crate) fn check_key(&self) -> LuaResult<()> {
+        match self {
+            LuaValue::Nil => LuaResult::Err(LuaError::InvalidTableIndex("nil")),
+            LuaValue::Number(n) if n.0.is_nan() => LuaResult::Err(LuaError::InvalidTableIndex("NaN")),
+            _ => LuaResult::Ok(())
+        }
+    }
+
+    // Raw t[key], nil when the key is absent
+    pub fn table_get(&self, key: &LuaValue) -> LuaResult<LuaValue> {
+        LuaResult::Ok(self.as_table()?.get(key).map(|v| v.borrow().clone()).unwrap_or(LuaValue::Nil))
+    }
+
+    // Only nil and false are falsy
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, LuaValue::Nil | LuaValue::Boolean(false))
+    }
+
+    pub fn as_f64(&self) -> LuaResult<&f64> {
         match self {
             LuaValue::Number(n) => LuaResult::Ok(&n.0),
             _ => LuaResult::Err(LuaError::ExpectedNumber)
         }
     }
 
-    pub fn as_string<'a>(&'a self) -> LuaResult<&'a String> {
+    pub fn as_string(&self) -> LuaResult<&LuaString> {
         match self {
             LuaValue::String(s) => LuaResult::Ok(s),
             _ => LuaResult::Err(LuaError::ExpectedString)
         }
     }
 
-    pub fn as_bool<'a>(&'a self) -> LuaResult<&'a bool> {
+    pub fn as_bool(&self) -> LuaResult<&bool> {
         match self {
             LuaValue::Boolean(b) => LuaResult::Ok(b),
             _ => LuaResult::Err(LuaError::ExpectedBoolean)
         }
     }
 
-    pub fn as_table<'a>(&'a self) -> LuaResult<&'a BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>>> {
+    // Only tables and userdata carry their own metatable, strings share the VM's
+    pub fn metatable(&self) -> Option<LuaTable> {
         match self {
-            LuaValue::Table(t) => LuaResult::Ok(t),
-            _ => LuaResult::Err(LuaError::ExpectedTable)
+            LuaValue::Table(t) => t.metatable(),
+            LuaValue::Userdata(u) => u.metatable(),
+            _ => None
+        }
+    }
+
+    pub fn metamethod(&self, event: &'static str) -> Option<Rc<RefCell<LuaValue>>> {
+        match self {
+            LuaValue::Table(t) => t.metamethod(event),
+            LuaValue::Userdata(u) => u.metamethod(event),
+            _ => None
         }
     }
 
-    pub fn as_table_mut<'a>(&'a mut self) -> LuaResult<&'a mut BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>>> {
+    pub fn as_table(&self) -> LuaResult<&LuaTable> {
         match self {
             LuaValue::Table(t) => LuaResult::Ok(t),
             _ => LuaResult::Err(LuaError::ExpectedTable)
         }
     }
 
-    pub fn as_function<'a>(&'a self) -> LuaResult<&'a LuaFunction> {
+    pub fn as_function(&self) -> LuaResult<&LuaFunction> {
         match self {
             LuaValue::Function(f) => LuaResult::Ok(f),
             _ => LuaResult::Err(LuaError::ExpectedFunction)
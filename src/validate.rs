@@ -0,0 +1,263 @@
+// Static checks on decoded bytecode, rejects chunks that would make the VM index out of bounds
+
+use crate::{bytecode::{LuaPrototype, OpCode}, types::value::LuaValue};
+
+#[derive(Debug)]
+pub enum ValidationError {
+    InvalidRegister { pc: usize, register: usize },
+    InvalidConstant { pc: usize, index: usize },
+    InvalidGlobalName { pc: usize, index: usize },
+    InvalidUpValue { pc: usize, index: usize },
+    InvalidPrototype { pc: usize, index: usize },
+    InvalidJump { pc: usize, target: i64 },
+    // A SETLIST batch number of 0, there's no batch before the first
+    InvalidBatch { pc: usize },
+    // A SETLIST with C == 0 without its batch number, or an ExtraArg anywhere else
+    MisplacedExtraArg { pc: usize },
+    MissingReturn
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+type ValidationResult = Result<(), ValidationError>;
+
+struct Validator<'a> {
+    function: &'a LuaPrototype,
+    pc: usize
+}
+
+impl Validator<'_> {
+    fn register(&self, register: usize) -> ValidationResult {
+        if register >= self.function.max_stack_size as usize {
+            return ValidationResult::Err(ValidationError::InvalidRegister { pc: self.pc, register });
+        }
+        ValidationResult::Ok(())
+    }
+
+    fn constant(&self, index: usize) -> ValidationResult {
+        if index >= self.function.constants.len() {
+            return ValidationResult::Err(ValidationError::InvalidConstant { pc: self.pc, index });
+        }
+        ValidationResult::Ok(())
+    }
+
+    // GETGLOBAL and SETGLOBAL name the global with a string constant
+    fn global_name(&self, index: usize) -> ValidationResult {
+        self.constant(index)?;
+        if !matches!(*self.function.constants[index].borrow(), LuaValue::String(_)) {
+            return ValidationResult::Err(ValidationError::InvalidGlobalName { pc: self.pc, index });
+        }
+        ValidationResult::Ok(())
+    }
+
+    // B and C can reference either a register or a constant, see get_rk in the VM
+    fn rk(&self, index: usize) -> ValidationResult {
+        if index >= 256 { self.constant(index - 256) } else { self.register(index) }
+    }
+
+    fn upvalue(&self, index: usize) -> ValidationResult {
+        if index >= self.function.upvalue_count as usize {
+            return ValidationResult::Err(ValidationError::InvalidUpValue { pc: self.pc, index });
+        }
+        ValidationResult::Ok(())
+    }
+
+    // Jumps are relative to the instruction following the current one
+    fn jump(&self, offset: i64) -> ValidationResult {
+        let target = self.pc as i64 + 1 + offset;
+        let into_extra_arg = self.function.instructions.get(target.max(0) as usize).is_some_and(|i| matches!(i.code, OpCode::ExtraArg));
+        if target < 0 || target > self.function.instructions.len() as i64 || into_extra_arg {
+            return ValidationResult::Err(ValidationError::InvalidJump { pc: self.pc, target });
+        }
+        ValidationResult::Ok(())
+    }
+
+    // The instruction at pc + offset must exist (skips, closure pseudo instructions)
+    fn follows(&self, offset: usize) -> ValidationResult {
+        if self.pc + offset >= self.function.instructions.len() {
+            return ValidationResult::Err(ValidationError::InvalidJump { pc: self.pc, target: (self.pc + offset) as i64 });
+        }
+        ValidationResult::Ok(())
+    }
+
+    fn validate(&mut self) -> ValidationResult {
+        let function = self.function;
+
+        match function.instructions.last() {
+            Some(inst) if matches!(inst.code, OpCode::Return) => {},
+            _ => return ValidationResult::Err(ValidationError::MissingReturn)
+        }
+
+        while self.pc < function.instructions.len() {
+            let inst = &function.instructions[self.pc];
+
+            match inst.code {
+                OpCode::Move | OpCode::UnaryMinus | OpCode::Not | OpCode::Len => {
+                    self.register(inst.A)?;
+                    self.register(inst.B)?;
+                },
+                OpCode::LoadK => {
+                    self.register(inst.A)?;
+                    self.constant(inst.Bx)?;
+                },
+                OpCode::GetGlobal | OpCode::SetGlobal => {
+                    self.register(inst.A)?;
+                    self.global_name(inst.Bx)?;
+                },
+                OpCode::LoadBool => {
+                    self.register(inst.A)?;
+                    if inst.C != 0 {
+                        self.follows(2)?;
+                    }
+                },
+                OpCode::LoadNil => {
+                    self.register(inst.A)?;
+                    self.register(inst.B)?;
+                    // B is the last register cleared, it can't come before A
+                    if inst.B < inst.A {
+                        return ValidationResult::Err(ValidationError::InvalidRegister { pc: self.pc, register: inst.B });
+                    }
+                },
+                OpCode::GetUpValue | OpCode::SetUpValue => {
+                    self.register(inst.A)?;
+                    self.upvalue(inst.B)?;
+                },
+                OpCode::GetTable => {
+                    self.register(inst.A)?;
+                    self.register(inst.B)?;
+                    self.rk(inst.C)?;
+                },
+                OpCode::SetTable => {
+                    self.register(inst.A)?;
+                    self.rk(inst.B)?;
+                    self.rk(inst.C)?;
+                },
+                OpCode::NewTable | OpCode::Close => {
+                    self.register(inst.A)?;
+                },
+                OpCode::LSelf => {
+                    self.register(inst.A + 1)?;
+                    self.register(inst.B)?;
+                    self.rk(inst.C)?;
+                },
+                OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::Mod | OpCode::Pow => {
+                    self.register(inst.A)?;
+                    self.rk(inst.B)?;
+                    self.rk(inst.C)?;
+                },
+                OpCode::Concat => {
+                    self.register(inst.A)?;
+                    self.register(inst.B)?;
+                    self.register(inst.C)?;
+                },
+                OpCode::Jmp => {
+                    self.jump(inst.sBx)?;
+                },
+                OpCode::Eq | OpCode::Lt | OpCode::Le => {
+                    self.rk(inst.B)?;
+                    self.rk(inst.C)?;
+                    self.follows(1)?;
+                },
+                OpCode::Test => {
+                    self.register(inst.A)?;
+                    self.follows(1)?;
+                },
+                OpCode::TestSet => {
+                    self.register(inst.A)?;
+                    self.register(inst.B)?;
+                    self.follows(1)?;
+                },
+                OpCode::Call | OpCode::TailCall => {
+                    self.register(inst.A)?;
+                    if inst.B > 0 {
+                        self.register(inst.A + inst.B - 1)?;
+                    }
+                    if inst.C > 1 {
+                        self.register(inst.A + inst.C - 2)?;
+                    }
+                },
+                OpCode::Return => {
+                    if inst.B > 1 {
+                        self.register(inst.A + inst.B - 2)?;
+                    }
+                },
+                OpCode::ForLoop | OpCode::ForPrep => {
+                    self.register(inst.A + 3)?;
+                    self.jump(inst.sBx)?;
+                },
+                OpCode::TForLoop => {
+                    self.register(inst.A + 2 + inst.C)?;
+                    self.follows(1)?;
+                },
+                OpCode::SetList => {
+                    self.register(inst.A)?;
+                    if inst.B > 0 {
+                        self.register(inst.A + inst.B)?;
+                    }
+                    // The batch number is the next word, which isn't an instruction
+                    if inst.C == 0 {
+                        self.follows(1)?;
+                        let extra = &function.instructions[self.pc + 1];
+                        if !matches!(extra.code, OpCode::ExtraArg) {
+                            return ValidationResult::Err(ValidationError::MisplacedExtraArg { pc: self.pc + 1 });
+                        }
+                        if extra.Bx == 0 {
+                            return ValidationResult::Err(ValidationError::InvalidBatch { pc: self.pc });
+                        }
+                        self.pc += 1;
+                    }
+                },
+                OpCode::Closure => {
+                    self.register(inst.A)?;
+                    let sub_func = match function.prototypes.get(inst.Bx) {
+                        Some(f) => f,
+                        None => return ValidationResult::Err(ValidationError::InvalidPrototype { pc: self.pc, index: inst.Bx })
+                    };
+
+                    // Upvalues are initialized by the pseudo instructions following CLOSURE
+                    for i in 0..sub_func.upvalue_count as usize {
+                        self.follows(i + 1)?;
+                        let pseudo = &function.instructions[self.pc + i + 1];
+                        match pseudo.code {
+                            OpCode::Move => self.register(pseudo.B)?,
+                            OpCode::GetUpValue => self.upvalue(pseudo.B)?,
+                            _ => return ValidationResult::Err(ValidationError::InvalidUpValue { pc: self.pc + i + 1, index: i })
+                        }
+                    }
+
+                    self.pc += sub_func.upvalue_count as usize;
+                },
+                OpCode::Vararg => {
+                    self.register(inst.A)?;
+                    if inst.B > 1 {
+                        self.register(inst.A + inst.B - 2)?;
+                    }
+                },
+                OpCode::ExtraArg => {
+                    return ValidationResult::Err(ValidationError::MisplacedExtraArg { pc: self.pc });
+                }
+            }
+
+            self.pc += 1;
+        }
+
+        ValidationResult::Ok(())
+    }
+}
+
+// Validates the function and all of its nested prototypes
+pub fn validate(function: &LuaPrototype) -> ValidationResult {
+    Validator { function, pc: 0 }.validate()?;
+
+    for sub_func in &function.prototypes {
+        validate(sub_func)?;
+    }
+
+    ValidationResult::Ok(())
+}
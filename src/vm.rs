@@ -1,6 +1,8 @@
-use std::{cell::RefCell, collections::BTreeMap, ops::Sub, rc::Rc};
+use std::{cell::{Cell, RefCell}, collections::BTreeMap, io::Write, rc::Rc};
 
-use crate::{bytecode::{LuaPrototype, OpCode, FIELDS_PER_FLUSH}, libs, lua_function, lua_return, types::{value::LuaValue, LuaError, LuaResult, LuaRuntimeResult}};
+use rand::{SeedableRng, rngs::StdRng};
+
+use crate::{convert::FromLua, bytecode::{self, DecodeError, Instruction, LuaPrototype, OpCode, FIELDS_PER_FLUSH, VARARG_ISVARARG, VARARG_NEEDSARG}, libs, lua_function, lua_return, lua_string, lua_table, types::{function::{LuaClosure, LuaFunction}, number, table::{LuaTable, TableEntries}, value::LuaValue, LuaError, LuaResult, LuaRuntimeResult, VmSnapshot}, validate::{self, ValidationError}};
 
 // Simplify getting indexing the constants list or stack
 // B and C can be above 255 (max stack size) to indicate that they are referencing a constant
@@ -8,7 +10,7 @@ macro_rules! get_rk {
     ($idx:expr, $constants:ident, $stack:ident) => {
         if $idx >= 256 {
             match $constants.get($idx - 256) {
-                Some(c) => c.clone(),
+                Some(c) => c.borrow().clone(),
                 None => return LuaResult::Err(LuaError::ConstantNotFound($idx - 256))
             }
         } else {
@@ -17,57 +19,620 @@ macro_rules! get_rk {
     };
 }
 
+// Free slots above max_stack_size, like LUA_MINSTACK
+const EXTRA_STACK: usize = 20;
+// Default limit of nested Lua calls, like LUAI_MAXCCALLS
+const DEFAULT_MAX_CALL_DEPTH: usize = 200;
+// Enough for the frames of a moderately deep recursion
+const DEFAULT_FRAME_POOL_SIZE: usize = 64;
+// Longest chain of __index or __newindex tables followed, like MAXTAGLOOP. Longer chains are taken for cycles
+const MAX_TAG_LOOP: usize = 100;
+// Multret results can grow a frame's stack up to this size, like LUAI_MAXCSTACK
+const MAX_STACK_SIZE: usize = 8000;
+
+// One past the highest register an instruction accesses through its fixed operands
+fn register_top(inst: &Instruction) -> usize {
+    let rk = |x: usize| if x >= 256 { 0 } else { x + 1 };
+
+    match inst.code {
+        OpCode::Move | OpCode::UnaryMinus | OpCode::Not | OpCode::Len | OpCode::LoadNil | OpCode::TestSet => inst.A.max(inst.B) + 1,
+        OpCode::LoadK | OpCode::GetGlobal | OpCode::SetGlobal | OpCode::LoadBool | OpCode::GetUpValue | OpCode::SetUpValue
+            | OpCode::NewTable | OpCode::Test | OpCode::Closure | OpCode::Close => inst.A + 1,
+        OpCode::GetTable => (inst.A.max(inst.B) + 1).max(rk(inst.C)),
+        OpCode::SetTable | OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::Mod | OpCode::Pow
+            | OpCode::Eq | OpCode::Lt | OpCode::Le => (inst.A + 1).max(rk(inst.B)).max(rk(inst.C)),
+        OpCode::LSelf => (inst.A + 2).max(inst.B + 1).max(rk(inst.C)),
+        OpCode::Concat => inst.A.max(inst.C) + 1,
+        OpCode::Call | OpCode::TailCall => (inst.A + 1).max(inst.A + inst.B).max((inst.A + inst.C).saturating_sub(1)),
+        OpCode::Return => (inst.A + inst.B).saturating_sub(1),
+        OpCode::Vararg => (inst.A + inst.B).saturating_sub(1),
+        OpCode::ForLoop | OpCode::ForPrep => inst.A + 4,
+        OpCode::TForLoop => inst.A + 3 + inst.C,
+        OpCode::SetList => (inst.A + 1).max(inst.A + inst.B),
+        OpCode::Jmp | OpCode::ExtraArg => 0
+    }
+}
+
+// Number in SK[idx] without cloning the value, None for anything else
+fn rk_number(idx: usize, constants: &[Rc<RefCell<LuaValue>>], stack: &[LuaValue]) -> Option<f64> {
+    if idx >= 256 {
+        match &*constants.get(idx - 256)?.borrow() {
+            LuaValue::Number(n) => Some(n.0),
+            _ => None
+        }
+    } else {
+        match stack.get(idx)? {
+            LuaValue::Number(n) => Some(n.0),
+            _ => None
+        }
+    }
+}
+
+// Make room for `size` registers, used when a multret call or vararg produces more values than the frame reserved
+fn grow_stack(stack: &mut Vec<LuaValue>, size: usize) -> LuaResult<()> {
+    if size > MAX_STACK_SIZE {
+        return LuaResult::Err(LuaError::StackOverflow);
+    }
+    if size > stack.len() {
+        stack.resize(size, LuaValue::Nil);
+    }
+    LuaResult::Ok(())
+}
+
+// A register captured by a closure that is still in scope. Closures share `cell`, `synced` is the
+// value register and cell agreed on after the last instruction
+struct OpenUpvalue {
+    cell: Rc<RefCell<LuaValue>>,
+    synced: LuaValue
+}
+
+// Identity rather than Lua equality, so a NaN register doesn't look modified on every instruction
+fn same_value(a: &LuaValue, b: &LuaValue) -> bool {
+    match (a, b) {
+        (LuaValue::Number(x), LuaValue::Number(y)) => x.0.to_bits() == y.0.to_bits(),
+        _ => a == b
+    }
+}
+
+// Run after every instruction: a register written by the frame is copied into its cell, a cell
+// written by a closure (SetUpValue) is copied back into the register
+fn sync_upvalues(stack: &mut [LuaValue], open_upvalues: &mut BTreeMap<usize, OpenUpvalue>) {
+    for (&reg, upvalue) in open_upvalues.iter_mut() {
+        if !same_value(&stack[reg], &upvalue.synced) {
+            *upvalue.cell.borrow_mut() = stack[reg].clone();
+            upvalue.synced = stack[reg].clone();
+        } else if !same_value(&upvalue.cell.borrow(), &upvalue.synced) {
+            upvalue.synced = upvalue.cell.borrow().clone();
+            stack[reg] = upvalue.synced.clone();
+        }
+    }
+}
+
+// Metamethod shared by both operands of a comparison, like get_comp_tm in lvm.c
+fn comparison_metamethod(lhs: &LuaValue, rhs: &LuaValue, event: &'static str) -> Option<Rc<RefCell<LuaValue>>> {
+    let tm1 = lhs.metamethod(event)?;
+    if lhs.metatable() == rhs.metatable() {
+        return Some(tm1);
+    }
+
+    let tm2 = rhs.metamethod(event)?;
+    if *tm1.borrow() == *tm2.borrow() { Some(tm1) } else { None }
+}
+
+// Raw equality first, tables and userdata fall back to a shared __eq metamethod
+fn values_equal(lhs: &LuaValue, rhs: &LuaValue) -> LuaResult<bool> {
+    if lhs == rhs {
+        return LuaResult::Ok(true);
+    }
+
+    let metamethod = match (lhs, rhs) {
+        (LuaValue::Table(_), LuaValue::Table(_)) | (LuaValue::Userdata(_), LuaValue::Userdata(_)) => comparison_metamethod(lhs, rhs, "__eq"),
+        _ => None
+    };
+
+    match metamethod {
+        Some(tm) => {
+            let results = tm.borrow().clone().call(vec![lhs.clone().into(), rhs.clone().into()])?;
+            LuaResult::Ok(results.first().is_some_and(|r| r.borrow().is_truthy()))
+        },
+        None => LuaResult::Ok(false)
+    }
+}
+
+// Metamethod for < and <=, both operands must be of the same type and provide the same one, like call_orderTM in lvm.c
+fn order_metamethod(lhs: &LuaValue, rhs: &LuaValue, event: &'static str) -> Option<Rc<RefCell<LuaValue>>> {
+    if !matches!((lhs, rhs), (LuaValue::Table(_), LuaValue::Table(_)) | (LuaValue::Userdata(_), LuaValue::Userdata(_))) {
+        return None;
+    }
+
+    let tm1 = lhs.metamethod(event)?;
+    let tm2 = rhs.metamethod(event)?;
+    if *tm1.borrow() == *tm2.borrow() { Some(tm1) } else { None }
+}
+
+fn call_order_metamethod(tm: Rc<RefCell<LuaValue>>, lhs: &LuaValue, rhs: &LuaValue) -> LuaResult<bool> {
+    let results = tm.borrow().clone().call(vec![lhs.clone().into(), rhs.clone().into()])?;
+    LuaResult::Ok(results.first().is_some_and(|r| r.borrow().is_truthy()))
+}
+
+fn less_than(lhs: &LuaValue, rhs: &LuaValue) -> LuaResult<bool> {
+    match (lhs, rhs) {
+        (LuaValue::Number(x), LuaValue::Number(y)) => LuaResult::Ok(x.0 < y.0),
+        (LuaValue::String(x), LuaValue::String(y)) => LuaResult::Ok(x < y),
+        _ => match order_metamethod(lhs, rhs, "__lt") {
+            Some(tm) => call_order_metamethod(tm, lhs, rhs),
+            None => LuaResult::Err(LuaError::AttemptedInvalidComparison)
+        }
+    }
+}
+
+// Without __le, a <= b is evaluated as not (b < a)
+fn less_equal(lhs: &LuaValue, rhs: &LuaValue) -> LuaResult<bool> {
+    match (lhs, rhs) {
+        (LuaValue::Number(x), LuaValue::Number(y)) => LuaResult::Ok(x.0 <= y.0),
+        (LuaValue::String(x), LuaValue::String(y)) => LuaResult::Ok(x <= y),
+        _ => {
+            if let Some(tm) = order_metamethod(lhs, rhs, "__le") {
+                return call_order_metamethod(tm, lhs, rhs);
+            }
+            match order_metamethod(rhs, lhs, "__lt") {
+                Some(tm) => LuaResult::Ok(!call_order_metamethod(tm, rhs, lhs)?),
+                None => LuaResult::Err(LuaError::AttemptedInvalidComparison)
+            }
+        }
+    }
+}
+
+// Strings and numbers concatenate directly, anything else needs a __concat metamethod on either operand
+fn concat_values(lhs: &LuaValue, rhs: &LuaValue) -> LuaResult<LuaValue> {
+    let is_concatenable = |v: &LuaValue| matches!(v, LuaValue::String(_) | LuaValue::Number(_));
+    if is_concatenable(lhs) && is_concatenable(rhs) {
+        return lhs.clone().concat(rhs.clone());
+    }
+
+    match lhs.metamethod("__concat").or_else(|| rhs.metamethod("__concat")) {
+        Some(tm) => {
+            let results = tm.borrow().clone().call(vec![lhs.clone().into(), rhs.clone().into()])?;
+            LuaResult::Ok(results.first().map(|r| r.borrow().clone()).unwrap_or(LuaValue::Nil))
+        },
+        None => LuaResult::Err(if is_concatenable(lhs) { rhs.concat_error() } else { lhs.concat_error() })
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Decode(DecodeError),
+    Validation(ValidationError)
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Decode(e) => write!(f, "{:?}", e),
+            LoadError::Validation(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<DecodeError> for LoadError {
+    fn from(value: DecodeError) -> Self {
+        Self::Decode(value)
+    }
+}
+
+impl From<ValidationError> for LoadError {
+    fn from(value: ValidationError) -> Self {
+        Self::Validation(value)
+    }
+}
+
+// A decoded chunk that passed static validation
+#[derive(Debug, Clone)]
+pub struct CompiledChunk {
+    pub prototype: LuaPrototype
+}
+
+// Destination of print and io.write
+pub type OutputSink = Rc<RefCell<dyn Write>>;
+
+// Written by print between and after its arguments, Lua uses a tab and a newline
+#[derive(Debug, Clone)]
+pub struct PrintConfig {
+    pub separator: String,
+    pub terminator: String
+}
+
+impl Default for PrintConfig {
+    fn default() -> Self {
+        Self { separator: "\t".to_owned(), terminator: "\n".to_owned() }
+    }
+}
+
+// Called by os.exit with the status code, terminates the process by default
+pub type ExitHandler = Rc<dyn Fn(i32)>;
+
+// Returned by an instruction hook, Abort stops the script with LuaError::AbortedByHook
+pub enum HookAction {
+    Continue,
+    Abort(String)
+}
+
+// Called with the pc, instruction and registers of the running frame before each instruction is dispatched
+pub type InstructionHook = Box<dyn FnMut(usize, &Instruction, &[LuaValue]) -> HookAction>;
+
+// How a single function activation ended
+enum Frame {
+    Return(LuaRuntimeResult<Vec<Rc<RefCell<LuaValue>>>>),
+    TailCall(LuaClosure, Vec<Rc<RefCell<LuaValue>>>)
+}
+
+// A running Lua function, used for introspection (debug.getinfo)
+#[derive(Clone)]
+pub struct CallInfo {
+    pub prototype: Rc<LuaPrototype>,
+    // Updated before every instruction
+    pub pc: Rc<Cell<i64>>
+}
+
+impl CallInfo {
+    pub fn current_line(&self) -> Option<i64> {
+        self.prototype.source_line_positions.get(self.pc.get() as usize).copied()
+    }
+}
+
+// Cloning a VirtualMachine yields a handle sharing the environment and state of the original
+#[derive(Clone)]
 pub struct VirtualMachine {
-    pub environment: Rc<RefCell<LuaValue>>
+    pub environment: Rc<RefCell<LuaValue>>,
+    // Metatable shared by all string values, allows ("abc"):upper()
+    pub string_metatable: Option<LuaTable>,
+    // Swappable at any time, the standard library holds a reference to the slot rather than the sink
+    pub output: Rc<RefCell<OutputSink>>,
+    pub print_config: Rc<RefCell<PrintConfig>>,
+    pub exit_handler: Rc<RefCell<ExitHandler>>,
+    // Generator behind math.random, reseeded by math.randomseed
+    pub(crate) rng: Rc<RefCell<StdRng>>,
+    // Innermost frame last
+    pub call_stack: Rc<RefCell<Vec<CallInfo>>>,
+    // Instructions executed since the last reset, shared with closures
+    steps: Rc<Cell<u64>>,
+    // Every Lua call recurses on the native stack, deeper calls fail with CallDepthExceeded
+    max_call_depth: Rc<Cell<usize>>,
+    // Attach a VmSnapshot of the failing frame to errors returned by execute
+    diagnostics: Rc<Cell<bool>>,
+    // GETGLOBAL of a name that was never assigned raises UndefinedGlobal instead of giving nil
+    strict_globals: Rc<Cell<bool>>,
+    hook: Rc<RefCell<Option<InstructionHook>>>,
+    // Register buffers of finished frames, reused by later calls instead of allocating new ones
+    frame_pool: Rc<RefCell<Vec<Vec<LuaValue>>>>,
+    frame_pool_size: Rc<Cell<usize>>
+}
+
+impl Default for VirtualMachine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VirtualMachine {
     pub fn new() -> Self {
         Self {
-            environment: Rc::new(RefCell::new(BTreeMap::new().into()))
+            environment: Rc::new(RefCell::new(BTreeMap::new().into())),
+            string_metatable: None,
+            output: Rc::new(RefCell::new(Rc::new(RefCell::new(std::io::stdout())))),
+            print_config: Rc::new(RefCell::new(PrintConfig::default())),
+            exit_handler: Rc::new(RefCell::new(Rc::new(|code| std::process::exit(code)))),
+            rng: Rc::new(RefCell::new(StdRng::from_os_rng())),
+            call_stack: Rc::new(RefCell::new(Vec::new())),
+            steps: Rc::new(Cell::new(0)),
+            max_call_depth: Rc::new(Cell::new(DEFAULT_MAX_CALL_DEPTH)),
+            diagnostics: Rc::new(Cell::new(false)),
+            strict_globals: Rc::new(Cell::new(false)),
+            hook: Rc::new(RefCell::new(None)),
+            frame_pool: Rc::new(RefCell::new(Vec::new())),
+            frame_pool_size: Rc::new(Cell::new(DEFAULT_FRAME_POOL_SIZE))
         }
     }
 
+    pub fn step_count(&self) -> u64 {
+        self.steps.get()
+    }
+
+    pub fn reset_step_count(&self) {
+        self.steps.set(0);
+    }
+
+    pub fn set_max_call_depth(&mut self, depth: usize) {
+        self.max_call_depth.set(depth);
+    }
+
+    // Keep at most `size` register buffers around for reuse, 0 disables pooling
+    pub fn set_frame_pool_size(&mut self, size: usize) {
+        self.frame_pool_size.set(size);
+        self.frame_pool.borrow_mut().truncate(size);
+    }
+
+    pub fn set_diagnostics(&mut self, enabled: bool) {
+        self.diagnostics.set(enabled);
+    }
+
+    // Assigning nil to a global still counts as defining it
+    pub fn set_strict_globals(&mut self, enabled: bool) {
+        self.strict_globals.set(enabled);
+    }
+
+    // Install or remove (None) the instruction hook, it also applies to already created closures
+    pub fn set_hook(&mut self, hook: Option<InstructionHook>) {
+        *self.hook.borrow_mut() = hook;
+    }
+
+    // Redirect print and io.write, e.g. to an Rc<RefCell<Vec<u8>>> to capture output
+    pub fn set_output(&mut self, sink: OutputSink) {
+        *self.output.borrow_mut() = sink;
+    }
+
+    // Also applies to print functions created before the call
+    pub fn set_print_config(&mut self, config: PrintConfig) {
+        *self.print_config.borrow_mut() = config;
+    }
+
+    // Intercept os.exit, if the handler returns the script stops with LuaError::ExitRequested
+    pub fn set_exit_handler(&mut self, handler: ExitHandler) {
+        *self.exit_handler.borrow_mut() = handler;
+    }
+
+    // Decode and validate a chunk, rejecting malformed and invalid bytecode before it's executed
+    pub fn load_and_validate(bytes: &[u8]) -> Result<CompiledChunk, LoadError> {
+        let prototype = bytecode::read_bytecode_sync(bytes)?;
+        validate::validate(&prototype)?;
+        Ok(CompiledChunk { prototype })
+    }
+
     pub fn load_std_libraries(&mut self) -> LuaResult<()> {
         // Merge the two maps, overwrite any pre-existing members
-        let mut env = self.environment.borrow_mut();
-        let env_table = env.as_table_mut()?;
-        let mut insert = |t: BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>>| {
+        let env = self.environment.borrow();
+        let env_table = env.as_table()?;
+        let insert = |t: TableEntries| {
             for (k, v) in t.iter() {
                 env_table.insert(k.clone(), v.clone());
             }
         };
 
-        insert(libs::global::make());
+        // Set up first, libraries may keep a clone of the VM (e.g. loadstring)
+        let string = LuaTable::new(libs::string::make());
+        self.string_metatable = Some(LuaTable::new(lua_table! {
+            lua_string!("__index") => LuaValue::from(string.clone())
+        }));
+
+        insert(libs::global::make(self));
+        // GETGLOBAL/SETGLOBAL and _G share the same table
+        env_table.insert(lua_string!("_G").into(), env.clone().into());
+        env_table.insert(lua_string!("string").into(), LuaValue::from(string).into());
+        env_table.insert(lua_string!("io").into(), LuaValue::from(libs::io::make(self)).into());
+        env_table.insert(lua_string!("math").into(), LuaValue::from(libs::math::make(self)).into());
+        env_table.insert(lua_string!("os").into(), LuaValue::from(libs::os::make(self)).into());
+        env_table.insert(lua_string!("bit").into(), LuaValue::from(libs::bit::make()).into());
+        env_table.insert(lua_string!("debug").into(), LuaValue::from(libs::debug::make(self)).into());
+
+        // require keeps a handle on the package table, replacing package.preload or package.loaded is seen by it
+        let package = LuaTable::new(libs::package::make());
+        let require_package = package.clone();
+        env_table.insert(lua_string!("package").into(), LuaValue::from(package).into());
+        env_table.insert(lua_string!("require").into(), LuaValue::Function(lua_function!(move |args| libs::package::require(&require_package, args))).into());
 
         LuaResult::Ok(())
     }
 
-    pub fn execute(&mut self, function: LuaPrototype, args: Option<Vec<Rc<RefCell<LuaValue>>>>, upvalues: Option<Vec<Rc<RefCell<LuaValue>>>>) -> LuaRuntimeResult<Vec<Rc<RefCell<LuaValue>>>> {
-        let mut upvalues = match upvalues {
-            Some(v) => v,
-            None => Vec::new()
+    // Drop every global and reinstall the standard library. The table is swapped in place, so handles
+    // to this VM (closures, loadstring) see the fresh environment as well
+    pub fn reset_environment(&mut self) -> LuaResult<()> {
+        *self.environment.borrow_mut() = BTreeMap::new().into();
+        self.load_std_libraries()
+    }
+
+    // Names of the globals with a string key and a non-nil value, in table order
+    pub fn global_names(&self) -> Vec<String> {
+        self.environment.borrow().table_iter()
+            .filter_map(|(k, _)| match k {
+                LuaValue::String(s) => Some(s.to_string()),
+                _ => None
+            })
+            .collect()
+    }
+
+    // Metamethod of any value, strings share the string metatable
+    fn metamethod(&self, value: &LuaValue, event: &'static str) -> Option<Rc<RefCell<LuaValue>>> {
+        match value {
+            LuaValue::Table(_) | LuaValue::Userdata(_) => value.metamethod(event),
+            LuaValue::String(_) => self.string_metatable.as_ref()
+                .and_then(|mt| mt.get(&lua_string!(event)))
+                .filter(|v| !matches!(*v.borrow(), LuaValue::Nil)),
+            _ => None
+        }
+    }
+
+    // object[key] honoring __index, which is either called with (object, key) or indexed in turn
+    // Chains of __index tables are followed in a loop rather than by recursion, up to MAX_TAG_LOOP of them
+    fn get_index(&self, object: &LuaValue, key: &LuaValue) -> LuaResult<LuaValue> {
+        let mut object = object.clone();
+        for _ in 0..MAX_TAG_LOOP {
+            if let LuaValue::Table(t) = &object
+                && let Some(v) = t.get(key)
+                && !matches!(*v.borrow(), LuaValue::Nil) {
+                return LuaResult::Ok(v.borrow().clone());
+            }
+
+            let handler = match self.metamethod(&object, "__index") {
+                Some(h) => h.borrow().clone(),
+                None if matches!(object, LuaValue::Table(_) | LuaValue::String(_)) => return LuaResult::Ok(LuaValue::Nil),
+                None => return LuaResult::Err(LuaError::AttemptedIndexOfNonTable(object.type_name()))
+            };
+
+            match handler {
+                LuaValue::Function(f) => {
+                    let results = f.invoke(&vec![object.into(), key.clone().into()])?;
+                    return LuaResult::Ok(results.first().map(|r| r.borrow().clone()).unwrap_or(LuaValue::Nil));
+                },
+                handler => object = handler
+            }
+        }
+        LuaResult::Err(LuaError::IndexChainTooLong)
+    }
+
+    // Functions and tables with a __call metamethod, anything else fails before the call with an error
+    // naming the callee when the bytecode tells where it was loaded from
+    fn check_callable(&self, function: &LuaPrototype, value: &LuaValue, reg: usize, pc: i64) -> LuaResult<()> {
+        let callable = match value {
+            LuaValue::Function(_) => true,
+            LuaValue::Table(_) | LuaValue::Userdata(_) => value.metamethod("__call").is_some(),
+            _ => false
         };
+        if callable {
+            return LuaResult::Ok(());
+        }
+        LuaResult::Err(LuaError::AttemptedCall(value.type_name(), function.object_name(reg, pc)))
+    }
+
+    // object[key] = value honoring __newindex, only consulted when the key is absent from the table
+    fn set_index(&self, object: &LuaValue, key: LuaValue, value: LuaValue) -> LuaResult<()> {
+        key.check_key()?;
+
+        let mut object = object.clone();
+        for _ in 0..MAX_TAG_LOOP {
+            let handler = match &object {
+                LuaValue::Table(t) => {
+                    let present = t.get(&key).is_some_and(|v| !matches!(*v.borrow(), LuaValue::Nil));
+                    match t.metamethod("__newindex") {
+                        Some(h) if !present => h.borrow().clone(),
+                        _ => {
+                            t.insert(key.into(), value.into());
+                            return LuaResult::Ok(());
+                        }
+                    }
+                },
+                _ => match self.metamethod(&object, "__newindex") {
+                    Some(h) => h.borrow().clone(),
+                    None => return LuaResult::Err(LuaError::AttemptedIndexOfNonTable(object.type_name()))
+                }
+            };
+
+            match handler {
+                LuaValue::Function(f) => {
+                    f.invoke(&vec![object.into(), key.into(), value.into()])?;
+                    return LuaResult::Ok(());
+                },
+                handler => object = handler
+            }
+        }
+        LuaResult::Err(LuaError::IndexChainTooLong)
+    }
+
+    // Run a chunk as the main program, `args` become the top-level varargs (`...`)
+    pub fn run_main(&mut self, chunk: CompiledChunk, args: &[LuaValue]) -> LuaRuntimeResult<Vec<Rc<RefCell<LuaValue>>>> {
+        let args = args.iter().map(|a| a.clone().into()).collect();
+        self.execute(chunk.prototype, Some(args), None)
+    }
+
+    // Run a function with `env` as its globals table instead of the VM's environment, closures created
+    // by it keep using `env`. Pass a table with only the allowed functions to sandbox a script
+    pub fn execute_in(&mut self, function: LuaPrototype, env: LuaTable) -> LuaRuntimeResult<Vec<Rc<RefCell<LuaValue>>>> {
+        let mut sandbox = self.clone();
+        sandbox.environment = Rc::new(RefCell::new(env.into()));
+        sandbox.execute(function, None, None)
+    }
+
+    // Call a function value (e.g. a global defined by a chunk) with plain values
+    pub fn call_value(&mut self, f: &LuaValue, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+        let args = args.into_iter().map(|a| a.into()).collect();
+        let results = f.clone().call(args)?;
+        LuaResult::Ok(results.iter().map(|r| r.borrow().clone()).collect())
+    }
+
+    // call_value converting the first result to a host type, e.g. `let n: Option<i64> = vm.call_as(&f, vec![2i64.into_lua()])?`
+    pub fn call_as<R: FromLua>(&mut self, f: &LuaValue, args: Vec<LuaValue>) -> LuaResult<R> {
+        let first = self.call_value(f, args)?.into_iter().next().unwrap_or(LuaValue::Nil);
+        R::from_lua(first)
+    }
+
+    pub fn execute(&mut self, function: LuaPrototype, args: Option<Vec<Rc<RefCell<LuaValue>>>>, upvalues: Option<Vec<Rc<RefCell<LuaValue>>>>) -> LuaRuntimeResult<Vec<Rc<RefCell<LuaValue>>>> {
+        self.execute_closure(Rc::new(function), args.unwrap_or_default(), upvalues.unwrap_or_default())
+    }
+
+    pub(crate) fn execute_closure(&mut self, mut function: Rc<LuaPrototype>, mut args: Vec<Rc<RefCell<LuaValue>>>, mut upvalues: Vec<Rc<RefCell<LuaValue>>>) -> LuaRuntimeResult<Vec<Rc<RefCell<LuaValue>>>> {
+        // Tail calls to Lua functions replace the running frame instead of recursing
+        if self.call_stack.borrow().len() >= self.max_call_depth.get() {
+            return LuaRuntimeResult { inner: LuaResult::Err(LuaError::CallDepthExceeded), source_line: None, source_name: None, snapshot: None };
+        }
+
+        loop {
+            let info = CallInfo { prototype: function.clone(), pc: Rc::new(Cell::new(0)) };
+            self.call_stack.borrow_mut().push(info.clone());
+            let mut stack = self.frame_pool.borrow_mut().pop().unwrap_or_default();
+            let frame = self.execute_frame(&function, &info.pc, args, upvalues, &mut stack);
+            self.call_stack.borrow_mut().pop();
+
+            // Cleared first, a pooled buffer mustn't keep values alive
+            stack.clear();
+            let mut pool = self.frame_pool.borrow_mut();
+            if pool.len() < self.frame_pool_size.get() {
+                pool.push(stack);
+            }
+            drop(pool);
+
+            match frame {
+                Frame::Return(result) => return result,
+                Frame::TailCall(closure, tail_args) => {
+                    function = closure.prototype;
+                    args = tail_args;
+                    upvalues = closure.upvalues;
+                }
+            }
+        }
+    }
+
+    // `stack` is an empty buffer from the frame pool
+    fn execute_frame(&mut self, function: &LuaPrototype, current_pc: &Cell<i64>, args: Vec<Rc<RefCell<LuaValue>>>, upvalues: Vec<Rc<RefCell<LuaValue>>>, stack: &mut Vec<LuaValue>) -> Frame {
         let mut vararg = Vec::new();
+        // Captured registers by index, closed (dropped) by Close or when the frame ends
+        let mut open_upvalues: BTreeMap<usize, OpenUpvalue> = BTreeMap::new();
+        let mut tail_call = None;
 
         let mut pc = 0i64;
-        let mut stack: Vec<Rc<RefCell<LuaValue>>> = vec![Rc::new(RefCell::new(LuaValue::Nil)); 255];
+        let size = (function.max_stack_size as usize).max(function.param_count as usize + 1) + EXTRA_STACK;
+        // Registers hold plain values, they're only wrapped in Rc cells when passed to calls, stored
+        // in tables or captured as upvalues
+        stack.resize(size, LuaValue::Nil);
+        // One past the last value produced by a multret Call or Vararg (B/C == 0),
+        // read by the following Call/Return with B == 0
         let mut stack_top = 0usize;
 
-        // push args onto the stack
-        if let Some(args) = args {
-            for i in 0..function.param_count as usize {
-                stack[i] = args[i].clone();
+        // push args onto the stack, missing parameters stay nil
+        for i in 0..(function.param_count as usize).min(args.len()) {
+            stack[i] = args[i].borrow().clone();
+        }
+
+        // push excess args into the vararg vector, fixed-arity functions discard them
+        if function.vararg_flags & VARARG_ISVARARG != 0 {
+            for arg in args.iter().skip(function.param_count as usize) {
+                vararg.push(arg.borrow().clone());
             }
+        }
 
-            // push excess args into the vararg vector
-            for i in function.param_count as usize..args.len() {
-                vararg.push(args[i].clone());
+        // Lua 5.0 style vararg functions receive the extra args as the `arg` local
+        if function.vararg_flags & VARARG_NEEDSARG != 0 {
+            #[allow(clippy::mutable_key_type)]
+            let mut arg = TableEntries::new();
+            for (i, v) in vararg.iter().enumerate() {
+                arg.insert(LuaValue::from((i + 1) as f64).into(), v.clone().into());
             }
+            arg.insert(lua_string!("n").into(), LuaValue::from(vararg.len() as f64).into());
+            stack[function.param_count as usize] = LuaValue::from(arg);
         }
 
-        let instructions = function.instructions;
-        let constants = function.constants;
+        let instructions = &function.instructions;
+        let constants = &function.constants;
         
         /* 
         Instruction notation:
@@ -79,31 +644,44 @@ impl VirtualMachine {
         UV = upvalue
         */
         while pc < instructions.len() as i64 {
+            current_pc.set(pc);
+            self.steps.set(self.steps.get() + 1);
             let inst = &instructions[pc as usize];
             let mut ignore_result = false;
             let mut run = || {
+                // Registers beyond the frame are an error rather than a panic
+                if register_top(inst) > stack.len() {
+                    return LuaResult::Err(LuaError::StackOverflow);
+                }
+
+                if let Some(hook) = self.hook.borrow_mut().as_mut()
+                    && let HookAction::Abort(reason) = hook(pc as usize, inst, stack) {
+                    return LuaResult::Err(LuaError::AbortedByHook(reason));
+                }
+
                 match inst.code {
                     // S[A] = S[B]
                     OpCode::Move => {
                         stack[inst.A] = stack[inst.B].clone();
                     },
-                    // S[A]..S[B] = nil
+                    // S[A]..S[B] = nil, both ends included
                     OpCode::LoadNil => {
-                        for i in inst.A..inst.B {
-                            stack[i] = LuaValue::Nil.into();
+                        if inst.B < inst.A {
+                            return LuaResult::Err(LuaError::MalformedInstruction(pc as usize));
                         }
+                        stack[inst.A..=inst.B].fill(LuaValue::Nil);
                     },
                     // S[A] = K[Bx]
                     OpCode::LoadK => {
                         stack[inst.A] = match constants.get(inst.Bx) {
-                            Some(k) => k.clone(),
+                            Some(k) => k.borrow().clone(),
                             None => return LuaResult::Err(LuaError::ConstantNotFound(inst.Bx))
                         };
                     },
                     // S[A] = (bool)B
                     // If C != 0 then PC++
                     OpCode::LoadBool => {
-                        stack[inst.A] = LuaValue::Boolean(inst.B > 0).into();
+                        stack[inst.A] = LuaValue::Boolean(inst.B > 0);
                         if inst.C != 0 {
                             pc += 1;
                         }
@@ -114,9 +692,14 @@ impl VirtualMachine {
                             Some(n) => n,
                             None => return LuaResult::Err(LuaError::ConstantNotFound(inst.Bx))
                         };
-                        stack[inst.A] = match self.environment.borrow().as_table()?.get(name) {
-                            Some(v) => v.clone(),
-                            None => LuaValue::Nil.into()
+                        // Global names are always strings in well formed chunks
+                        if !matches!(*name.borrow(), LuaValue::String(_)) {
+                            return LuaResult::Err(LuaError::ExpectedString);
+                        }
+                        stack[inst.A] = match self.environment.borrow().as_table()?.get(&name.borrow()) {
+                            Some(v) => v.borrow().clone(),
+                            None if self.strict_globals.get() => return LuaResult::Err(LuaError::UndefinedGlobal(name.borrow().to_display_string())),
+                            None => LuaValue::Nil
                         };
                     },
                     // E[K[Bx]] = S[A]
@@ -125,79 +708,104 @@ impl VirtualMachine {
                             Some(n) => n,
                             None => return LuaResult::Err(LuaError::ConstantNotFound(inst.Bx))
                         };
-                        self.environment.borrow_mut().as_table_mut()?.insert(name.clone(), stack[inst.A].clone());
+                        // Global names are always strings in well formed chunks
+                        if !matches!(*name.borrow(), LuaValue::String(_)) {
+                            return LuaResult::Err(LuaError::ExpectedString);
+                        }
+                        self.environment.borrow().as_table()?.insert(name.clone(), stack[inst.A].clone().into());
                     },
                     // S[A] = UV[B]
                     OpCode::GetUpValue => {
-                        stack[inst.A] = upvalues[inst.Bx].clone();
+                        stack[inst.A] = match upvalues.get(inst.B) {
+                            Some(v) => v.borrow().clone(),
+                            None => return LuaResult::Err(LuaError::UpValueNotFound(inst.B))
+                        };
                     },
                     // UV[B] = S[A]
                     OpCode::SetUpValue => {
-                        upvalues[inst.B] = stack[inst.A].clone();
+                        if inst.B >= upvalues.len() {
+                            return LuaResult::Err(LuaError::UpValueNotFound(inst.B));
+                        }
+                        // Written in place so the enclosing function and sibling closures see it
+                        *upvalues[inst.B].borrow_mut() = stack[inst.A].clone();
                     },
                     // S[A] = S[B][SK[C]]
                     OpCode::GetTable => {
-                        let v = match &*stack[inst.B].borrow() {
-                            LuaValue::Table(t) => {
-                                t.get(&get_rk!(inst.C, constants, stack)).or(Some(&LuaValue::Nil.into())).unwrap().clone()
-                            },
-                            _ => LuaValue::Nil.into()
-                        };
-                        stack[inst.A] = v;
+                        // Values without an __index metamethod raise AttemptedIndexOfNonTable
+                        stack[inst.A] = self.get_index(&stack[inst.B], &get_rk!(inst.C, constants, stack))?;
                     },
                     // S[A][SK[B]] = SK[C]
                     OpCode::SetTable => {
-                        match &mut *stack[inst.A].borrow_mut() {
-                            LuaValue::Table(t) => {
-                                let index = get_rk!(inst.B, constants, stack);
-                                t.insert(index, get_rk!(inst.C, constants, stack));
-                            },
-                            _ => return LuaResult::Err(LuaError::AttemptedIndexOfNonTable)
-                        }
+                        let key = get_rk!(inst.B, constants, stack);
+                        let value = get_rk!(inst.C, constants, stack);
+                        self.set_index(&stack[inst.A], key, value)?;
                     },
                     // S[A] = SK[B] <operation> SK[C]
                     OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::Pow | OpCode::Mod => {
-                        let lhs = get_rk!(inst.B, constants, stack).borrow().clone();
-                        let rhs = get_rk!(inst.C, constants, stack).borrow().clone();
-                        let res = match inst.code {
-                            OpCode::Add => lhs + rhs,
-                            OpCode::Sub => lhs - rhs,
-                            OpCode::Mul => lhs * rhs,
-                            OpCode::Div => lhs / rhs,
-                            OpCode::Pow => lhs.pow(rhs),
-                            OpCode::Mod => lhs.modulo(rhs),
-                            _ => panic!()
+                        // Fast path for two numbers, must agree with the LuaValue operators below
+                        let res = if let (Some(a), Some(b)) = (rk_number(inst.B, constants, stack), rk_number(inst.C, constants, stack)) {
+                            LuaValue::from(match inst.code {
+                                OpCode::Add => a + b,
+                                OpCode::Sub => a - b,
+                                OpCode::Mul => a * b,
+                                OpCode::Div => a / b,
+                                OpCode::Pow => a.powf(b),
+                                OpCode::Mod => number::modulo(a, b),
+                                _ => panic!()
+                            })
+                        } else {
+                            let lhs = get_rk!(inst.B, constants, stack);
+                            let rhs = get_rk!(inst.C, constants, stack);
+                            match inst.code {
+                                OpCode::Add => lhs + rhs,
+                                OpCode::Sub => lhs - rhs,
+                                OpCode::Mul => lhs * rhs,
+                                OpCode::Div => lhs / rhs,
+                                OpCode::Pow => lhs.pow(rhs),
+                                OpCode::Mod => lhs.modulo(rhs),
+                                _ => panic!()
+                            }?
                         };
-                        stack[inst.A] = res?.into();
+                        stack[inst.A] = res;
                     },
                     // S[A] = -S[B]
                     OpCode::UnaryMinus => {
-                        let v = stack[inst.B].borrow().clone().unm()?;
-                        stack[inst.A] = v.into();
+                        // Like luaV_execute the metamethod receives the operand twice
+                        let v = match &stack[inst.B] {
+                            LuaValue::Number(_) | LuaValue::String(_) => stack[inst.B].clone().unm()?,
+                            operand => match self.metamethod(operand, "__unm") {
+                                Some(tm) => {
+                                    let results = tm.borrow().clone().call(vec![operand.clone().into(), operand.clone().into()])?;
+                                    results.first().map(|r| r.borrow().clone()).unwrap_or(LuaValue::Nil)
+                                },
+                                None => operand.clone().unm()?
+                            }
+                        };
+                        stack[inst.A] = v;
                     },
                     // S[A] = not S[B]
                     OpCode::Not => {
-                        let v = match *stack[inst.B].borrow() {
+                        let v = match stack[inst.B] {
                             LuaValue::Boolean(b) => LuaValue::Boolean(!b),
                             _ => return LuaResult::Err(LuaError::AttemptedNotOperationOnNonBoolean)
                         };
-                        stack[inst.A] = v.into();
+                        stack[inst.A] = v;
                     },
                     // S[A] = length of S[B]
                     OpCode::Len => {
-                        let v = match stack[inst.B].borrow().clone() {
+                        let v = match &stack[inst.B] {
                             LuaValue::String(s) => LuaValue::Number((s.len() as f64).into()),
-                            LuaValue::Table(t) => LuaValue::Number((t.keys().len() as f64).into()),
-                            _ => return LuaResult::Err(LuaError::UnsupportedLengthOperation)
+                            LuaValue::Table(t) => LuaValue::Number((t.border() as f64).into()),
+                            v => return LuaResult::Err(LuaError::UnsupportedLengthOperation(v.type_name()))
                         };
-                        stack[inst.A] = v.into();
+                        stack[inst.A] = v;
                     },
                     // S[A] = concat S[B..C]
                     OpCode::Concat => {
-                        let mut v = stack[inst.B].clone();
-                        for i in inst.B+1..inst.C+1 {
-                            let x = v.borrow().clone().concat(stack[i].borrow().clone())?.into();
-                            v = x;
+                        // Right associative like luaV_concat, metamethods see the partial result
+                        let mut v = stack[inst.C].clone();
+                        for i in (inst.B..inst.C).rev() {
+                            v = concat_values(&stack[i], &v)?;
                         }
                         stack[inst.A] = v;
                     },
@@ -213,18 +821,28 @@ impl VirtualMachine {
                         } else {
                             inst.A + inst.B
                         };
-                        for i in inst.A + 1..last_arg_idx {
-                            args.push(stack[i].clone());
+                        // B == 0 without an earlier multret leaves stack_top below the arguments
+                        if last_arg_idx < inst.A + 1 {
+                            return LuaResult::Err(LuaError::MalformedInstruction(pc as usize));
+                        }
+                        for value in &stack[inst.A + 1..last_arg_idx] {
+                            args.push(value.clone().into());
                         }
 
-                        let results = stack[inst.A].borrow().clone().call(args)?;
+                        self.check_callable(function, &stack[inst.A], inst.A, pc)?;
+                        let results = stack[inst.A].clone().call(args)?;
                         
                         if inst.C == 0 {
-                            stack_top = inst.A + results.len() - 1;
+                            stack_top = inst.A + results.len();
+                            grow_stack(stack, stack_top)?;
                         }
 
+                        // Lua pads missing results with nil
                         for i in 0.. if inst.C != 0 { inst.C - 1 } else { results.len() } {
-                            stack[inst.A + i] = results[i].clone();
+                            stack[inst.A + i] = match results.get(i) {
+                                Some(v) => v.borrow().clone(),
+                                None => LuaValue::Nil
+                            };
                         }
                     },
                     // return S[A]..S[A+B-1]
@@ -236,36 +854,56 @@ impl VirtualMachine {
                         } else {
                             inst.A + inst.B - 1
                         };
+                        if last_value_idx < inst.A {
+                            return LuaResult::Err(LuaError::MalformedInstruction(pc as usize));
+                        }
 
-                        for i in inst.A..last_value_idx {
-                            values.push(stack[i].clone());
+                        for value in &stack[inst.A..last_value_idx] {
+                            values.push(value.clone().into());
                         }
 
                         return LuaResult::Ok(values);
                     },
                     // return S[A](S[A+1]..S[A+B-1])
                     OpCode::TailCall => {
-                        let mut args = Vec::new();
-                        
-                        for i in inst.A + 1..inst.A + inst.B - 1 {
-                            args.push(stack[i].clone());
+                        // Same argument convention as Call, B == 0 passes everything up to stack_top
+                        let last_arg_idx = if inst.B == 0 {
+                            stack_top
+                        } else {
+                            inst.A + inst.B
+                        };
+                        // B == 0 without an earlier multret leaves stack_top below the arguments
+                        if last_arg_idx < inst.A + 1 {
+                            return LuaResult::Err(LuaError::MalformedInstruction(pc as usize));
+                        }
+                        let args = stack[inst.A + 1..last_arg_idx].iter().map(|v| v.clone().into()).collect();
+
+                        // Lua functions are run by execute_closure in place of this frame. A closure from
+                        // another environment (execute_in) is called normally so it keeps its own globals
+                        let callee = stack[inst.A].clone();
+                        self.check_callable(function, &callee, inst.A, pc)?;
+                        if let LuaValue::Function(f) = &callee && let Some(closure) = f.closure()
+                            && Rc::ptr_eq(&closure.vm.environment, &self.environment) {
+                            tail_call = Some((closure.clone(), args));
+                            return LuaResult::Ok(vec![]);
                         }
 
-                        return LuaResult::Ok(stack[inst.A].borrow().clone().call(args)?);
+                        return callee.call(args);
                     },
-                    // S[A]..S[A+B] = vararg
+                    // S[A]..S[A+B-2] = vararg
                     OpCode::Vararg => {
                         let len = if inst.B == 0 {
                             stack_top = inst.A + vararg.len();
+                            grow_stack(stack, stack_top)?;
                             vararg.len()
                         } else {
-                            inst.B
+                            inst.B - 1
                         };
 
                         for i in 0..len {
                             let v = match vararg.get(i) {
                                 Some(v) => v.clone(),
-                                None => LuaValue::Nil.into()
+                                None => LuaValue::Nil
                             };
                             stack[inst.A + i] = v;
                         }
@@ -274,16 +912,7 @@ impl VirtualMachine {
                     // S[A] = S[B](SK[C])
                     OpCode::LSelf => {
                         stack[inst.A + 1] = stack[inst.B].clone();
-                        let v = match &*stack[inst.B].borrow() {
-                            LuaValue::Table(t) => {
-                                let key = get_rk!(inst.C, constants, stack);
-                                match t.get(&key) {
-                                    Some(v) => v.clone(),
-                                    None => LuaValue::Nil.into()
-                                }
-                            },
-                            _ => return LuaResult::Err(LuaError::AttemptedIndexOfNonTable)
-                        };
+                        let v = self.get_index(&stack[inst.B], &get_rk!(inst.C, constants, stack))?;
                         stack[inst.A] = v;
                     },
                     // If SK[B] <operation> SK[C] != A then PC++
@@ -291,9 +920,9 @@ impl VirtualMachine {
                         let lhs = get_rk!(inst.B, constants, stack);
                         let rhs = get_rk!(inst.C, constants, stack);
                         let res = match inst.code {
-                            OpCode::Eq => lhs.eq(&rhs),
-                            OpCode::Lt => lhs.lt(&rhs),
-                            OpCode::Le => lhs.le(&rhs),
+                            OpCode::Eq => values_equal(&lhs, &rhs)?,
+                            OpCode::Lt => less_than(&lhs, &rhs)?,
+                            OpCode::Le => less_equal(&lhs, &rhs)?,
                             _ => panic!()
                         };
 
@@ -301,20 +930,14 @@ impl VirtualMachine {
                             pc += 1;
                         }
                     },
+                    // Only nil and false are false
                     OpCode::Test => {
-                        if let LuaValue::Boolean(b) = &*stack[inst.A].borrow() {
-                            if *b != (inst.C == 1) {
-                                pc += 1;
-                            }
+                        if stack[inst.A].is_truthy() != (inst.C == 1) {
+                            pc += 1;
                         }
                     },
                     OpCode::TestSet => {
-                        let v = match &*stack[inst.B].borrow() {
-                            LuaValue::Boolean(b) => *b,
-                            _ => panic!()
-                        };
-
-                        if v == (inst.C == 1) {
+                        if stack[inst.B].is_truthy() == (inst.C == 1) {
                             stack[inst.A] = stack[inst.B].clone();
                         } else {
                             pc += 1;
@@ -323,75 +946,112 @@ impl VirtualMachine {
                     // S[A] -= S[A+2]
                     // PC += sBX
                     OpCode::ForPrep => {
-                        let index = stack[inst.A].borrow().clone();
-                        let step = stack[inst.A + 2].borrow().clone();
-                        stack[inst.A] = index.sub(step)?.into();
+                        // Numeric strings are converted once here like arithmetic operands, so ForLoop only sees numbers
+                        let mut values = [0f64; 3];
+                        for (i, what) in ["initial value", "limit", "step"].into_iter().enumerate() {
+                            values[i] = stack[inst.A + i].coerce_number()
+                                .map_err(|_| LuaError::ForLoopExpectedNumber(what))?;
+                        }
+
+                        let [index, limit, step] = values;
+                        if step == 0.0 {
+                            return LuaResult::Err(LuaError::ForLoopZeroStep);
+                        }
+                        stack[inst.A] = LuaValue::from(index - step);
+                        stack[inst.A + 1] = LuaValue::from(limit);
+                        stack[inst.A + 2] = LuaValue::from(step);
                         pc += inst.sBx;
                     },
                     // S[A] += S[A+2]
-                    // if S[A] < S[A+1]
+                    // if S[A] <= S[A+1] (>= for a negative step)
                     //   S[A+3] = S[A]
                     //   PC += sBx
                     OpCode::ForLoop => {
-                        let index = stack[inst.A].borrow().clone();
-                        let limit = stack[inst.A + 1].borrow().clone();
-                        let step = stack[inst.A + 2].borrow().clone();
+                        // Add first and compare the sum like luaV_execute, so float steps accumulate
+                        // the same rounding and run the same number of iterations as Lua
+                        let mut values = [0f64; 3];
+                        for (i, what) in ["initial value", "limit", "step"].into_iter().enumerate() {
+                            values[i] = match &stack[inst.A + i] {
+                                LuaValue::Number(n) => n.0,
+                                _ => return LuaResult::Err(LuaError::ForLoopExpectedNumber(what))
+                            };
+                        }
 
-                        let do_loop = if step >= 0f64.into() {
-                            index < limit
-                        } else {
-                            index > limit
-                        };
+                        let [index, limit, step] = values;
+                        let index = index + step;
+                        let do_loop = if 0.0 < step { index <= limit } else { limit <= index };
 
                         if do_loop {
-                            stack[inst.A] = (index.clone() + step)?.into();
-                            stack[inst.A + 3] = stack[inst.A].clone();
+                            stack[inst.A] = LuaValue::from(index);
+                            stack[inst.A + 3] = LuaValue::from(index);
                             pc += inst.sBx;
                         }
                     },
-                    // S[A+3]..S[A+2=C] = S[A](S[A+1], S[A+2])
+                    // S[A+3]..S[A+2+C] = S[A](S[A+1], S[A+2])
                     // if S[A+3] != nil
                     //   S[A+2] = S[A+3]
                     // else
                     //   PC++
-                    // Note: this is entirely untested as I haven't implemented iterator functions yet
                     OpCode::TForLoop => {
-                        let results = stack[inst.A].borrow().clone().call(vec![
-                            stack[inst.A + 1].clone(),
-                            stack[inst.A + 2].clone()
+                        let results = stack[inst.A].clone().call(vec![
+                            stack[inst.A + 1].clone().into(),
+                            stack[inst.A + 2].clone().into()
                         ])?;
 
-                        for i in inst.A + 3..inst.A + 2 + inst.C {
-                            stack[i] = results[i - inst.A - 3].clone();
+                        // Iterators signal the end by returning nothing, pad missing results with nil
+                        for i in 0..inst.C {
+                            stack[inst.A + 3 + i] = match results.get(i) {
+                                Some(v) => v.borrow().clone(),
+                                None => LuaValue::Nil
+                            };
                         }
 
-                        if !matches!(*stack[inst.A + 3].borrow(), LuaValue::Nil) {
+                        if !matches!(stack[inst.A + 3], LuaValue::Nil) {
                             stack[inst.A + 2] = stack[inst.A + 3].clone();
-                            pc += inst.sBx;
+                        } else {
+                            // Skip the jump back to the loop body
+                            pc += 1;
                         }
-
-                        pc += 1;
                     },
                     // S[A] = array table of size B, filled with nils
                     OpCode::NewTable => {
-                        let mut table: BTreeMap<Rc<RefCell<LuaValue>>, Rc<RefCell<LuaValue>>> = BTreeMap::new();
+                        #[allow(clippy::mutable_key_type)]
+                        let mut table = TableEntries::new();
 
                         for i in 1..inst.B + 1 {
                             table.insert(LuaValue::Number((i as f64).into()).into(), LuaValue::Nil.into());
                         }
 
-                        stack[inst.A] = LuaValue::Table(table).into();
+                        stack[inst.A] = LuaValue::from(table);
                     },
                     // S[A][(C-1)*FIELDS_PER_FLUSH+i] = S[A+i]
                     OpCode::SetList => {
-                        match &mut *stack[inst.A].borrow_mut() {
+                        match &stack[inst.A] {
                             LuaValue::Table(t) => {
-                                for i in 1..inst.B {
-                                    let key = (((inst.C - 1) * FIELDS_PER_FLUSH + i) as f64).into();
-                                    t.insert(LuaValue::Number(key).into(), stack[inst.A + i].clone());
+                                // B == 0 stores everything up to stack_top, left by a multret call or vararg
+                                let count = if inst.B == 0 {
+                                    stack_top.saturating_sub(inst.A + 1)
+                                } else {
+                                    inst.B
+                                };
+
+                                // C == 0 means the batch number didn't fit into C and is the following ExtraArg
+                                let batch = if inst.C == 0 {
+                                    pc += 1;
+                                    match instructions.get(pc as usize) {
+                                        Some(extra) => extra.Bx,
+                                        None => return LuaResult::Err(LuaError::MalformedInstruction(pc as usize - 1))
+                                    }
+                                } else {
+                                    inst.C
+                                };
+
+                                for i in 1..=count {
+                                    let key = (((batch - 1) * FIELDS_PER_FLUSH + i) as f64).into();
+                                    t.insert(LuaValue::Number(key).into(), stack[inst.A + i].clone().into());
                                 }
                             },
-                            _ => return LuaResult::Err(LuaError::AttemptedIndexOfNonTable)
+                            v => return LuaResult::Err(LuaError::AttemptedIndexOfNonTable(v.type_name()))
                         }
                     },
                     // S[A] = function.prototypes[Bx]
@@ -404,8 +1064,17 @@ impl VirtualMachine {
                             for i in 0..sub_func.upvalue_count as usize {
                                 let pseudo = &instructions[(pc as usize) + i + 1];
 
+                                // Closures capturing the same register share its cell
                                 if matches!(pseudo.code, OpCode::Move) {
-                                    sub_upvalues.push(stack[pseudo.B].clone());
+                                    let value = match stack.get(pseudo.B) {
+                                        Some(v) => v.clone(),
+                                        None => return LuaResult::Err(LuaError::StackOverflow)
+                                    };
+                                    let upvalue = open_upvalues.entry(pseudo.B).or_insert_with(|| OpenUpvalue {
+                                        cell: Rc::new(RefCell::new(value.clone())),
+                                        synced: value
+                                    });
+                                    sub_upvalues.push(upvalue.cell.clone());
                                 } else if matches!(pseudo.code, OpCode::GetUpValue) {
                                     sub_upvalues.push(upvalues[pseudo.B].clone());
                                 }
@@ -413,26 +1082,25 @@ impl VirtualMachine {
 
                             pc += sub_func.upvalue_count as i64;
 
-                            Some(sub_upvalues)
+                            sub_upvalues
                         } else {
-                            None
+                            Vec::new()
                         };
 
-
-                        // Create new virtual machine and clone a reference to the environment
-                        let mut new_vm = VirtualMachine::new();
-                        new_vm.environment = self.environment.clone();
-                        let func = lua_function!(move |args| {
-                            new_vm.execute(sub_func.clone(), Some(args.to_vec()), sub_upvalues.clone()).inner
+                        // The closure keeps a VM handle sharing the environment and state
+                        let func = LuaFunction::from_closure(LuaClosure {
+                            prototype: Rc::new(sub_func),
+                            upvalues: sub_upvalues,
+                            vm: self.clone()
                         });
-                        stack[inst.A] = LuaValue::Function(func).into();
+                        stack[inst.A] = LuaValue::Function(func);
                     },
-                    // UV[0..A] = nil
+                    // Close upvalues of S[A] and above, later closures capture fresh cells
                     OpCode::Close => {
-                        for i in 0..inst.A {
-                            upvalues[i] = LuaValue::Nil.into();
-                        }
-                    }
+                        open_upvalues.retain(|&reg, _| reg < inst.A);
+                    },
+                    // Consumed by SetList, only reached by a skip over the SETLIST
+                    OpCode::ExtraArg => {}
                 };
 
                 ignore_result = true;
@@ -440,17 +1108,36 @@ impl VirtualMachine {
             };
 
             let result = run();
+            sync_upvalues(stack, &mut open_upvalues);
+            if let Some((closure, args)) = tail_call.take() {
+                return Frame::TailCall(closure, args);
+            }
             if !ignore_result {
-                return LuaRuntimeResult {
+                let snapshot = if result.is_err() && self.diagnostics.get() {
+                    let top = stack_top.max(function.max_stack_size as usize).min(stack.len());
+                    Some(VmSnapshot {
+                        pc,
+                        opcode: instructions[pc as usize].code,
+                        registers: stack[..top].iter().enumerate()
+                            .map(|(i, v)| (i, v.clone()))
+                            .filter(|(_, v)| !matches!(v, LuaValue::Nil))
+                            .collect()
+                    })
+                } else {
+                    None
+                };
+
+                return Frame::Return(LuaRuntimeResult {
                     inner: result,
                     source_line: function.source_line_positions.get(pc as usize).copied(),
-                    source_name: function.source_name
-                };
+                    source_name: function.source_name.clone(),
+                    snapshot
+                });
             }
 
             pc += 1;
         }
 
-        LuaRuntimeResult { inner: LuaResult::Ok(vec![]), source_line: None, source_name: None }
+        Frame::Return(LuaRuntimeResult { inner: LuaResult::Ok(vec![]), source_line: None, source_name: None, snapshot: None })
     }
 }
@@ -0,0 +1,359 @@
+mod common;
+
+use common::*;
+use lua51_vm::{bytecode::{self, DecodeError, Instruction, LuaLocal, LuaPrototype}, types::{LuaError, value::LuaValue}, validate::ValidationError, vm::{LoadError, VirtualMachine}};
+
+fn dump(chunk: &LuaPrototype) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytecode::write_bytecode(chunk, &mut bytes).unwrap();
+    bytes
+}
+
+// Position of the little endian instruction `word` in a dumped chunk
+fn find_word(bytes: &[u8], word: u32) -> usize {
+    bytes.windows(4).position(|w| w == word.to_le_bytes()).expect("word is in the chunk")
+}
+
+#[test]
+fn malformed_opcode_is_a_decode_error() {
+    let mut bytes = dump(&chunk(&[], &[abc(RETURN, 0, 1, 0)]));
+    let at = find_word(&bytes, abc(RETURN, 0, 1, 0));
+    bytes[at] = 40;
+
+    let result = VirtualMachine::load_and_validate(&bytes);
+    assert!(matches!(result, Err(LoadError::Decode(DecodeError::InvalidOpCode(40)))));
+}
+
+#[test]
+fn load_and_validate_checks_registers() {
+    // LOADK into register 5 of a function with 2 registers
+    let mut function = LuaPrototype::new();
+    function.max_stack_size = 2;
+    function.constants.push(n(1.0).into());
+    for word in [abx(LOADK, 5, 0), abc(RETURN, 0, 1, 0)] {
+        function.instructions.push(Instruction::try_from(word).unwrap());
+        function.source_line_positions.push(1);
+    }
+
+    let result = VirtualMachine::load_and_validate(&dump(&function));
+    assert!(matches!(result, Err(LoadError::Validation(ValidationError::InvalidRegister { pc: 0, register: 5 }))));
+
+    function.max_stack_size = 6;
+    let loaded = VirtualMachine::load_and_validate(&dump(&function)).unwrap();
+    assert_eq!(loaded.prototype.instructions.len(), 2);
+}
+
+#[test]
+fn load_and_validate_rejects_a_reversed_loadnil() {
+    let mut function = LuaPrototype::new();
+    function.max_stack_size = 6;
+    for word in [abc(LOADNIL, 5, 2, 0), abc(RETURN, 0, 1, 0)] {
+        function.instructions.push(Instruction::try_from(word).unwrap());
+        function.source_line_positions.push(1);
+    }
+
+    let result = VirtualMachine::load_and_validate(&dump(&function));
+    assert!(matches!(result, Err(LoadError::Validation(ValidationError::InvalidRegister { pc: 0, register: 2 }))));
+}
+
+#[test]
+fn builder_reports_malformed_opcodes() {
+    let result = function(&[], &[63, abc(RETURN, 0, 1, 0)]).build();
+    assert!(matches!(result, Err(LoadError::Decode(DecodeError::InvalidOpCode(63)))));
+}
+
+// local t = {} t[549 * 50 + 1] = "x" (SETLIST batch 550) return t[27451]
+fn large_batch() -> Vec<u32> {
+    vec![
+        abc(NEWTABLE, 0, 0, 0),
+        abx(LOADK, 1, 0),
+        abc(SETLIST, 0, 1, 0),
+        550,
+        abc(GETTABLE, 2, 0, k(1)),
+        abc(RETURN, 2, 2, 0)
+    ]
+}
+
+#[test]
+fn setlist_batch_word_is_not_decoded() {
+    // 550 & 63 is 38, which isn't an opcode
+    let built = chunk(&[s("x"), n(27451.0)], &large_batch());
+    assert_eq!(run(built.clone()).unwrap(), vec![s("x")]);
+
+    let loaded = VirtualMachine::load_and_validate(&dump(&built)).unwrap();
+    assert_eq!(run(loaded.prototype).unwrap(), vec![s("x")]);
+}
+
+#[test]
+fn setlist_batch_zero_is_rejected() {
+    let mut code = large_batch();
+    code[3] = 0;
+    let result = function(&[s("x"), n(27451.0)], &code).build();
+    assert!(matches!(result, Err(LoadError::Validation(ValidationError::InvalidBatch { pc: 2 }))));
+}
+
+#[test]
+fn register_beyond_frame_is_a_stack_overflow() {
+    // LOADK into register 200 of a function with 2 registers, executed without validation
+    let mut function = LuaPrototype::new();
+    function.max_stack_size = 2;
+    function.constants.push(n(1.0).into());
+    for word in [abx(LOADK, 200, 0), abc(RETURN, 0, 1, 0)] {
+        function.instructions.push(Instruction::try_from(word).unwrap());
+        function.source_line_positions.push(1);
+    }
+
+    assert!(matches!(run(function), Err(LuaError::StackOverflow)));
+}
+
+#[test]
+fn global_names_must_be_strings() {
+    // GETGLOBAL 0 K(0) where K(0) is the number 1
+    let code = [abx(GETGLOBAL, 0, 0), abc(RETURN, 0, 2, 0)];
+    let result = function(&[n(1.0)], &code).build();
+    assert!(matches!(result, Err(LoadError::Validation(ValidationError::InvalidGlobalName { pc: 0, index: 0 }))));
+
+    // The VM checks it too when a chunk skips validation
+    let mut function = LuaPrototype::new();
+    function.max_stack_size = 2;
+    function.constants.push(n(1.0).into());
+    for word in code {
+        function.instructions.push(Instruction::try_from(word).unwrap());
+        function.source_line_positions.push(1);
+    }
+    assert!(matches!(run(function), Err(LuaError::ExpectedString)));
+}
+
+#[test]
+fn loadstring_returns_a_callable_chunk() {
+    // loadstring(<bytecode of "return 42">)(), source code is rejected with nil and a message
+    let blob = dump(&chunk(&[n(42.0)], &[abx(LOADK, 0, 0), abc(RETURN, 0, 2, 0)]));
+    let mut vm = vm();
+    let f = call(&mut vm, "loadstring", vec![LuaValue::String(blob.into())]).unwrap().remove(0);
+    assert_eq!(vm.call_value(&f, vec![]).unwrap(), vec![n(42.0)]);
+
+    let result = call(&mut vm, "loadstring", vec![s("return 42")]).unwrap();
+    assert_eq!(result.len(), 2);
+    assert_eq!(result[0], LuaValue::Nil);
+}
+
+#[test]
+fn loadfile_and_dofile_read_bytecode_files() {
+    let path = std::env::temp_dir().join(format!("lua51-vm-dofile-{}.luac", std::process::id()));
+    std::fs::write(&path, dump(&chunk(&[n(7.0)], &[abx(LOADK, 0, 0), abc(RETURN, 0, 2, 0)]))).unwrap();
+    let name = s(path.to_str().unwrap());
+    let mut vm = vm();
+
+    let f = call(&mut vm, "loadfile", vec![name.clone()]).unwrap().remove(0);
+    assert_eq!(vm.call_value(&f, vec![]).unwrap(), vec![n(7.0)]);
+    assert_eq!(call(&mut vm, "dofile", vec![name]).unwrap(), vec![n(7.0)]);
+    std::fs::remove_file(&path).unwrap();
+
+    // A missing file is a nil and a message from loadfile but an error from dofile
+    let missing = s(path.to_str().unwrap());
+    let result = call(&mut vm, "loadfile", vec![missing.clone()]).unwrap();
+    assert_eq!(result[0], LuaValue::Nil);
+    assert!(matches!(call(&mut vm, "dofile", vec![missing]), Err(LuaError::ChunkLoadFailed(_))));
+}
+
+#[test]
+fn binary_string_constants_round_trip() {
+    let bytes: &[u8] = b"a\xff\0b";
+    let blob = dump(&chunk(&[LuaValue::String(bytes.into())], &[abx(LOADK, 0, 0), abc(RETURN, 0, 2, 0)]));
+    let loaded = VirtualMachine::load_and_validate(&blob).unwrap();
+    let LuaValue::String(constant) = loaded.prototype.constants[0].borrow().clone() else { panic!("constant is a string") };
+    assert_eq!(constant.as_bytes(), bytes);
+}
+
+#[test]
+fn local_names_resolve_by_register_and_pc() {
+    // local a = 1 local b = 2 do local c = 3 end local d = 4
+    let mut chunk = chunk(&[n(1.0), n(2.0), n(3.0), n(4.0)], &[
+        abx(LOADK, 0, 0),
+        abx(LOADK, 1, 1),
+        abx(LOADK, 2, 2),
+        abx(LOADK, 2, 3),
+        abc(RETURN, 0, 1, 0)
+    ]);
+    for (name, start_pc, end_pc) in [("a", 1, 5), ("b", 2, 5), ("c", 3, 4), ("d", 4, 5)] {
+        chunk.locals.push(LuaLocal { name: name.to_owned(), start_pc, end_pc });
+    }
+
+    assert_eq!(chunk.local_name_at(0, 1), Some("a"));
+    assert_eq!(chunk.local_name_at(1, 1), None);
+    assert_eq!(chunk.local_name_at(2, 3), Some("c"));
+    assert_eq!(chunk.local_name_at(2, 4), Some("d"));
+    assert_eq!(*chunk.constant(3).unwrap(), n(4.0));
+    assert!(chunk.constant(4).is_none());
+}
+
+#[test]
+fn builder_prototypes_run_through_execute() {
+    // return "hi"
+    let prototype = LuaPrototype::builder()
+        .max_stack_size(2)
+        .constant("hi")
+        .instruction(abx(LOADK, 0, 0))
+        .instruction(abc(RETURN, 0, 2, 0))
+        .build()
+        .unwrap();
+    let results = VirtualMachine::new().execute(prototype, None, None).inner.unwrap();
+    assert_eq!(*results[0].borrow(), s("hi"));
+}
+
+// A chunk as a big-endian luac with 4 byte size_t would write it, the layout otherwise matches write_bytecode
+fn dump_big_endian(chunk: &LuaPrototype) -> Vec<u8> {
+    fn string(out: &mut Vec<u8>, s: &[u8]) {
+        out.extend((s.len() as u32 + 1).to_be_bytes());
+        out.extend(s);
+        out.push(0);
+    }
+    fn int(out: &mut Vec<u8>, n: i64) {
+        out.extend((n as i32).to_be_bytes());
+    }
+    fn function(out: &mut Vec<u8>, f: &LuaPrototype, parent: Option<&String>) {
+        match f.source_name.as_ref().filter(|s| Some(*s) != parent) {
+            Some(s) => string(out, s.as_bytes()),
+            None => out.extend(0u32.to_be_bytes())
+        }
+        int(out, f.line_defined);
+        int(out, f.last_line_defined);
+        out.extend([f.upvalue_count, f.param_count, f.vararg_flags, f.max_stack_size]);
+        int(out, f.instructions.len() as i64);
+        for inst in &f.instructions {
+            out.extend(inst.encode().to_be_bytes());
+        }
+        int(out, f.constants.len() as i64);
+        for constant in &f.constants {
+            match &*constant.borrow() {
+                LuaValue::Number(n) => {
+                    out.push(3);
+                    out.extend(n.0.to_be_bytes());
+                },
+                LuaValue::String(s) => {
+                    out.push(4);
+                    string(out, s.as_bytes());
+                },
+                v => panic!("no fixture encoding for {:?}", v)
+            }
+        }
+        int(out, f.prototypes.len() as i64);
+        for p in &f.prototypes {
+            function(out, p, f.source_name.as_ref());
+        }
+        int(out, f.source_line_positions.len() as i64);
+        for line in &f.source_line_positions {
+            int(out, *line);
+        }
+        int(out, f.locals.len() as i64);
+        for local in &f.locals {
+            string(out, local.name.as_bytes());
+            int(out, local.start_pc);
+            int(out, local.end_pc);
+        }
+        int(out, f.upvalues.len() as i64);
+        for upvalue in &f.upvalues {
+            string(out, upvalue.as_bytes());
+        }
+    }
+
+    let mut out = vec![0x1B, 0x4C, 0x75, 0x61, 0x51, 0, 0, 4, 4, 4, 8, 0];
+    function(&mut out, chunk, None);
+    out
+}
+
+#[test]
+fn big_endian_chunks_decode_like_little_endian_ones() {
+    // local x = -1.5 local function f() return "nested" end return f(), x .. "str"
+    let f = function(&[s("nested")], &[
+        abx(LOADK, 0, 0),
+        abc(RETURN, 0, 2, 0)
+    ]).build().unwrap();
+    let mut chunk = function(&[n(-1.5), s("str")], &[
+        abx(LOADK, 0, 0),
+        abx(CLOSURE, 1, 0),
+        abc(MOVE, 2, 1, 0),
+        abc(CALL, 2, 1, 2),
+        abc(MOVE, 3, 0, 0),
+        abx(LOADK, 4, 1),
+        abc(CONCAT, 3, 3, 4),
+        asbx(JMP, 0, 0),
+        abc(RETURN, 2, 3, 0)
+    ]).prototype(f).vararg_flags(2).build().unwrap();
+    chunk.locals.push(LuaLocal { name: "x".to_owned(), start_pc: 1, end_pc: 9 });
+    chunk.upvalues.push("unused".to_owned());
+
+    let big = bytecode::read_bytecode_sync(&dump_big_endian(&chunk)).unwrap();
+    let little = bytecode::read_bytecode_sync(&dump(&chunk)).unwrap();
+    assert_eq!(format!("{:?}", big), format!("{:?}", little));
+    assert_eq!(format!("{:?}", big), format!("{:?}", chunk));
+
+    let loaded = VirtualMachine::load_and_validate(&dump_big_endian(&chunk)).unwrap();
+    assert_eq!(run(loaded.prototype).unwrap(), vec![s("nested"), s("-1.5str")]);
+}
+
+#[test]
+fn oversized_counts_are_rejected_before_allocating() {
+    let mut bytes = dump(&chunk(&[s("x")], &[abc(RETURN, 0, 1, 0)]));
+    // The instruction count sits right before the first instruction
+    let at = find_word(&bytes, abc(RETURN, 0, 1, 0)) - 4;
+    assert_eq!(bytes[at..at + 4], 1u32.to_le_bytes());
+
+    let mut huge = bytes.clone();
+    huge[at..at + 4].copy_from_slice(&4_000_000_000u32.to_le_bytes());
+    assert!(matches!(bytecode::read_bytecode_sync(&huge), Err(DecodeError::MalformedSizeField)));
+
+    huge[at..at + 4].copy_from_slice(&0x7fff_ffffu32.to_le_bytes());
+    assert!(matches!(bytecode::read_bytecode_sync(&huge), Err(DecodeError::MalformedSizeField)));
+
+    // A configured maximum rejects counts the default would accept
+    bytes[at..at + 4].copy_from_slice(&2u32.to_le_bytes());
+    let limits = bytecode::DecodeLimits { max_count: 1, ..Default::default() };
+    assert!(matches!(bytecode::read_bytecode_sync_with_limits(&bytes, limits), Err(DecodeError::MalformedSizeField)));
+}
+
+#[test]
+fn instructions_encode_back_to_their_words() {
+    // The batch word, a backwards jump and RK operands cover every op mode
+    let mut words = large_batch();
+    words.splice(5..5, [
+        abc(EQ, 1, 2, k(0)),
+        asbx(JMP, 0, -2),
+        abx(GETGLOBAL, 3, 0)
+    ]);
+    let built = chunk(&[s("x"), n(27451.0)], &words);
+    let bytes = dump(&built);
+
+    let decoded = bytecode::read_bytecode_sync(&bytes).unwrap();
+    let encoded: Vec<u32> = decoded.instructions.iter().map(u32::from).collect();
+    assert_eq!(encoded, words);
+    assert_eq!(dump(&decoded), bytes);
+}
+
+#[test]
+fn written_chunks_read_back_unchanged() {
+    // local x = 1 local f = function() return x end return f(), "s", true, nil
+    let mut inner = function(&[], &[abc(GETUPVAL, 0, 0, 0), abc(RETURN, 0, 2, 0)]).upvalue_count(1).build().unwrap();
+    inner.upvalues.push("x".to_owned());
+    inner.line_defined = 1;
+    inner.last_line_defined = 1;
+    let mut outer = function(&[n(1.0), s("s"), LuaValue::from(true), LuaValue::Nil], &[
+        abx(LOADK, 0, 0),
+        abx(CLOSURE, 1, 0),
+        abc(MOVE, 0, 0, 0),
+        abc(MOVE, 2, 1, 0),
+        abc(CALL, 2, 1, 2),
+        abx(LOADK, 3, 1),
+        abx(LOADK, 4, 2),
+        abx(LOADK, 5, 3),
+        abc(RETURN, 2, 5, 0)
+    ]).prototype(inner).vararg_flags(2).build().unwrap();
+    outer.locals.push(LuaLocal { name: "x".to_owned(), start_pc: 1, end_pc: 9 });
+    outer.locals.push(LuaLocal { name: "f".to_owned(), start_pc: 3, end_pc: 9 });
+
+    let bytes = dump(&outer);
+    let read = bytecode::read_bytecode_sync(&bytes).unwrap();
+    assert_eq!(format!("{:?}", read), format!("{:?}", outer));
+    assert_eq!(dump(&read), bytes);
+    assert_eq!(run(read).unwrap(), vec![n(1.0), s("s"), LuaValue::from(true), LuaValue::Nil]);
+}
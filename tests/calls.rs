@@ -0,0 +1,356 @@
+mod common;
+
+use common::*;
+use std::{cell::RefCell, rc::Rc};
+
+use lua51_vm::{types::{LuaError, LuaRuntimeResult, value::LuaValue}, vm::CompiledChunk};
+
+#[test]
+fn tail_call_keeps_the_callee_environment() {
+    // x = "main" function g() return x end
+    let g = function(&[s("x")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(RETURN, 0, 2, 0)
+    ]).build().unwrap();
+    let main = function(&[s("x"), s("main"), s("g")], &[
+        abx(LOADK, 0, 1),
+        abx(SETGLOBAL, 0, 0),
+        abx(CLOSURE, 0, 0),
+        abx(SETGLOBAL, 0, 2),
+        abc(RETURN, 0, 1, 0)
+    ]).prototype(g).vararg_flags(2).build().unwrap();
+    let mut vm = vm();
+    run_in(&mut vm, main).unwrap();
+
+    // Run in a sandbox where x = "sandbox": return g()
+    let mut env = LuaValue::new_table();
+    env.table_set(s("x"), s("sandbox")).unwrap();
+    env.table_set(s("g"), vm.environment.borrow().table_get(&s("g")).unwrap()).unwrap();
+    let LuaValue::Table(env) = env else { unreachable!() };
+    let sandboxed = chunk(&[s("g")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(TAILCALL, 0, 1, 0),
+        abc(RETURN, 0, 0, 0)
+    ]);
+    let results = vm.execute_in(sandboxed, env).inner.unwrap();
+    assert_eq!(*results[0].borrow(), s("main"));
+}
+
+#[test]
+fn sandbox_without_print_reads_nil() {
+    // return print, x in an env holding only x
+    let mut env = LuaValue::new_table();
+    env.table_set(s("x"), s("sandbox")).unwrap();
+    let LuaValue::Table(env) = env else { unreachable!() };
+    let chunk = chunk(&[s("print"), s("x")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(GETGLOBAL, 1, 1),
+        abc(RETURN, 0, 3, 0)
+    ]);
+    let mut vm = vm();
+    let results = vm.execute_in(chunk, env).inner.unwrap();
+    assert_eq!(*results[0].borrow(), LuaValue::Nil);
+    assert_eq!(*results[1].borrow(), s("sandbox"));
+    assert!(matches!(global(&vm, "print"), LuaValue::Function(_)));
+}
+
+#[test]
+fn missing_call_results_are_nil() {
+    // local a, b, c = (function() return 1 end)() over registers holding "junk"
+    let f = function(&[n(1.0)], &[
+        abx(LOADK, 0, 0),
+        abc(RETURN, 0, 2, 0)
+    ]).build().unwrap();
+    let chunk = function(&[s("junk")], &[
+        abx(LOADK, 1, 0),
+        abx(LOADK, 2, 0),
+        abx(CLOSURE, 0, 0),
+        abc(CALL, 0, 1, 4),
+        abc(RETURN, 0, 4, 0)
+    ]).prototype(f).vararg_flags(2).build().unwrap();
+    assert_eq!(run(chunk).unwrap(), vec![n(1.0), LuaValue::Nil, LuaValue::Nil]);
+}
+
+#[test]
+fn tail_calls_reuse_the_frame() {
+    // function count(n) if n == 0 then return "done" end return count(n - 1) end
+    let count = function(&[n(0.0), s("done"), s("count"), n(1.0)], &[
+        abc(EQ, 0, 0, k(0)),
+        asbx(JMP, 0, 2),
+        abx(LOADK, 1, 1),
+        abc(RETURN, 1, 2, 0),
+        abx(GETGLOBAL, 1, 2),
+        abc(SUB, 2, 0, k(3)),
+        abc(TAILCALL, 1, 2, 0),
+        abc(RETURN, 1, 0, 0)
+    ]).param_count(1).build().unwrap();
+    // return count(100000)
+    let chunk = function(&[s("count"), n(100000.0)], &[
+        abx(CLOSURE, 0, 0),
+        abx(SETGLOBAL, 0, 0),
+        abx(GETGLOBAL, 0, 0),
+        abx(LOADK, 1, 1),
+        abc(CALL, 0, 2, 0),
+        abc(RETURN, 0, 0, 0)
+    ]).prototype(count).vararg_flags(2).build().unwrap();
+    assert_eq!(run(chunk).unwrap(), vec![s("done")]);
+}
+
+// function(...) return select("#", ...) end
+fn count_args() -> lua51_vm::bytecode::LuaPrototype {
+    function(&[s("select"), s("#")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(LOADK, 1, 1),
+        abc(VARARG, 2, 0, 0),
+        abc(CALL, 0, 0, 2),
+        abc(RETURN, 0, 2, 0)
+    ]).vararg_flags(2).build().unwrap()
+}
+
+#[test]
+fn empty_vararg_passes_no_arguments() {
+    // local f = count_args return f(...) called without arguments
+    let chunk = function(&[s("junk")], &[
+        abx(CLOSURE, 0, 0),
+        abx(LOADK, 1, 0),
+        abc(VARARG, 1, 0, 0),
+        abc(CALL, 0, 0, 2),
+        abc(RETURN, 0, 2, 0)
+    ]).prototype(count_args()).vararg_flags(2).build().unwrap();
+    assert_eq!(run(chunk).unwrap(), vec![n(0.0)]);
+}
+
+#[test]
+fn only_vararg_functions_keep_extra_arguments() {
+    let args = || Some(vec![n(1.0).into(), n(2.0).into(), n(3.0).into()]);
+    let results = |r: LuaRuntimeResult<Vec<Rc<RefCell<LuaValue>>>>| r.inner.unwrap().iter().map(|v| v.borrow().clone()).collect::<Vec<_>>();
+    let mut vm = vm();
+
+    assert_eq!(results(vm.execute(count_args(), args(), None)), vec![n(3.0)]);
+
+    // function(a, b) return a, b end drops the third argument
+    let fixed = function(&[], &[
+        abc(RETURN, 0, 3, 0)
+    ]).param_count(2).build().unwrap();
+    assert_eq!(results(vm.execute(fixed, args(), None)), vec![n(1.0), n(2.0)]);
+
+    // Lua 5.0 style function(a, ...) return arg.n, arg[1] end gets the extras in arg
+    let legacy = function(&[s("n"), n(1.0)], &[
+        abc(GETTABLE, 2, 1, k(0)),
+        abc(GETTABLE, 3, 1, k(1)),
+        abc(RETURN, 2, 3, 0)
+    ]).param_count(1).vararg_flags(7).build().unwrap();
+    assert_eq!(results(vm.execute(legacy, args(), None)), vec![n(2.0), n(2.0)]);
+}
+
+#[test]
+fn select_counts_trailing_nils() {
+    // return count_args(1, nil, nil)
+    let chunk = function(&[n(1.0)], &[
+        abx(CLOSURE, 0, 0),
+        abx(LOADK, 1, 0),
+        abc(LOADNIL, 2, 3, 0),
+        abc(CALL, 0, 4, 2),
+        abc(RETURN, 0, 2, 0)
+    ]).prototype(count_args()).vararg_flags(2).build().unwrap();
+    assert_eq!(run(chunk).unwrap(), vec![n(3.0)]);
+}
+
+#[test]
+fn run_main_passes_top_level_varargs() {
+    // return select('#', ...)
+    let chunk = chunk(&[s("select"), s("#")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(LOADK, 1, 1),
+        abc(VARARG, 2, 0, 0),
+        abc(CALL, 0, 0, 2),
+        abc(RETURN, 0, 2, 0)
+    ]);
+    let mut vm = vm();
+    let results = vm.run_main(CompiledChunk { prototype: chunk }, &[s("a"), LuaValue::Nil, n(3.0)]).inner.unwrap();
+    assert_eq!(*results[0].borrow(), n(3.0));
+}
+
+#[test]
+fn getupval_reads_the_indexed_upvalue() {
+    // local a, b = 1, 2 local function f() b = b + 1 return b end return f(), a
+    let f = function(&[n(1.0)], &[
+        abc(GETUPVAL, 0, 1, 0),
+        abc(ADD, 0, 0, k(0)),
+        abc(SETUPVAL, 0, 1, 0),
+        abc(GETUPVAL, 0, 1, 0),
+        abc(RETURN, 0, 2, 0)
+    ]).upvalue_count(2).build().unwrap();
+    let chunk = function(&[n(1.0), n(2.0)], &[
+        abx(LOADK, 0, 0),
+        abx(LOADK, 1, 1),
+        abx(CLOSURE, 2, 0),
+        abc(MOVE, 0, 0, 0),
+        abc(MOVE, 0, 1, 0),
+        abc(CALL, 2, 1, 2),
+        abc(MOVE, 3, 0, 0),
+        abc(RETURN, 2, 3, 0)
+    ]).prototype(f).vararg_flags(2).build().unwrap();
+    assert_eq!(run(chunk).unwrap(), vec![n(3.0), n(1.0)]);
+}
+
+#[test]
+fn call_value_runs_a_lua_defined_global() {
+    // function add(a, b) return a + b end
+    let add = function(&[], &[
+        abc(ADD, 2, 0, 1),
+        abc(RETURN, 2, 2, 0)
+    ]).param_count(2).build().unwrap();
+    let chunk = function(&[s("add")], &[
+        abx(CLOSURE, 0, 0),
+        abx(SETGLOBAL, 0, 0),
+        abc(RETURN, 0, 1, 0)
+    ]).prototype(add).vararg_flags(2).build().unwrap();
+
+    let mut vm = vm();
+    run_in(&mut vm, chunk).unwrap();
+    let add = global(&vm, "add");
+    assert_eq!(vm.call_value(&add, vec![n(2.0), n(3.0)]).unwrap(), vec![n(5.0)]);
+}
+
+#[test]
+fn tail_calls_forward_every_argument() {
+    // return count_args(1, 2)
+    let fixed = function(&[n(1.0), n(2.0)], &[
+        abx(CLOSURE, 0, 0),
+        abx(LOADK, 1, 0),
+        abx(LOADK, 2, 1),
+        abc(TAILCALL, 0, 3, 0),
+        abc(RETURN, 0, 0, 0)
+    ]).prototype(count_args()).vararg_flags(2).build().unwrap();
+    assert_eq!(run(fixed).unwrap(), vec![n(2.0)]);
+
+    // return count_args(...) with three top-level varargs
+    let multret = function(&[], &[
+        abx(CLOSURE, 0, 0),
+        abc(VARARG, 1, 0, 0),
+        abc(TAILCALL, 0, 0, 0),
+        abc(RETURN, 0, 0, 0)
+    ]).prototype(count_args()).vararg_flags(2).build().unwrap();
+    let results = vm().run_main(CompiledChunk { prototype: multret }, &[n(1.0), n(2.0), n(3.0)]).inner.unwrap();
+    assert_eq!(*results[0].borrow(), n(3.0));
+}
+
+#[test]
+fn fixed_vararg_copies_b_minus_one_values() {
+    // local a, b, c c = "kept" a, b = ... return a, b, c with three varargs
+    let chunk = chunk(&[s("kept")], &[
+        abx(LOADK, 2, 0),
+        abc(VARARG, 0, 3, 0),
+        abc(RETURN, 0, 4, 0)
+    ]);
+    let results = vm().run_main(CompiledChunk { prototype: chunk }, &[n(1.0), n(2.0), n(3.0)]).inner.unwrap();
+    let results: Vec<_> = results.iter().map(|v| v.borrow().clone()).collect();
+    assert_eq!(results, vec![n(1.0), n(2.0), s("kept")]);
+}
+
+#[test]
+fn closures_in_a_loop_capture_their_own_variable() {
+    // local fs = {} for i = 1, 3 do fs[i] = function() return i end end return fs[1](), fs[2](), fs[3]()
+    let f = function(&[], &[
+        abc(GETUPVAL, 0, 0, 0),
+        abc(RETURN, 0, 2, 0)
+    ]).upvalue_count(1).build().unwrap();
+    let chunk = function(&[n(1.0), n(3.0), n(2.0)], &[
+        abc(NEWTABLE, 0, 3, 0),
+        abx(LOADK, 1, 0),
+        abx(LOADK, 2, 1),
+        abx(LOADK, 3, 0),
+        asbx(FORPREP, 1, 4),
+        abx(CLOSURE, 5, 0),
+        abc(MOVE, 0, 4, 0),
+        abc(SETTABLE, 0, 4, 5),
+        abc(CLOSE, 4, 0, 0),
+        asbx(FORLOOP, 1, -5),
+        abc(GETTABLE, 1, 0, k(0)),
+        abc(CALL, 1, 1, 2),
+        abc(GETTABLE, 2, 0, k(2)),
+        abc(CALL, 2, 1, 2),
+        abc(GETTABLE, 3, 0, k(1)),
+        abc(CALL, 3, 1, 2),
+        abc(RETURN, 1, 4, 0)
+    ]).prototype(f).vararg_flags(2).build().unwrap();
+    assert_eq!(run(chunk).unwrap(), vec![n(1.0), n(2.0), n(3.0)]);
+}
+
+#[test]
+fn deep_recursion_is_a_clean_error() {
+    // function f(n) if n == 0 then return 0 end return 1 + f(n - 1) end
+    let f = function(&[n(0.0), s("f"), n(1.0)], &[
+        abc(EQ, 0, 0, k(0)),
+        asbx(JMP, 0, 1),
+        abc(RETURN, 0, 2, 0),
+        abx(GETGLOBAL, 1, 1),
+        abc(SUB, 2, 0, k(2)),
+        abc(CALL, 1, 2, 2),
+        abc(ADD, 1, k(2), 1),
+        abc(RETURN, 1, 2, 0)
+    ]).param_count(1).build().unwrap();
+    let chunk = |depth: f64| function(&[s("f"), n(depth)], &[
+        abx(CLOSURE, 0, 0),
+        abx(SETGLOBAL, 0, 0),
+        abx(LOADK, 1, 1),
+        abc(CALL, 0, 2, 0),
+        abc(RETURN, 0, 0, 0)
+    ]).prototype(f.clone()).vararg_flags(2).build().unwrap();
+
+    let mut vm = vm();
+    vm.set_max_call_depth(50);
+    assert_eq!(run_in(&mut vm, chunk(40.0)).unwrap(), vec![n(40.0)]);
+    assert!(matches!(run_in(&mut vm, chunk(1e6)), Err(LuaError::CallDepthExceeded)));
+}
+
+#[test]
+fn needsarg_functions_get_an_arg_table() {
+    // local function f(...) return arg.n, arg[1], arg[2] end return f("a", "b"), f()
+    let f = function(&[s("n"), n(1.0), n(2.0)], &[
+        abc(GETTABLE, 1, 0, k(0)),
+        abc(GETTABLE, 2, 0, k(1)),
+        abc(GETTABLE, 3, 0, k(2)),
+        abc(RETURN, 1, 4, 0)
+    ]).vararg_flags(7).build().unwrap();
+    let chunk = function(&[s("a"), s("b")], &[
+        abx(CLOSURE, 0, 0),
+        abc(MOVE, 1, 0, 0),
+        abx(LOADK, 2, 0),
+        abx(LOADK, 3, 1),
+        abc(CALL, 1, 3, 4),
+        abc(MOVE, 4, 0, 0),
+        abc(CALL, 4, 1, 4),
+        abc(RETURN, 1, 7, 0)
+    ]).prototype(f).vararg_flags(2).build().unwrap();
+    assert_eq!(run(chunk).unwrap(), vec![n(2.0), s("a"), s("b"), n(0.0), LuaValue::Nil, LuaValue::Nil]);
+}
+
+#[test]
+fn multret_return_passes_every_call_result_on() {
+    // local function f() return 1, 2, 3 end
+    let f = || function(&[n(1.0), n(2.0), n(3.0)], &[
+        abx(LOADK, 0, 0),
+        abx(LOADK, 1, 1),
+        abx(LOADK, 2, 2),
+        abc(RETURN, 0, 4, 0)
+    ]).build().unwrap();
+
+    // return f()
+    let direct = function(&[], &[
+        abx(CLOSURE, 0, 0),
+        abc(CALL, 0, 1, 0),
+        abc(RETURN, 0, 0, 0)
+    ]).prototype(f()).vararg_flags(2).build().unwrap();
+    assert_eq!(run(direct).unwrap(), vec![n(1.0), n(2.0), n(3.0)]);
+
+    // return "a", f()
+    let prefixed = function(&[s("a")], &[
+        abx(LOADK, 0, 0),
+        abx(CLOSURE, 1, 0),
+        abc(CALL, 1, 1, 0),
+        abc(RETURN, 0, 0, 0)
+    ]).prototype(f()).vararg_flags(2).build().unwrap();
+    assert_eq!(run(prefixed).unwrap(), vec![s("a"), n(1.0), n(2.0), n(3.0)]);
+}
@@ -0,0 +1,130 @@
+// Helpers shared by the integration tests. There is no compiler, so scripts are assembled from
+// instruction words; each test spells out the Lua it stands for in a comment
+#![allow(dead_code)]
+
+use std::{cell::RefCell, rc::Rc};
+
+use lua51_vm::{bytecode::{LuaPrototype, LuaPrototypeBuilder}, types::{LuaResult, value::LuaValue}, vm::VirtualMachine};
+
+pub const MOVE: u32 = 0;
+pub const LOADK: u32 = 1;
+pub const LOADBOOL: u32 = 2;
+pub const LOADNIL: u32 = 3;
+pub const GETUPVAL: u32 = 4;
+pub const GETGLOBAL: u32 = 5;
+pub const GETTABLE: u32 = 6;
+pub const SETGLOBAL: u32 = 7;
+pub const SETUPVAL: u32 = 8;
+pub const SETTABLE: u32 = 9;
+pub const NEWTABLE: u32 = 10;
+pub const SELF: u32 = 11;
+pub const ADD: u32 = 12;
+pub const SUB: u32 = 13;
+pub const MUL: u32 = 14;
+pub const DIV: u32 = 15;
+pub const MOD: u32 = 16;
+pub const POW: u32 = 17;
+pub const UNM: u32 = 18;
+pub const NOT: u32 = 19;
+pub const LEN: u32 = 20;
+pub const CONCAT: u32 = 21;
+pub const JMP: u32 = 22;
+pub const EQ: u32 = 23;
+pub const LT: u32 = 24;
+pub const LE: u32 = 25;
+pub const TEST: u32 = 26;
+pub const TESTSET: u32 = 27;
+pub const CALL: u32 = 28;
+pub const TAILCALL: u32 = 29;
+pub const RETURN: u32 = 30;
+pub const FORLOOP: u32 = 31;
+pub const FORPREP: u32 = 32;
+pub const TFORLOOP: u32 = 33;
+pub const SETLIST: u32 = 34;
+pub const CLOSE: u32 = 35;
+pub const CLOSURE: u32 = 36;
+pub const VARARG: u32 = 37;
+
+pub fn abc(op: u32, a: u32, b: u32, c: u32) -> u32 {
+    op | a << 6 | c << 14 | b << 23
+}
+
+pub fn abx(op: u32, a: u32, bx: u32) -> u32 {
+    op | a << 6 | bx << 14
+}
+
+pub fn asbx(op: u32, a: u32, sbx: i32) -> u32 {
+    op | a << 6 | ((sbx + 131071) as u32) << 14
+}
+
+// RK operand naming constant `i`
+pub fn k(i: u32) -> u32 {
+    256 + i
+}
+
+// A function with room for 32 registers, `code` must end in RETURN
+pub fn function(constants: &[LuaValue], code: &[u32]) -> LuaPrototypeBuilder {
+    let mut builder = LuaPrototype::builder().max_stack_size(32).source_name("@test.lua");
+    for c in constants {
+        builder = builder.constant(c.clone());
+    }
+    for (i, inst) in code.iter().enumerate() {
+        builder = builder.line(i as i64 + 1).instruction(*inst);
+    }
+    builder
+}
+
+// A vararg main chunk like luac produces
+pub fn chunk(constants: &[LuaValue], code: &[u32]) -> LuaPrototype {
+    function(constants, code).vararg_flags(2).build().expect("test chunk is valid")
+}
+
+pub fn vm() -> VirtualMachine {
+    let mut vm = VirtualMachine::new();
+    vm.load_std_libraries().expect("standard libraries load");
+    vm
+}
+
+// Redirect print and io.write of `vm` into a buffer
+pub fn capture(vm: &mut VirtualMachine) -> Rc<RefCell<Vec<u8>>> {
+    let buffer = Rc::new(RefCell::new(Vec::new()));
+    vm.set_output(buffer.clone());
+    buffer
+}
+
+pub fn output(buffer: &Rc<RefCell<Vec<u8>>>) -> String {
+    String::from_utf8(buffer.borrow().clone()).expect("output is UTF-8")
+}
+
+pub fn run_in(vm: &mut VirtualMachine, chunk: LuaPrototype) -> LuaResult<Vec<LuaValue>> {
+    let results = vm.execute(chunk, None, None).inner?;
+    LuaResult::Ok(results.iter().map(|v| v.borrow().clone()).collect())
+}
+
+// Run with the standard library loaded
+pub fn run(chunk: LuaPrototype) -> LuaResult<Vec<LuaValue>> {
+    run_in(&mut vm(), chunk)
+}
+
+// A global, or a field of one with "string.rep"
+pub fn global(vm: &VirtualMachine, path: &str) -> LuaValue {
+    let mut value = vm.environment.borrow().clone();
+    for name in path.split('.') {
+        value = value.table_get(&s(name)).expect("path goes through tables");
+    }
+    value
+}
+
+// Call a library function from the host, e.g. call(&mut vm, "string.rep", vec![s("a"), n(3.0)])
+pub fn call(vm: &mut VirtualMachine, path: &str, args: Vec<LuaValue>) -> LuaResult<Vec<LuaValue>> {
+    let f = global(vm, path);
+    vm.call_value(&f, args)
+}
+
+pub fn s(value: &str) -> LuaValue {
+    LuaValue::from(value)
+}
+
+pub fn n(value: f64) -> LuaValue {
+    LuaValue::from(value)
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use common::*;
+use lua51_vm::{convert::{FromLua, IntoLua}, types::value::LuaValue};
+
+#[test]
+fn integers_round_trip() {
+    assert_eq!(42i64.into_lua(), n(42.0));
+    assert_eq!(i64::from_lua(n(42.0)).unwrap(), 42);
+    assert_eq!(i64::from_lua(n(-2.7)).unwrap(), -2);
+    assert!(i64::from_lua(LuaValue::from(true)).is_err());
+}
+
+#[test]
+fn string_vectors_become_array_tables() {
+    // return #names, names[2]
+    let names = vec!["ada".to_owned(), "bob".to_owned()].into_lua();
+    let mut vm = vm();
+    vm.environment.borrow_mut().table_set(s("names"), names.clone()).unwrap();
+    let chunk = chunk(&[s("names"), n(2.0)], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(LEN, 1, 0, 0),
+        abc(GETTABLE, 2, 0, k(1)),
+        abc(RETURN, 1, 3, 0)
+    ]);
+    assert_eq!(run_in(&mut vm, chunk).unwrap(), vec![n(2.0), s("bob")]);
+    assert_eq!(Vec::<String>::from_lua(names).unwrap(), ["ada", "bob"]);
+}
+
+#[test]
+fn none_is_nil() {
+    assert_eq!(None::<i64>.into_lua(), LuaValue::Nil);
+    assert_eq!(Some(1.5).into_lua(), n(1.5));
+    assert_eq!(Option::<String>::from_lua(LuaValue::Nil).unwrap(), None);
+    assert_eq!(Option::<String>::from_lua(s("x")).unwrap(), Some("x".to_owned()));
+}
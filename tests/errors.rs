@@ -0,0 +1,319 @@
+mod common;
+
+use common::*;
+use std::rc::Rc;
+
+use lua51_vm::{bytecode::OpCode, types::{LuaError, value::LuaValue}, vm::HookAction};
+
+#[test]
+fn pcall_can_call_pcall() {
+    // return pcall(pcall, print, "hi")
+    let chunk = chunk(&[s("pcall"), s("print"), s("hi")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(GETGLOBAL, 1, 0),
+        abx(GETGLOBAL, 2, 1),
+        abx(LOADK, 3, 2),
+        abc(CALL, 0, 4, 0),
+        abc(RETURN, 0, 0, 0)
+    ]);
+    let mut vm = vm();
+    let out = capture(&mut vm);
+    let results = run_in(&mut vm, chunk).unwrap();
+    assert_eq!(results, vec![LuaValue::from(true), LuaValue::from(true)]);
+    assert_eq!(output(&out), "hi\n");
+}
+
+#[test]
+fn nested_pcall_reports_the_inner_error() {
+    // return pcall(pcall, error, "boom")
+    let chunk = chunk(&[s("pcall"), s("error"), s("boom")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(GETGLOBAL, 1, 0),
+        abx(GETGLOBAL, 2, 1),
+        abx(LOADK, 3, 2),
+        abc(CALL, 0, 4, 0),
+        abc(RETURN, 0, 0, 0)
+    ]);
+    let results = run(chunk).unwrap();
+    assert_eq!(results[0], LuaValue::from(true));
+    assert_eq!(results[1], LuaValue::from(false));
+    assert!(matches!(&results[2], LuaValue::String(_)));
+}
+
+#[test]
+fn pcall_passes_exit_requests_on() {
+    // return pcall(os.exit, 3)
+    let chunk = chunk(&[s("pcall"), s("os"), s("exit"), n(3.0)], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(GETGLOBAL, 1, 1),
+        abc(GETTABLE, 1, 1, k(2)),
+        abx(LOADK, 2, 3),
+        abc(CALL, 0, 3, 0),
+        abc(RETURN, 0, 0, 0)
+    ]);
+    let mut vm = vm();
+    vm.set_exit_handler(Rc::new(|_| {}));
+    assert!(matches!(run_in(&mut vm, chunk), Err(LuaError::ExitRequested(3))));
+}
+
+#[test]
+fn xpcall_passes_hook_aborts_on() {
+    // return xpcall(function() local x = 1 end, print)
+    let f = function(&[n(1.0)], &[
+        abx(LOADK, 0, 0),
+        abc(RETURN, 0, 1, 0)
+    ]).build().unwrap();
+    let chunk = function(&[s("xpcall"), s("print")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(CLOSURE, 1, 0),
+        abx(GETGLOBAL, 2, 1),
+        abc(CALL, 0, 3, 0),
+        abc(RETURN, 0, 0, 0)
+    ]).prototype(f).vararg_flags(2).build().unwrap();
+    let mut vm = vm();
+    vm.set_hook(Some(Box::new(|_, inst, _| match inst.code {
+        OpCode::LoadK => HookAction::Abort("budget".to_owned()),
+        _ => HookAction::Continue
+    })));
+    assert!(matches!(run_in(&mut vm, chunk), Err(LuaError::AbortedByHook(reason)) if reason == "budget"));
+}
+
+#[test]
+fn pcall_catches_call_depth_errors() {
+    // function f() local x = f() return x end return pcall(f)
+    let f = function(&[s("f")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(CALL, 0, 1, 2),
+        abc(RETURN, 0, 2, 0)
+    ]).build().unwrap();
+    let chunk = function(&[s("f"), s("pcall")], &[
+        abx(CLOSURE, 0, 0),
+        abx(SETGLOBAL, 0, 0),
+        abx(GETGLOBAL, 0, 1),
+        abx(GETGLOBAL, 1, 0),
+        abc(CALL, 0, 2, 0),
+        abc(RETURN, 0, 0, 0)
+    ]).prototype(f).vararg_flags(2).build().unwrap();
+    let mut vm = vm();
+    vm.set_max_call_depth(10);
+    let results = run_in(&mut vm, chunk).unwrap();
+    assert_eq!(results[0], LuaValue::from(false));
+    assert!(matches!(&results[1], LuaValue::String(_)));
+}
+
+#[test]
+fn getinfo_reports_the_calling_line() {
+    // local info = debug.getinfo(1) (on line 4) return info.currentline, info.source, info.linedefined
+    let g = function(&[s("debug"), s("getinfo"), n(1.0), s("currentline"), s("source"), s("linedefined")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(GETTABLE, 0, 0, k(1)),
+        abx(LOADK, 1, 2),
+        abc(CALL, 0, 2, 2),
+        abc(GETTABLE, 1, 0, k(3)),
+        abc(GETTABLE, 2, 0, k(4)),
+        abc(GETTABLE, 3, 0, k(5)),
+        abc(RETURN, 1, 4, 0)
+    ]).build().unwrap();
+    let chunk = function(&[], &[
+        abx(CLOSURE, 0, 0),
+        abc(CALL, 0, 1, 0),
+        abc(RETURN, 0, 0, 0)
+    ]).prototype(g).vararg_flags(2).build().unwrap();
+    assert_eq!(run(chunk).unwrap(), vec![n(4.0), s("@test.lua"), n(0.0)]);
+}
+
+#[test]
+fn arithmetic_errors_name_the_bad_operand() {
+    // return nil + 1 / return true * 2
+    let add = chunk(&[n(1.0)], &[
+        abc(LOADNIL, 0, 0, 0),
+        abc(ADD, 0, 0, k(0)),
+        abc(RETURN, 0, 2, 0)
+    ]);
+    let mul = chunk(&[n(2.0)], &[
+        abc(LOADBOOL, 0, 1, 0),
+        abc(MUL, 0, 0, k(0)),
+        abc(RETURN, 0, 2, 0)
+    ]);
+
+    let nil_error = run(add).unwrap_err();
+    assert!(matches!(nil_error, LuaError::UnsupportedArithmeticOperation("nil", 1)));
+    assert_eq!(nil_error.to_string(), "attempt to perform arithmetic on a nil value");
+    let boolean_error = run(mul).unwrap_err();
+    assert!(matches!(boolean_error, LuaError::UnsupportedArithmeticOperation("boolean", 1)));
+    assert_eq!(boolean_error.to_string(), "attempt to perform arithmetic on a boolean value");
+}
+
+#[test]
+fn xpcall_returns_the_handler_result() {
+    // return xpcall(function() error("boom", 0) end, function(m) return "handled: " .. m end)
+    let f = function(&[s("error"), s("boom"), n(0.0)], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(LOADK, 1, 1),
+        abx(LOADK, 2, 2),
+        abc(CALL, 0, 3, 1),
+        abc(RETURN, 0, 1, 0)
+    ]).build().unwrap();
+    let handler = function(&[s("handled: ")], &[
+        abx(LOADK, 1, 0),
+        abc(MOVE, 2, 0, 0),
+        abc(CONCAT, 1, 1, 2),
+        abc(RETURN, 1, 2, 0)
+    ]).param_count(1).build().unwrap();
+    let chunk = function(&[s("xpcall")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(CLOSURE, 1, 0),
+        abx(CLOSURE, 2, 1),
+        abc(CALL, 0, 3, 0),
+        abc(RETURN, 0, 0, 0)
+    ]).prototype(f).prototype(handler).vararg_flags(2).build().unwrap();
+    assert_eq!(run(chunk).unwrap(), vec![LuaValue::from(false), s("handled: boom")]);
+}
+
+#[test]
+fn length_errors_name_the_operand_type() {
+    // return #5 / return #true / return #nil
+    let cases = [
+        (abx(LOADK, 0, 0), "number"),
+        (abc(LOADBOOL, 0, 1, 0), "boolean"),
+        (abc(LOADNIL, 0, 0, 0), "nil")
+    ];
+    for (load, type_name) in cases {
+        let chunk = chunk(&[n(5.0)], &[load, abc(LEN, 0, 0, 0), abc(RETURN, 0, 2, 0)]);
+        let error = run(chunk).unwrap_err();
+        assert!(matches!(error, LuaError::UnsupportedLengthOperation(t) if t == type_name));
+        assert_eq!(error.to_string(), format!("attempt to get length of a {} value", type_name));
+    }
+}
+
+#[test]
+fn diagnostics_snapshot_the_faulting_frame() {
+    // local a, b = 5, true return a + b
+    let chunk = || chunk(&[n(5.0)], &[
+        abx(LOADK, 0, 0),
+        abc(LOADBOOL, 1, 1, 0),
+        abc(ADD, 2, 0, 1),
+        abc(RETURN, 2, 2, 0)
+    ]);
+    let mut vm = vm();
+    assert!(vm.execute(chunk(), None, None).snapshot.is_none());
+
+    vm.set_diagnostics(true);
+    let result = vm.execute(chunk(), None, None);
+    assert!(result.inner.is_err());
+    let snapshot = result.snapshot.expect("diagnostics capture a snapshot");
+    assert_eq!(snapshot.pc, 2);
+    assert!(matches!(snapshot.opcode, OpCode::Add));
+    assert_eq!(snapshot.registers, vec![(0, n(5.0)), (1, LuaValue::from(true))]);
+}
+
+#[test]
+fn arithmetic_coerces_lua_numeric_strings() {
+    // return "0x10" + 0, " 2 " * 3
+    let numeric = chunk(&[s("0x10"), n(0.0), s(" 2 "), n(3.0)], &[
+        abc(ADD, 0, k(0), k(1)),
+        abc(MUL, 1, k(2), k(3)),
+        abc(RETURN, 0, 3, 0)
+    ]);
+    assert_eq!(run(numeric).unwrap(), vec![n(16.0), n(6.0)]);
+
+    // return "abc" + 1
+    let letters = chunk(&[s("abc"), n(1.0)], &[
+        abc(ADD, 0, k(0), k(1)),
+        abc(RETURN, 0, 2, 0)
+    ]);
+    let error = run(letters).unwrap_err();
+    assert!(matches!(error, LuaError::UnsupportedArithmeticOperation("string", 1)));
+    assert_eq!(error.to_string(), "attempt to perform arithmetic on a string value");
+}
+
+#[test]
+fn indexing_nil_names_the_type() {
+    // local x = nil return x.y
+    let chunk = chunk(&[s("y")], &[
+        abc(LOADNIL, 0, 0, 0),
+        abc(GETTABLE, 1, 0, k(0)),
+        abc(RETURN, 1, 2, 0)
+    ]);
+    let error = run(chunk).unwrap_err();
+    assert!(matches!(error, LuaError::AttemptedIndexOfNonTable("nil")));
+    assert_eq!(error.to_string(), "attempt to index a nil value");
+}
+
+#[test]
+fn calling_an_undefined_global_names_it() {
+    // foo()
+    let global = chunk(&[s("foo")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(CALL, 0, 1, 1),
+        abc(RETURN, 0, 1, 0)
+    ]);
+    assert_eq!(run(global).unwrap_err().to_string(), "attempt to call a nil value (global 'foo')");
+
+    // string.nope()
+    let field = chunk(&[s("string"), s("nope")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(GETTABLE, 0, 0, k(1)),
+        abc(CALL, 0, 1, 1),
+        abc(RETURN, 0, 1, 0)
+    ]);
+    assert_eq!(run(field).unwrap_err().to_string(), "attempt to call a nil value (field 'nope')");
+}
+
+#[test]
+fn strict_globals_reject_undefined_reads() {
+    // defined = 1 return defined / return undefined
+    let defined = chunk(&[s("defined"), n(1.0)], &[
+        abx(LOADK, 0, 1),
+        abx(SETGLOBAL, 0, 0),
+        abx(GETGLOBAL, 1, 0),
+        abc(RETURN, 1, 2, 0)
+    ]);
+    let undefined = chunk(&[s("undefined")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(RETURN, 0, 2, 0)
+    ]);
+    let mut vm = vm();
+    vm.set_strict_globals(true);
+    assert_eq!(run_in(&mut vm, defined).unwrap(), vec![n(1.0)]);
+    assert!(matches!(run_in(&mut vm, undefined.clone()), Err(LuaError::UndefinedGlobal(name)) if name == "undefined"));
+
+    vm.set_strict_globals(false);
+    assert_eq!(run_in(&mut vm, undefined).unwrap(), vec![LuaValue::Nil]);
+}
+
+#[test]
+fn traceback_as_xpcall_handler_names_the_source() {
+    // return xpcall(function() error("boom") end, debug.traceback)
+    let f = function(&[s("error"), s("boom")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(LOADK, 1, 1),
+        abc(CALL, 0, 2, 1),
+        abc(RETURN, 0, 1, 0)
+    ]).build().unwrap();
+    let chunk = function(&[s("xpcall"), s("debug"), s("traceback")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(CLOSURE, 1, 0),
+        abx(GETGLOBAL, 2, 1),
+        abc(GETTABLE, 2, 2, k(2)),
+        abc(CALL, 0, 3, 0),
+        abc(RETURN, 0, 0, 0)
+    ]).prototype(f).vararg_flags(2).build().unwrap();
+    let results = run(chunk).unwrap();
+    assert_eq!(results[0], LuaValue::from(false));
+    let LuaValue::String(trace) = &results[1] else { panic!("traceback returns a string") };
+    let trace = trace.to_str_lossy();
+    assert!(trace.contains("boom") && trace.contains("test.lua"), "{}", trace);
+}
+
+#[test]
+fn pow_and_mod_coerce_strings_like_add() {
+    // return "2" ^ "3", "7" % "3", "0x10" % 3
+    let numeric = chunk(&[s("2"), s("3"), s("7"), s("0x10"), n(3.0)], &[
+        abc(POW, 0, k(0), k(1)),
+        abc(MOD, 1, k(2), k(1)),
+        abc(MOD, 2, k(3), k(4)),
+        abc(RETURN, 0, 4, 0)
+    ]);
+    assert_eq!(run(numeric).unwrap(), vec![n(8.0), n(1.0), n(1.0)]);
+}
@@ -0,0 +1,234 @@
+mod common;
+
+use common::*;
+use std::{cell::Cell, rc::Rc};
+
+use lua51_vm::{bytecode::{Instruction, LuaPrototype}, types::{LuaError, value::LuaValue}, vm::HookAction};
+
+#[test]
+fn step_count_matches_the_instructions_run() {
+    // for i = 1, 10 do end
+    // 3 loads, FORPREP, 11 FORLOOPs (10 iterations and the exit) and RETURN
+    let chunk = chunk(&[n(1.0), n(10.0)], &[
+        abx(LOADK, 0, 0),
+        abx(LOADK, 1, 1),
+        abx(LOADK, 2, 0),
+        asbx(FORPREP, 0, 0),
+        asbx(FORLOOP, 0, -1),
+        abc(RETURN, 0, 1, 0)
+    ]);
+    let mut vm = vm();
+    run_in(&mut vm, chunk).unwrap();
+    assert_eq!(vm.step_count(), 16);
+
+    vm.reset_step_count();
+    assert_eq!(vm.step_count(), 0);
+}
+
+#[test]
+fn for_loop_coerces_numeric_strings() {
+    // local sum = 0 for i = "1", "3" do sum = sum + i end return sum
+    let chunk = chunk(&[n(0.0), s("1"), s("3"), n(1.0)], &[
+        abx(LOADK, 0, 0),
+        abx(LOADK, 1, 1),
+        abx(LOADK, 2, 2),
+        abx(LOADK, 3, 3),
+        asbx(FORPREP, 1, 1),
+        abc(ADD, 0, 0, 4),
+        asbx(FORLOOP, 1, -2),
+        abc(RETURN, 0, 2, 0)
+    ]);
+    assert_eq!(run(chunk).unwrap(), vec![n(6.0)]);
+}
+
+#[test]
+fn for_loop_rejects_non_numeric_operands() {
+    // for i = 1, {} do end
+    let chunk = chunk(&[n(1.0)], &[
+        abx(LOADK, 0, 0),
+        abc(NEWTABLE, 1, 0, 0),
+        abx(LOADK, 2, 0),
+        asbx(FORPREP, 0, 0),
+        asbx(FORLOOP, 0, -1),
+        abc(RETURN, 0, 1, 0)
+    ]);
+    let error = run(chunk).unwrap_err();
+    assert!(matches!(error, LuaError::ForLoopExpectedNumber("limit")));
+    assert_eq!(error.to_string(), "'for' limit must be a number");
+}
+
+#[test]
+fn hook_counts_instructions_and_aborts() {
+    // while true do end
+    let chunk = chunk(&[], &[
+        asbx(JMP, 0, -1),
+        abc(RETURN, 0, 1, 0)
+    ]);
+    let mut vm = vm();
+    let seen = Rc::new(Cell::new(0));
+    let counter = seen.clone();
+    vm.set_hook(Some(Box::new(move |pc, _, _| {
+        assert_eq!(pc, 0);
+        counter.set(counter.get() + 1);
+        if counter.get() == 100 { HookAction::Abort("limit".to_owned()) } else { HookAction::Continue }
+    })));
+    assert!(matches!(run_in(&mut vm, chunk), Err(LuaError::AbortedByHook(reason)) if reason == "limit"));
+    assert_eq!(seen.get(), 100);
+}
+
+#[test]
+fn test_and_testset_use_truthiness() {
+    // local a = (0 and "taken" or "skipped"), (nil and "taken" or "skipped") written as ifs,
+    // then local t = {} or "c", nil or "c"
+    let chunk = chunk(&[n(0.0), s("taken"), s("skipped"), s("c")], &[
+        abx(LOADK, 0, 0),
+        abc(TEST, 0, 0, 0),
+        asbx(JMP, 0, 2),
+        abx(LOADK, 1, 1),
+        asbx(JMP, 0, 1),
+        abx(LOADK, 1, 2),
+        abc(LOADNIL, 0, 0, 0),
+        abc(TEST, 0, 0, 0),
+        asbx(JMP, 0, 2),
+        abx(LOADK, 2, 1),
+        asbx(JMP, 0, 1),
+        abx(LOADK, 2, 2),
+        abc(NEWTABLE, 4, 0, 0),
+        abx(LOADK, 5, 3),
+        abc(TESTSET, 3, 4, 1),
+        asbx(JMP, 0, 1),
+        abc(MOVE, 3, 5, 0),
+        abc(LOADNIL, 4, 4, 0),
+        abc(TESTSET, 6, 4, 1),
+        asbx(JMP, 0, 1),
+        abc(MOVE, 6, 5, 0),
+        abc(RETURN, 1, 7, 0)
+    ]);
+    let results = run(chunk).unwrap();
+    assert_eq!(results[..2], [s("taken"), s("skipped")]);
+    assert!(matches!(results[2], LuaValue::Table(_)));
+    assert_eq!(results[5], s("c"));
+}
+
+#[test]
+fn comparison_then_loadbool_assigns_both_outcomes() {
+    // local x, y = 1, 2 local a, b, c, d = x < y, y < x, x <= x, x == y
+    // The register after the idiom proves the LOADBOOL skip lands on the next instruction
+    let idiom = |op, target, lhs, rhs| [
+        abc(op, 1, lhs, rhs),
+        asbx(JMP, 0, 1),
+        abc(LOADBOOL, target, 0, 1),
+        abc(LOADBOOL, target, 1, 0)
+    ];
+    let mut code = vec![abx(LOADK, 0, 0), abx(LOADK, 1, 1)];
+    code.extend(idiom(LT, 2, 0, 1));
+    code.extend(idiom(LT, 3, 1, 0));
+    code.extend(idiom(LE, 4, 0, 0));
+    code.extend(idiom(EQ, 5, 0, 1));
+    code.extend([abx(LOADK, 6, 2), abc(RETURN, 2, 6, 0)]);
+
+    let chunk = chunk(&[n(1.0), n(2.0), s("after")], &code);
+    let (yes, no) = (LuaValue::from(true), LuaValue::from(false));
+    assert_eq!(run(chunk).unwrap(), vec![yes.clone(), no.clone(), yes, no, s("after")]);
+}
+
+#[test]
+fn for_loop_iteration_counts_match_reference_lua() {
+    // local count, last = 0 for i = start, limit, step do count = count + 1 last = i end return count, last
+    let count = |start: f64, limit: f64, step: f64| {
+        let chunk = chunk(&[n(0.0), n(1.0), n(start), n(limit), n(step)], &[
+            abx(LOADK, 0, 0),
+            abc(LOADNIL, 1, 1, 0),
+            abx(LOADK, 2, 2),
+            abx(LOADK, 3, 3),
+            abx(LOADK, 4, 4),
+            asbx(FORPREP, 2, 2),
+            abc(ADD, 0, 0, k(1)),
+            abc(MOVE, 1, 5, 0),
+            asbx(FORLOOP, 2, -3),
+            abc(RETURN, 0, 3, 0)
+        ]);
+        run(chunk).unwrap()
+    };
+    assert_eq!(count(1.0, 10.0, 1.0), vec![n(10.0), n(10.0)]);
+    // 0.1 + 0.1 + 0.1 overshoots 0.3, so reference Lua stops after 0.2
+    assert_eq!(count(0.0, 0.3, 0.1), vec![n(3.0), n(0.2)]);
+    assert_eq!(count(10.0, 1.0, -3.0), vec![n(4.0), n(1.0)]);
+    assert_eq!(count(1.0, 0.0, 1.0), vec![n(0.0), LuaValue::Nil]);
+}
+
+#[test]
+fn for_prep_coerces_every_string_operand() {
+    // local sum = 0 for i = start, limit, step do sum = sum + i end return sum
+    let sum = |start: LuaValue, limit: LuaValue, step: LuaValue| {
+        let chunk = chunk(&[n(0.0), start, limit, step], &[
+            abx(LOADK, 0, 0),
+            abx(LOADK, 1, 1),
+            abx(LOADK, 2, 2),
+            abx(LOADK, 3, 3),
+            asbx(FORPREP, 1, 1),
+            abc(ADD, 0, 0, 4),
+            asbx(FORLOOP, 1, -2),
+            abc(RETURN, 0, 2, 0)
+        ]);
+        run(chunk)
+    };
+    assert_eq!(sum(s("10"), s("1"), s("-3")).unwrap(), vec![n(22.0)]);
+    assert_eq!(sum(s("0x2"), s(" 6 "), s("2")).unwrap(), vec![n(12.0)]);
+
+    let error = sum(s("x"), n(1.0), n(1.0)).unwrap_err();
+    assert_eq!(error.to_string(), "'for' initial value must be a number");
+    let error = sum(n(1.0), n(2.0), s("")).unwrap_err();
+    assert_eq!(error.to_string(), "'for' step must be a number");
+}
+
+#[test]
+fn for_loop_rejects_a_zero_step() {
+    // for i = 1, 10, step do end
+    for step in [n(0.0), n(-0.0), s("0")] {
+        let chunk = chunk(&[n(1.0), n(10.0), step], &[
+            abx(LOADK, 0, 0),
+            abx(LOADK, 1, 1),
+            abx(LOADK, 2, 2),
+            asbx(FORPREP, 0, 0),
+            asbx(FORLOOP, 0, -1),
+            abc(RETURN, 0, 1, 0)
+        ]);
+        let error = run(chunk).unwrap_err();
+        assert!(matches!(error, LuaError::ForLoopZeroStep));
+        assert_eq!(error.to_string(), "'for' step is zero");
+    }
+}
+
+// A chunk that skips the builder's validation, like one passed straight to execute
+fn unvalidated(code: &[u32]) -> LuaPrototype {
+    let mut function = LuaPrototype::new();
+    function.max_stack_size = 8;
+    for word in code {
+        function.instructions.push(Instruction::try_from(*word).unwrap());
+        function.source_line_positions.push(1);
+    }
+    function
+}
+
+#[test]
+fn malformed_register_ranges_error_instead_of_panicking() {
+    // LOADNIL 5 2, the range ends before it starts
+    let error = run(unvalidated(&[abc(LOADNIL, 5, 2, 0), abc(RETURN, 0, 1, 0)])).unwrap_err();
+    assert!(matches!(error, LuaError::MalformedInstruction(0)));
+
+    // B == 0 with no multret before it, stack_top is still below A
+    let error = run(unvalidated(&[abc(NEWTABLE, 3, 0, 0), abc(CALL, 3, 0, 1), abc(RETURN, 0, 1, 0)])).unwrap_err();
+    assert!(matches!(error, LuaError::MalformedInstruction(1)));
+    let error = run(unvalidated(&[abc(NEWTABLE, 3, 0, 0), abc(TAILCALL, 3, 0, 0), abc(RETURN, 0, 1, 0)])).unwrap_err();
+    assert!(matches!(error, LuaError::MalformedInstruction(1)));
+    let error = run(unvalidated(&[abc(RETURN, 3, 0, 0)])).unwrap_err();
+    assert!(matches!(error, LuaError::MalformedInstruction(0)));
+}
+
+#[test]
+fn setlist_without_its_batch_word_errors() {
+    // SETLIST with C == 0 as the last word, so there's no batch number after it
+    let error = run(unvalidated(&[abc(NEWTABLE, 0, 0, 0), abc(SETLIST, 0, 0, 0)])).unwrap_err();
+    assert!(matches!(error, LuaError::MalformedInstruction(1)));
+}
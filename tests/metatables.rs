@@ -0,0 +1,440 @@
+mod common;
+
+use common::*;
+use std::rc::Rc;
+
+use lua51_vm::{lua_function, types::{LuaError, LuaResult, function::LuaFunctionArgs, userdata::LuaUserdata, value::LuaValue}, vm::VirtualMachine};
+
+// A native metamethod returning `result`
+fn returning(result: LuaValue) -> LuaValue {
+    LuaValue::Function(lua_function!(move |_: &LuaFunctionArgs| LuaResult::Ok(vec![result.clone().into()])))
+}
+
+fn set_global(vm: &VirtualMachine, name: &str, value: LuaValue) {
+    vm.environment.borrow_mut().table_set(s(name), value).unwrap();
+}
+
+#[test]
+fn tostring_metamethod_can_call_tostring() {
+    // local t = setmetatable({}, {__tostring = function() return tostring(5) end})
+    // print(t) return tostring(t)
+    let metamethod = function(&[s("tostring"), n(5.0)], &[
+        abx(GETGLOBAL, 1, 0),
+        abx(LOADK, 2, 1),
+        abc(CALL, 1, 2, 2),
+        abc(RETURN, 1, 2, 0)
+    ]).param_count(1).build().unwrap();
+    let chunk = function(&[s("__tostring"), s("setmetatable"), s("tostring"), s("print")], &[
+        abc(NEWTABLE, 0, 0, 0),
+        abc(NEWTABLE, 1, 0, 0),
+        abx(CLOSURE, 2, 0),
+        abc(SETTABLE, 1, k(0), 2),
+        abx(GETGLOBAL, 2, 1),
+        abc(MOVE, 3, 0, 0),
+        abc(MOVE, 4, 1, 0),
+        abc(CALL, 2, 3, 1),
+        abx(GETGLOBAL, 2, 3),
+        abc(MOVE, 3, 0, 0),
+        abc(CALL, 2, 2, 1),
+        abx(GETGLOBAL, 2, 2),
+        abc(MOVE, 3, 0, 0),
+        abc(CALL, 2, 2, 2),
+        abc(RETURN, 2, 2, 0)
+    ]).prototype(metamethod).vararg_flags(2).build().unwrap();
+
+    let mut vm = vm();
+    let out = capture(&mut vm);
+    assert_eq!(run_in(&mut vm, chunk).unwrap(), vec![s("5")]);
+    assert_eq!(output(&out), "5\n");
+}
+
+#[test]
+fn concat_rejects_a_tostring_that_returns_a_non_string() {
+    let mut metatable = LuaValue::new_table();
+    metatable.table_set(s("__tostring"), returning(n(1.0))).unwrap();
+    let (LuaValue::Table(t), LuaValue::Table(metatable)) = (LuaValue::new_table(), metatable) else { unreachable!() };
+    t.set_metatable(Some(metatable));
+    let t = LuaValue::from(t);
+
+    assert!(matches!(s("a").concat(t.clone()), Err(LuaError::AttemptedTableConcatenation)));
+    assert!(matches!(n(1.0).concat(t), Err(LuaError::AttemptedTableConcatenation)));
+}
+
+#[test]
+fn userdata_keeps_its_identity_through_a_table() {
+    let u = LuaUserdata::new(vec![1, 2, 3]);
+    let mut vm = vm();
+    set_global(&vm, "u", u.clone().into());
+    // local t = {} t.k = u return t.k
+    let chunk = chunk(&[s("u"), s("k")], &[
+        abc(NEWTABLE, 0, 0, 1),
+        abx(GETGLOBAL, 1, 0),
+        abc(SETTABLE, 0, k(1), 1),
+        abc(GETTABLE, 2, 0, k(1)),
+        abc(RETURN, 2, 2, 0)
+    ]);
+    let LuaValue::Userdata(back) = run_in(&mut vm, chunk).unwrap().remove(0) else { panic!("expected userdata") };
+    assert_eq!(back.id(), u.id());
+    assert!(Rc::ptr_eq(back.data(), u.data()));
+    assert_eq!(*back.borrow::<Vec<i32>>().unwrap(), vec![1, 2, 3]);
+}
+
+#[test]
+fn userdata_metamethods_apply_to_operators() {
+    let mut metatable = LuaValue::new_table();
+    metatable.table_set(s("__concat"), returning(s("cat"))).unwrap();
+    metatable.table_set(s("__eq"), returning(LuaValue::from(true))).unwrap();
+    metatable.table_set(s("__lt"), returning(LuaValue::from(true))).unwrap();
+    metatable.table_set(s("__le"), returning(LuaValue::from(false))).unwrap();
+    let LuaValue::Table(metatable) = metatable else { unreachable!() };
+    let (u, v) = (LuaUserdata::new(1), LuaUserdata::new(2));
+    u.set_metatable(Some(metatable.clone()));
+    v.set_metatable(Some(metatable));
+
+    let mut vm = vm();
+    set_global(&vm, "u", u.into());
+    set_global(&vm, "v", v.into());
+    // return u .. "x", u == v, u < v, u <= v
+    let chunk = chunk(&[s("u"), s("v"), s("x")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(LOADK, 1, 2),
+        abc(CONCAT, 0, 0, 1),
+        abx(GETGLOBAL, 4, 0),
+        abx(GETGLOBAL, 5, 1),
+        abc(EQ, 1, 4, 5),
+        asbx(JMP, 0, 1),
+        abc(LOADBOOL, 1, 0, 1),
+        abc(LOADBOOL, 1, 1, 0),
+        abc(LT, 1, 4, 5),
+        asbx(JMP, 0, 1),
+        abc(LOADBOOL, 2, 0, 1),
+        abc(LOADBOOL, 2, 1, 0),
+        abc(LE, 1, 4, 5),
+        asbx(JMP, 0, 1),
+        abc(LOADBOOL, 3, 0, 1),
+        abc(LOADBOOL, 3, 1, 0),
+        abc(RETURN, 0, 5, 0)
+    ]);
+    assert_eq!(run_in(&mut vm, chunk).unwrap(), vec![s("cat"), LuaValue::from(true), LuaValue::from(true), LuaValue::from(false)]);
+}
+
+#[test]
+fn userdata_without_concat_names_its_type() {
+    let mut vm = vm();
+    set_global(&vm, "u", LuaUserdata::new(()).into());
+    // return u .. "x"
+    let chunk = chunk(&[s("u"), s("x")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(LOADK, 1, 1),
+        abc(CONCAT, 0, 0, 1),
+        abc(RETURN, 0, 2, 0)
+    ]);
+    assert!(matches!(run_in(&mut vm, chunk), Err(LuaError::AttemptedUserdataConcatenation)));
+}
+
+#[test]
+fn metatable_field_protects_the_metatable() {
+    let mut vm = vm();
+    let (object, mut metatable) = (LuaValue::new_table(), LuaValue::new_table());
+    metatable.table_set(s("__metatable"), s("locked")).unwrap();
+    call(&mut vm, "setmetatable", vec![object.clone(), metatable]).unwrap();
+
+    assert_eq!(call(&mut vm, "getmetatable", vec![object.clone()]).unwrap(), vec![s("locked")]);
+    let result = call(&mut vm, "setmetatable", vec![object, LuaValue::new_table()]);
+    assert!(matches!(result, Err(LuaError::ProtectedMetatable)));
+}
+
+#[test]
+fn locked_metatable_from_lua() {
+    // setmetatable(t, {__metatable = "locked"})
+    // return getmetatable(t), pcall(setmetatable, t, {})
+    let chunk = chunk(&[s("setmetatable"), s("__metatable"), s("locked"), s("getmetatable"), s("pcall")], &[
+        abc(NEWTABLE, 0, 0, 0),
+        abx(GETGLOBAL, 1, 0),
+        abc(MOVE, 2, 0, 0),
+        abc(NEWTABLE, 3, 0, 1),
+        abc(SETTABLE, 3, k(1), k(2)),
+        abc(CALL, 1, 3, 1),
+        abx(GETGLOBAL, 1, 3),
+        abc(MOVE, 2, 0, 0),
+        abc(CALL, 1, 2, 2),
+        abx(GETGLOBAL, 2, 4),
+        abx(GETGLOBAL, 3, 0),
+        abc(MOVE, 4, 0, 0),
+        abc(NEWTABLE, 5, 0, 0),
+        abc(CALL, 2, 4, 0),
+        abc(RETURN, 1, 0, 0)
+    ]);
+    let results = run(chunk).unwrap();
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[..2], [s("locked"), LuaValue::from(false)]);
+}
+
+#[test]
+fn shared_eq_metamethod_compares_distinct_tables() {
+    // a and b share {__eq = function() return true end}, c has no metatable
+    // return a == b, a == c
+    let mut vm = vm();
+    let mut metatable = LuaValue::new_table();
+    metatable.table_set(s("__eq"), returning(LuaValue::from(true))).unwrap();
+    let (a, b) = (LuaValue::new_table(), LuaValue::new_table());
+    call(&mut vm, "setmetatable", vec![a.clone(), metatable.clone()]).unwrap();
+    call(&mut vm, "setmetatable", vec![b.clone(), metatable]).unwrap();
+    assert_ne!(a, b);
+    set_global(&vm, "a", a);
+    set_global(&vm, "b", b);
+    set_global(&vm, "c", LuaValue::new_table());
+
+    let chunk = chunk(&[s("a"), s("b"), s("c")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(GETGLOBAL, 1, 1),
+        abx(GETGLOBAL, 2, 2),
+        abc(EQ, 1, 0, 1),
+        asbx(JMP, 0, 1),
+        abc(LOADBOOL, 3, 0, 1),
+        abc(LOADBOOL, 3, 1, 0),
+        abc(EQ, 1, 0, 2),
+        asbx(JMP, 0, 1),
+        abc(LOADBOOL, 4, 0, 1),
+        abc(LOADBOOL, 4, 1, 0),
+        abc(RETURN, 3, 3, 0)
+    ]);
+    assert_eq!(run_in(&mut vm, chunk).unwrap(), vec![LuaValue::from(true), LuaValue::from(false)]);
+}
+
+// A table {v = v} for the comparison tests
+fn version(vm: &mut VirtualMachine, v: f64, metatable: &LuaValue) -> LuaValue {
+    let mut t = LuaValue::new_table();
+    t.table_set(s("v"), n(v)).unwrap();
+    call(vm, "setmetatable", vec![t.clone(), metatable.clone()]).unwrap();
+    t
+}
+
+#[test]
+fn lt_metamethod_orders_tables_and_derives_le() {
+    // mt.__lt = function(a, b) return a.v < b.v end
+    // return a < b, b < a, a <= b, b <= a
+    let lt = function(&[s("v")], &[
+        abc(GETTABLE, 2, 0, k(0)),
+        abc(GETTABLE, 3, 1, k(0)),
+        abc(LT, 1, 2, 3),
+        asbx(JMP, 0, 1),
+        abc(LOADBOOL, 2, 0, 1),
+        abc(LOADBOOL, 2, 1, 0),
+        abc(RETURN, 2, 2, 0)
+    ]).param_count(2).build().unwrap();
+    let chunk = function(&[s("mt"), s("__lt"), s("a"), s("b")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(CLOSURE, 1, 0),
+        abc(SETTABLE, 0, k(1), 1),
+        abx(GETGLOBAL, 0, 2),
+        abx(GETGLOBAL, 1, 3),
+        abc(LT, 1, 0, 1),
+        asbx(JMP, 0, 1),
+        abc(LOADBOOL, 2, 0, 1),
+        abc(LOADBOOL, 2, 1, 0),
+        abc(LT, 1, 1, 0),
+        asbx(JMP, 0, 1),
+        abc(LOADBOOL, 3, 0, 1),
+        abc(LOADBOOL, 3, 1, 0),
+        abc(LE, 1, 0, 1),
+        asbx(JMP, 0, 1),
+        abc(LOADBOOL, 4, 0, 1),
+        abc(LOADBOOL, 4, 1, 0),
+        abc(LE, 1, 1, 0),
+        asbx(JMP, 0, 1),
+        abc(LOADBOOL, 5, 0, 1),
+        abc(LOADBOOL, 5, 1, 0),
+        abc(RETURN, 2, 5, 0)
+    ]).prototype(lt).vararg_flags(2).build().unwrap();
+
+    let mut vm = vm();
+    let metatable = LuaValue::new_table();
+    let (a, b) = (version(&mut vm, 1.0, &metatable), version(&mut vm, 2.0, &metatable));
+    set_global(&vm, "mt", metatable);
+    set_global(&vm, "a", a);
+    set_global(&vm, "b", b);
+    let (yes, no) = (LuaValue::from(true), LuaValue::from(false));
+    assert_eq!(run_in(&mut vm, chunk).unwrap(), vec![yes.clone(), no.clone(), yes, no]);
+}
+
+#[test]
+fn concat_metamethod_applies_on_either_side() {
+    // mt.__concat replaces a table operand with its name field
+    // return t .. "!", "<" .. t
+    let concat = lua_function!(|args: &LuaFunctionArgs| {
+        let part = |v: LuaValue| match v {
+            LuaValue::Table(_) => v.table_get(&s("name")),
+            v => LuaResult::Ok(v)
+        };
+        let (lhs, rhs) = (part(args[0].borrow().clone())?, part(args[1].borrow().clone())?);
+        LuaResult::Ok(vec![lhs.concat(rhs)?.into()])
+    });
+    let mut metatable = LuaValue::new_table();
+    metatable.table_set(s("__concat"), LuaValue::Function(concat)).unwrap();
+    let mut t = LuaValue::new_table();
+    t.table_set(s("name"), s("point")).unwrap();
+    let mut vm = vm();
+    call(&mut vm, "setmetatable", vec![t.clone(), metatable]).unwrap();
+    set_global(&vm, "t", t);
+
+    let chunk = chunk(&[s("t"), s("!"), s("<")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(LOADK, 1, 1),
+        abc(CONCAT, 2, 0, 1),
+        abx(LOADK, 3, 2),
+        abc(MOVE, 4, 0, 0),
+        abc(CONCAT, 3, 3, 4),
+        abc(RETURN, 2, 3, 0)
+    ]);
+    assert_eq!(run_in(&mut vm, chunk).unwrap(), vec![s("point!"), s("<point")]);
+}
+
+#[test]
+fn call_metamethod_receives_the_table_first() {
+    // mt.__call returns its arguments
+    // return t(1, 2)
+    let echo = lua_function!(|args: &LuaFunctionArgs| LuaResult::Ok(args.clone()));
+    let mut metatable = LuaValue::new_table();
+    metatable.table_set(s("__call"), LuaValue::Function(echo)).unwrap();
+    let t = LuaValue::new_table();
+    let mut vm = vm();
+    call(&mut vm, "setmetatable", vec![t.clone(), metatable]).unwrap();
+    set_global(&vm, "t", t.clone());
+
+    let chunk = chunk(&[s("t"), n(1.0), n(2.0)], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(LOADK, 1, 1),
+        abx(LOADK, 2, 2),
+        abc(CALL, 0, 3, 0),
+        abc(RETURN, 0, 0, 0)
+    ]);
+    assert_eq!(run_in(&mut vm, chunk).unwrap(), vec![t, n(1.0), n(2.0)]);
+}
+
+// A table with a metatable holding `fields`
+fn with_metatable(vm: &mut VirtualMachine, fields: Vec<(&str, LuaValue)>) -> LuaValue {
+    let (t, mut metatable) = (LuaValue::new_table(), LuaValue::new_table());
+    for (name, value) in fields {
+        metatable.table_set(s(name), value).unwrap();
+    }
+    call(vm, "setmetatable", vec![t.clone(), metatable]).unwrap();
+    t
+}
+
+#[test]
+fn index_and_newindex_functions_are_called() {
+    // __index returns key .. "!", __newindex stores value * 2 in log[key]
+    // t.y = 5 return t.x, log.y, t.y
+    let index = lua_function!(|args: &LuaFunctionArgs| LuaResult::Ok(vec![args[1].borrow().clone().concat(s("!"))?.into()]));
+    let mut vm = vm();
+    let log = LuaValue::new_table();
+    let target = log.clone();
+    let newindex = lua_function!(move |args: &LuaFunctionArgs| {
+        let doubled = (args[2].borrow().clone() * n(2.0))?;
+        target.clone().table_set(args[1].borrow().clone(), doubled)?;
+        LuaResult::Ok(vec![])
+    });
+    let t = with_metatable(&mut vm, vec![("__index", LuaValue::Function(index)), ("__newindex", LuaValue::Function(newindex))]);
+    set_global(&vm, "t", t.clone());
+    set_global(&vm, "log", log);
+
+    let chunk = chunk(&[s("t"), s("y"), n(5.0), s("x"), s("log")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(SETTABLE, 0, k(1), k(2)),
+        abc(GETTABLE, 1, 0, k(3)),
+        abx(GETGLOBAL, 2, 4),
+        abc(GETTABLE, 2, 2, k(1)),
+        abc(GETTABLE, 3, 0, k(1)),
+        abc(RETURN, 1, 4, 0)
+    ]);
+    assert_eq!(run_in(&mut vm, chunk).unwrap(), vec![s("x!"), n(10.0), s("y!")]);
+    assert_eq!(t.table_iter().count(), 0);
+}
+
+#[test]
+fn index_and_newindex_tables_are_followed() {
+    // base = {x = 1}, middle's __index is base and t's is middle
+    // t.y = 2 goes to store through __newindex, return t.x, store.y, t.y
+    let mut vm = vm();
+    let mut base = LuaValue::new_table();
+    base.table_set(s("x"), n(1.0)).unwrap();
+    let middle = with_metatable(&mut vm, vec![("__index", base)]);
+    let store = LuaValue::new_table();
+    let t = with_metatable(&mut vm, vec![("__index", middle), ("__newindex", store.clone())]);
+    set_global(&vm, "t", t);
+    set_global(&vm, "store", store);
+
+    let chunk = chunk(&[s("t"), s("y"), n(2.0), s("x"), s("store")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(SETTABLE, 0, k(1), k(2)),
+        abc(GETTABLE, 1, 0, k(3)),
+        abx(GETGLOBAL, 2, 4),
+        abc(GETTABLE, 2, 2, k(1)),
+        abc(GETTABLE, 3, 0, k(1)),
+        abc(RETURN, 1, 4, 0)
+    ]);
+    assert_eq!(run_in(&mut vm, chunk).unwrap(), vec![n(1.0), n(2.0), LuaValue::Nil]);
+}
+
+#[test]
+fn unm_metamethod_negates_a_vector() {
+    // mt.__unm = function(v) return {x = -v.x} end
+    // return (-t).x, -5
+    let unm = function(&[s("x")], &[
+        abc(GETTABLE, 1, 0, k(0)),
+        abc(UNM, 1, 1, 0),
+        abc(NEWTABLE, 2, 0, 1),
+        abc(SETTABLE, 2, k(0), 1),
+        abc(RETURN, 2, 2, 0)
+    ]).param_count(1).build().unwrap();
+    let chunk = function(&[s("mt"), s("__unm"), s("t"), s("x"), n(5.0)], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(CLOSURE, 1, 0),
+        abc(SETTABLE, 0, k(1), 1),
+        abx(GETGLOBAL, 0, 2),
+        abc(UNM, 1, 0, 0),
+        abc(GETTABLE, 1, 1, k(3)),
+        abx(LOADK, 2, 4),
+        abc(UNM, 2, 2, 0),
+        abc(RETURN, 1, 3, 0)
+    ]).prototype(unm).vararg_flags(2).build().unwrap();
+
+    let mut vm = vm();
+    let metatable = LuaValue::new_table();
+    let mut t = LuaValue::new_table();
+    t.table_set(s("x"), n(3.0)).unwrap();
+    call(&mut vm, "setmetatable", vec![t.clone(), metatable.clone()]).unwrap();
+    set_global(&vm, "mt", metatable);
+    set_global(&vm, "t", t);
+    assert_eq!(run_in(&mut vm, chunk).unwrap(), vec![n(-3.0), n(-5.0)]);
+}
+
+#[test]
+fn cyclic_index_chains_are_a_clean_error() {
+    // a and b each use the other as __index and __newindex
+    // return a.missing / a.missing = 1
+    let mut vm = vm();
+    let (a, b) = (LuaValue::new_table(), LuaValue::new_table());
+    for (t, other) in [(&a, &b), (&b, &a)] {
+        let mut metatable = LuaValue::new_table();
+        metatable.table_set(s("__index"), other.clone()).unwrap();
+        metatable.table_set(s("__newindex"), other.clone()).unwrap();
+        call(&mut vm, "setmetatable", vec![t.clone(), metatable]).unwrap();
+    }
+    set_global(&vm, "a", a);
+
+    let read = chunk(&[s("a"), s("missing")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(GETTABLE, 0, 0, k(1)),
+        abc(RETURN, 0, 2, 0)
+    ]);
+    assert!(matches!(run_in(&mut vm, read), Err(LuaError::IndexChainTooLong)));
+
+    let write = chunk(&[s("a"), s("missing"), n(1.0)], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(SETTABLE, 0, k(1), k(2)),
+        abc(RETURN, 0, 1, 0)
+    ]);
+    assert!(matches!(run_in(&mut vm, write), Err(LuaError::IndexChainTooLong)));
+}
@@ -0,0 +1,353 @@
+mod common;
+
+use common::*;
+use lua51_vm::{types::{LuaError, value::LuaValue}, vm::PrintConfig};
+
+#[test]
+fn preload_loader_can_require() {
+    // package.preload.b = function() return "b" end
+    // package.preload.a = function() return require("b") .. "a" end
+    // return require("a"), package.loaded.b
+    let b = function(&[s("b")], &[
+        abx(LOADK, 1, 0),
+        abc(RETURN, 1, 2, 0)
+    ]).param_count(1).build().unwrap();
+    let a = function(&[s("require"), s("b"), s("a")], &[
+        abx(GETGLOBAL, 1, 0),
+        abx(LOADK, 2, 1),
+        abc(CALL, 1, 2, 2),
+        abx(LOADK, 2, 2),
+        abc(CONCAT, 1, 1, 2),
+        abc(RETURN, 1, 2, 0)
+    ]).param_count(1).build().unwrap();
+    let chunk = function(&[s("package"), s("preload"), s("a"), s("b"), s("require"), s("loaded")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(GETTABLE, 1, 0, k(1)),
+        abx(CLOSURE, 2, 0),
+        abc(SETTABLE, 1, k(3), 2),
+        abx(CLOSURE, 2, 1),
+        abc(SETTABLE, 1, k(2), 2),
+        abx(GETGLOBAL, 2, 4),
+        abx(LOADK, 3, 2),
+        abc(CALL, 2, 2, 2),
+        abc(GETTABLE, 3, 0, k(5)),
+        abc(GETTABLE, 3, 3, k(3)),
+        abc(RETURN, 2, 3, 0)
+    ]).prototype(b).prototype(a).vararg_flags(2).build().unwrap();
+
+    assert_eq!(run(chunk).unwrap(), vec![s("ba"), s("b")]);
+}
+
+#[test]
+fn preload_loader_runs_once() {
+    // loads = 0
+    // package.preload.m = function() loads = loads + 1 return {} end
+    // local x, y = require("m"), require("m")
+    // return loads, x == y
+    let loader = function(&[s("loads"), n(1.0)], &[
+        abx(GETGLOBAL, 1, 0),
+        abc(ADD, 1, 1, k(1)),
+        abx(SETGLOBAL, 1, 0),
+        abc(NEWTABLE, 1, 0, 0),
+        abc(RETURN, 1, 2, 0)
+    ]).param_count(1).build().unwrap();
+    let chunk = function(&[s("package"), s("preload"), s("m"), s("loads"), n(0.0), s("require")], &[
+        abx(LOADK, 0, 4),
+        abx(SETGLOBAL, 0, 3),
+        abx(GETGLOBAL, 0, 0),
+        abc(GETTABLE, 0, 0, k(1)),
+        abx(CLOSURE, 1, 0),
+        abc(SETTABLE, 0, k(2), 1),
+        abx(GETGLOBAL, 0, 5),
+        abx(LOADK, 1, 2),
+        abc(CALL, 0, 2, 2),
+        abx(GETGLOBAL, 1, 5),
+        abx(LOADK, 2, 2),
+        abc(CALL, 1, 2, 2),
+        abx(GETGLOBAL, 2, 3),
+        abc(EQ, 1, 0, 1),
+        asbx(JMP, 0, 1),
+        abc(LOADBOOL, 3, 0, 1),
+        abc(LOADBOOL, 3, 1, 0),
+        abc(RETURN, 2, 3, 0)
+    ]).prototype(loader).vararg_flags(2).build().unwrap();
+
+    assert_eq!(run(chunk).unwrap(), vec![n(1.0), LuaValue::from(true)]);
+}
+
+#[test]
+fn require_keeps_what_the_loader_put_in_package_loaded() {
+    // package.preload.m = function(name) package.loaded[name] = "set" end
+    // return require("m"), package.loaded.m
+    let loader = function(&[s("package"), s("loaded"), s("set")], &[
+        abx(GETGLOBAL, 1, 0),
+        abc(GETTABLE, 1, 1, k(1)),
+        abc(SETTABLE, 1, 0, k(2)),
+        abc(RETURN, 0, 1, 0)
+    ]).param_count(1).build().unwrap();
+    let chunk = function(&[s("package"), s("preload"), s("m"), s("require"), s("loaded")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(GETTABLE, 1, 0, k(1)),
+        abx(CLOSURE, 2, 0),
+        abc(SETTABLE, 1, k(2), 2),
+        abx(GETGLOBAL, 2, 3),
+        abx(LOADK, 3, 2),
+        abc(CALL, 2, 2, 2),
+        abc(GETTABLE, 3, 0, k(4)),
+        abc(GETTABLE, 3, 3, k(2)),
+        abc(RETURN, 2, 3, 0)
+    ]).prototype(loader).vararg_flags(2).build().unwrap();
+
+    assert_eq!(run(chunk).unwrap(), vec![s("set"), s("set")]);
+}
+
+#[test]
+fn floor_and_ceil_index_integer_slots() {
+    let mut vm = vm();
+
+    // t[math.floor(2.9)] is t[2]
+    let mut t = LuaValue::new_table();
+    t.table_set(n(2.0), s("two")).unwrap();
+    let key = call(&mut vm, "math.floor", vec![n(2.9)]).unwrap().remove(0);
+    assert_eq!(t.table_get(&key).unwrap(), s("two"));
+
+    // math.ceil(-0.5) is 0, not -0
+    let zero = call(&mut vm, "math.ceil", vec![n(-0.5)]).unwrap().remove(0);
+    let LuaValue::Number(z) = zero else { panic!("number expected") };
+    assert!(z.0 == 0.0 && z.0.is_sign_positive());
+    assert_eq!(call(&mut vm, "tostring", vec![zero]).unwrap(), vec![s("0")]);
+}
+
+#[test]
+fn math_arguments_are_checked_like_arithmetic() {
+    let mut vm = vm();
+    assert_eq!(call(&mut vm, "math.floor", vec![s("2.5")]).unwrap(), vec![n(2.0)]);
+    assert!(matches!(call(&mut vm, "math.floor", vec![s("x")]), Err(LuaError::BadArgument(1, "floor", _))));
+    assert!(matches!(call(&mut vm, "math.fmod", vec![n(1.0)]), Err(LuaError::BadArgument(2, "fmod", _))));
+}
+
+#[test]
+fn io_write_adds_no_separators() {
+    let mut vm = vm();
+    let out = capture(&mut vm);
+    call(&mut vm, "io.write", vec![s("a"), n(1.0), s("b"), n(2.5)]).unwrap();
+    call(&mut vm, "io.write", vec![s("c")]).unwrap();
+    assert_eq!(output(&out), "a1b2.5c");
+}
+
+#[test]
+fn print_writes_to_the_output_sink() {
+    // print("a", 1, nil, true)
+    let chunk = chunk(&[s("print"), s("a"), n(1.0)], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(LOADK, 1, 1),
+        abx(LOADK, 2, 2),
+        abc(LOADNIL, 3, 3, 0),
+        abc(LOADBOOL, 4, 1, 0),
+        abc(CALL, 0, 5, 1),
+        abc(RETURN, 0, 1, 0)
+    ]);
+    let mut vm = vm();
+    let out = capture(&mut vm);
+    run_in(&mut vm, chunk).unwrap();
+    assert_eq!(*out.borrow(), b"a\t1\tnil\ttrue\n");
+}
+
+#[test]
+fn bit_operations_wrap_to_32_bits() {
+    let mut vm = vm();
+    let mut bit = |name: &str, args: &[f64]| call(&mut vm, &format!("bit.{name}"), args.iter().map(|a| n(*a)).collect()).unwrap();
+
+    assert_eq!(bit("lshift", &[1.0, 31.0]), vec![n(-2147483648.0)]);
+    assert_eq!(bit("lshift", &[3.0, 31.0]), vec![n(-2147483648.0)]);
+    assert_eq!(bit("lshift", &[1.0, 32.0]), vec![n(1.0)]);
+    assert_eq!(bit("arshift", &[-256.0, 4.0]), vec![n(-16.0)]);
+    assert_eq!(bit("rshift", &[-256.0, 4.0]), vec![n(268435440.0)]);
+    assert_eq!(bit("tobit", &[4294967297.0]), vec![n(1.0)]);
+    assert_eq!(bit("bnot", &[0.0]), vec![n(-1.0)]);
+    assert_eq!(bit("band", &[-1.0, 255.0, 15.0]), vec![n(15.0)]);
+}
+
+#[test]
+fn large_integral_numbers_format_like_14g() {
+    let mut vm = vm();
+    let big = 2f64.powi(53) + 1.0;
+    assert_eq!(call(&mut vm, "tostring", vec![n(big)]).unwrap(), vec![s("9.007199254741e+15")]);
+    assert_eq!(call(&mut vm, "tostring", vec![n(1e18)]).unwrap(), vec![s("1e+18")]);
+}
+
+#[test]
+fn collectgarbage_returns_numbers() {
+    let mut vm = vm();
+    let count = call(&mut vm, "collectgarbage", vec![s("count")]).unwrap();
+    assert!(matches!(count[0], LuaValue::Number(_)));
+    assert_eq!(call(&mut vm, "collectgarbage", vec![]).unwrap(), vec![n(0.0)]);
+    assert_eq!(call(&mut vm, "collectgarbage", vec![s("step")]).unwrap()[0], n(0.0));
+}
+
+#[test]
+fn modf_fmod_and_logarithms() {
+    let mut vm = vm();
+    let parts = call(&mut vm, "math.modf", vec![n(3.7)]).unwrap();
+    let [LuaValue::Number(whole), LuaValue::Number(fraction)] = &parts[..] else { panic!("modf returns two numbers") };
+    assert_eq!(whole.0, 3.0);
+    assert!((fraction.0 - 0.7).abs() < 1e-12);
+
+    assert_eq!(call(&mut vm, "math.fmod", vec![n(-5.0), n(3.0)]).unwrap(), vec![n(-2.0)]);
+    assert_eq!(call(&mut vm, "math.log10", vec![n(1000.0)]).unwrap(), vec![n(3.0)]);
+    assert_eq!(call(&mut vm, "math.log", vec![n(1.0)]).unwrap(), vec![n(0.0)]);
+    assert_eq!(call(&mut vm, "math.exp", vec![n(0.0)]).unwrap(), vec![n(1.0)]);
+}
+
+#[test]
+fn integral_results_print_and_index_like_integers() {
+    // local t = {} t[3] = "three" local i = 6 / 2 return tostring(i), tostring(7 / 2), t[i]
+    let chunk = chunk(&[n(3.0), s("three"), n(6.0), n(2.0), s("tostring"), n(7.0)], &[
+        abc(NEWTABLE, 0, 0, 0),
+        abc(SETTABLE, 0, k(0), k(1)),
+        abc(DIV, 1, k(2), k(3)),
+        abx(GETGLOBAL, 2, 4),
+        abc(MOVE, 3, 1, 0),
+        abc(CALL, 2, 2, 2),
+        abx(GETGLOBAL, 3, 4),
+        abc(DIV, 4, k(5), k(3)),
+        abc(CALL, 3, 2, 2),
+        abc(GETTABLE, 4, 0, 1),
+        abc(RETURN, 2, 4, 0)
+    ]);
+    assert_eq!(run(chunk).unwrap(), vec![s("3"), s("3.5"), s("three")]);
+}
+
+#[test]
+fn randomseed_makes_random_reproducible() {
+    let mut vm = vm();
+    let mut sequence = || {
+        call(&mut vm, "math.randomseed", vec![n(42.0)]).unwrap();
+        (0..20).map(|_| call(&mut vm, "math.random", vec![n(1.0), n(6.0)]).unwrap().remove(0)).collect::<Vec<_>>()
+    };
+    let (first, second) = (sequence(), sequence());
+    assert_eq!(first, second);
+    assert!(first.iter().all(|v| matches!(v, LuaValue::Number(x) if (1.0..=6.0).contains(&x.0) && x.0.fract() == 0.0)));
+
+    assert!(matches!(call(&mut vm, "math.random", vec![n(5.0), n(1.0)]), Err(LuaError::EmptyInterval)));
+}
+
+#[test]
+fn globals_and_g_share_storage() {
+    // _G.y = 5 z = 6 return y, _G.z
+    let chunk = chunk(&[s("_G"), s("y"), n(5.0), s("z"), n(6.0)], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(SETTABLE, 0, k(1), k(2)),
+        abx(LOADK, 1, 4),
+        abx(SETGLOBAL, 1, 3),
+        abx(GETGLOBAL, 1, 1),
+        abc(GETTABLE, 2, 0, k(3)),
+        abc(RETURN, 1, 3, 0)
+    ]);
+    assert_eq!(run(chunk).unwrap(), vec![n(5.0), n(6.0)]);
+}
+
+#[test]
+fn bad_arguments_are_reported_like_lua() {
+    let mut vm = vm();
+    let mut message = |path: &str, args: Vec<LuaValue>| call(&mut vm, path, args).unwrap_err().to_string();
+    assert_eq!(message("tostring", vec![]), "bad argument #1 to 'tostring' (value expected)");
+    assert_eq!(message("error", vec![s("x"), s("abc")]), "bad argument #2 to 'error' (number expected, got string)");
+    assert_eq!(message("string.format", vec![s("%d"), s("x")]), "bad argument #2 to 'format' (number expected, got string)");
+    assert_eq!(message("math.floor", vec![]), "bad argument #1 to 'floor' (number expected, got no value)");
+}
+
+#[test]
+fn min_and_max_take_any_number_of_arguments() {
+    let mut vm = vm();
+    let numbers = |xs: &[f64]| xs.iter().map(|x| n(*x)).collect::<Vec<_>>();
+    assert_eq!(call(&mut vm, "math.max", numbers(&[3.0, 1.0, 4.0, 1.0, 5.0])).unwrap(), vec![n(5.0)]);
+    assert_eq!(call(&mut vm, "math.min", numbers(&[3.0, 1.0, 4.0, 1.0, 5.0])).unwrap(), vec![n(1.0)]);
+    assert_eq!(call(&mut vm, "math.max", numbers(&[7.0])).unwrap(), vec![n(7.0)]);
+
+    let error = call(&mut vm, "math.max", vec![n(1.0), s("x")]).unwrap_err();
+    assert_eq!(error.to_string(), "bad argument #2 to 'max' (number expected, got string)");
+
+    // NaN first is kept, NaN later is skipped
+    let LuaValue::Number(first) = call(&mut vm, "math.max", numbers(&[f64::NAN, 1.0])).unwrap()[0] else { panic!() };
+    assert!(first.0.is_nan());
+    assert_eq!(call(&mut vm, "math.max", numbers(&[1.0, f64::NAN])).unwrap(), vec![n(1.0)]);
+}
+
+#[test]
+fn type_names_match_the_type_function() {
+    let mut vm = vm();
+    let print = global(&vm, "print");
+    let cases = [
+        (LuaValue::Nil, "nil"),
+        (n(1.5), "number"),
+        (s("x"), "string"),
+        (LuaValue::from(true), "boolean"),
+        (LuaValue::new_table(), "table"),
+        (print, "function")
+    ];
+    for (value, name) in cases {
+        assert_eq!(value.type_name(), name);
+        assert_eq!(call(&mut vm, "type", vec![value]).unwrap(), vec![s(name)]);
+    }
+}
+
+#[test]
+fn print_uses_the_configured_separator_and_terminator() {
+    let mut vm = vm();
+    let out = capture(&mut vm);
+    call(&mut vm, "print", vec![]).unwrap();
+    call(&mut vm, "print", vec![s("a"), n(1.0)]).unwrap();
+    vm.set_print_config(PrintConfig { separator: ",".to_owned(), terminator: ";".to_owned() });
+    call(&mut vm, "print", vec![s("a"), n(1.0), LuaValue::Nil]).unwrap();
+    vm.set_print_config(PrintConfig { separator: ",".to_owned(), ..Default::default() });
+    call(&mut vm, "print", vec![s("b")]).unwrap();
+    assert_eq!(output(&out), "\na\t1\na,1,nil;b\n");
+}
+
+#[test]
+fn reset_environment_drops_user_globals_only() {
+    // answer = 42
+    let define = chunk(&[s("answer"), n(42.0)], &[
+        abx(LOADK, 0, 1),
+        abx(SETGLOBAL, 0, 0),
+        abc(RETURN, 0, 1, 0)
+    ]);
+    // return answer, print
+    let read = || chunk(&[s("answer"), s("print")], &[
+        abx(GETGLOBAL, 0, 0),
+        abx(GETGLOBAL, 1, 1),
+        abc(RETURN, 0, 3, 0)
+    ]);
+    let mut vm = vm();
+    run_in(&mut vm, define).unwrap();
+    assert_eq!(run_in(&mut vm, read()).unwrap()[0], n(42.0));
+    assert!(vm.global_names().contains(&"answer".to_owned()));
+
+    vm.reset_environment().unwrap();
+    let names = vm.global_names();
+    assert!(!names.contains(&"answer".to_owned()));
+    assert!(names.contains(&"print".to_owned()) && names.contains(&"string".to_owned()));
+    let results = run_in(&mut vm, read()).unwrap();
+    assert_eq!(results[0], LuaValue::Nil);
+    assert!(matches!(results[1], LuaValue::Function(_)));
+}
+
+#[test]
+fn os_getenv_reads_a_set_variable() {
+    // return os.getenv("LUA51_VM_GETENV_TEST"), os.getenv("LUA51_VM_GETENV_UNSET")
+    // SAFETY: no other test reads or writes this variable
+    unsafe { std::env::set_var("LUA51_VM_GETENV_TEST", "set"); }
+    let chunk = chunk(&[s("os"), s("getenv"), s("LUA51_VM_GETENV_TEST"), s("LUA51_VM_GETENV_UNSET")], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(GETTABLE, 0, 0, k(1)),
+        abx(LOADK, 1, 2),
+        abc(CALL, 0, 2, 2),
+        abx(GETGLOBAL, 1, 0),
+        abc(GETTABLE, 1, 1, k(1)),
+        abx(LOADK, 2, 3),
+        abc(CALL, 1, 2, 2),
+        abc(RETURN, 0, 3, 0)
+    ]);
+    assert_eq!(run(chunk).unwrap(), vec![s("set"), LuaValue::Nil]);
+}
@@ -0,0 +1,129 @@
+mod common;
+
+use common::*;
+use lua51_vm::types::value::LuaValue;
+
+#[test]
+fn gmatch_resumes_across_tforloop() {
+    // local s = "" for w in string.gmatch("a,b,c", "[^,]+") do s = s .. w .. "|" end return s
+    let chunk = chunk(&[s(""), s("string"), s("gmatch"), s("a,b,c"), s("[^,]+"), s("|")], &[
+        abx(LOADK, 0, 0),
+        abx(GETGLOBAL, 1, 1),
+        abc(GETTABLE, 1, 1, k(2)),
+        abx(LOADK, 2, 3),
+        abx(LOADK, 3, 4),
+        abc(CALL, 1, 3, 4),
+        asbx(JMP, 0, 4),
+        abc(MOVE, 5, 0, 0),
+        abc(MOVE, 6, 4, 0),
+        abx(LOADK, 7, 5),
+        abc(CONCAT, 0, 5, 7),
+        abc(TFORLOOP, 1, 0, 1),
+        asbx(JMP, 0, -6),
+        abc(RETURN, 0, 2, 0)
+    ]);
+    assert_eq!(run(chunk).unwrap(), vec![s("a|b|c|")]);
+}
+
+#[test]
+fn gmatch_steps_over_empty_matches() {
+    let mut vm = vm();
+    let iterator = call(&mut vm, "string.gmatch", vec![s("ab1cd"), s("%a*")]).unwrap().remove(0);
+    let mut matches = vec![];
+    loop {
+        let result = vm.call_value(&iterator, vec![]).unwrap();
+        match result.first() {
+            Some(LuaValue::String(m)) => matches.push(m.to_string()),
+            _ => break
+        }
+    }
+    assert_eq!(matches, ["ab", "", "cd", ""]);
+}
+
+#[test]
+fn string_methods_resolve_through_the_string_metatable() {
+    // local str = "abc" return str:len(), str:upper()
+    let chunk = chunk(&[s("abc"), s("len"), s("upper")], &[
+        abx(LOADK, 0, 0),
+        abc(SELF, 1, 0, k(1)),
+        abc(CALL, 1, 2, 2),
+        abc(SELF, 2, 0, k(2)),
+        abc(CALL, 2, 2, 2),
+        abc(RETURN, 1, 3, 0)
+    ]);
+    assert_eq!(run(chunk).unwrap(), vec![n(3.0), s("ABC")]);
+}
+
+#[test]
+fn reverse_and_plain_find() {
+    let mut vm = vm();
+    assert_eq!(call(&mut vm, "string.reverse", vec![s("abc")]).unwrap(), vec![s("cba")]);
+    assert_eq!(call(&mut vm, "string.find", vec![s("a.b"), s("."), n(1.0), LuaValue::from(true)]).unwrap(), vec![n(2.0), n(2.0)]);
+    // Without plain the dot matches anything
+    assert_eq!(call(&mut vm, "string.find", vec![s("a.b"), s(".")]).unwrap(), vec![n(1.0), n(1.0)]);
+    assert_eq!(call(&mut vm, "string.find", vec![s("a.b"), s("%"), n(1.0), LuaValue::from(true)]).unwrap(), vec![LuaValue::Nil]);
+}
+
+#[test]
+fn gmatch_stops_early_on_a_large_string() {
+    let mut vm = vm();
+    let text = (0..200_000).map(|i| format!("w{i} ")).collect::<String>();
+    let iterator = call(&mut vm, "string.gmatch", vec![s(&text), s("%w+")]).unwrap().remove(0);
+    for expected in ["w0", "w1", "w2"] {
+        assert_eq!(vm.call_value(&iterator, vec![]).unwrap(), vec![s(expected)]);
+    }
+}
+
+#[test]
+fn find_returns_captures_after_the_indices() {
+    let mut vm = vm();
+    let results = call(&mut vm, "string.find", vec![s("key=val"), s("(%w+)=(%w+)")]).unwrap();
+    assert_eq!(results, vec![n(1.0), n(7.0), s("key"), s("val")]);
+}
+
+#[test]
+fn len_operator_and_string_len_count_bytes() {
+    // local s = "héllo" return #s, string.len(s)
+    let chunk = chunk(&[s("héllo"), s("string"), s("len")], &[
+        abx(LOADK, 0, 0),
+        abc(LEN, 1, 0, 0),
+        abx(GETGLOBAL, 2, 1),
+        abc(GETTABLE, 2, 2, k(2)),
+        abc(MOVE, 3, 0, 0),
+        abc(CALL, 2, 2, 2),
+        abc(RETURN, 1, 3, 0)
+    ]);
+    assert_eq!(run(chunk).unwrap(), vec![n(6.0), n(6.0)]);
+}
+
+#[test]
+fn gmatch_iterator_stays_exhausted() {
+    let mut vm = vm();
+    let iterator = call(&mut vm, "string.gmatch", vec![s("one two three"), s("%a+")]).unwrap().remove(0);
+    let mut count = 0;
+    while !vm.call_value(&iterator, vec![]).unwrap().is_empty() {
+        count += 1;
+    }
+    assert_eq!(count, 3);
+    assert!(vm.call_value(&iterator, vec![]).unwrap().is_empty());
+}
+
+#[test]
+fn byte_accepts_negative_and_ranged_indices() {
+    let mut vm = vm();
+    assert_eq!(call(&mut vm, "string.byte", vec![s("ABC"), n(-1.0)]).unwrap(), vec![n(67.0)]);
+    assert_eq!(call(&mut vm, "string.byte", vec![s("ABC"), n(1.0), n(3.0)]).unwrap(), vec![n(65.0), n(66.0), n(67.0)]);
+    assert_eq!(call(&mut vm, "string.byte", vec![s("ABC")]).unwrap(), vec![n(65.0)]);
+    assert_eq!(call(&mut vm, "string.byte", vec![s("ABC"), n(2.0), n(10.0)]).unwrap(), vec![n(66.0), n(67.0)]);
+    assert_eq!(call(&mut vm, "string.byte", vec![s("ABC"), n(5.0)]).unwrap(), vec![]);
+}
+
+#[test]
+fn format_q_escapes_like_reference_lua() {
+    let mut vm = vm();
+    let raw = LuaValue::String(b"say \"hi\"\\\nnul\0cr\r\xff".as_slice().into());
+    let quoted = call(&mut vm, "string.format", vec![s("%q"), raw]).unwrap().remove(0);
+    let LuaValue::String(quoted) = quoted else { panic!("format returns a string") };
+    assert_eq!(quoted.as_bytes(), b"\"say \\\"hi\\\"\\\\\\\nnul\\000cr\\r\xff\"");
+    assert_eq!(call(&mut vm, "string.format", vec![s("x=%q"), s("plain")]).unwrap(), vec![s("x=\"plain\"")]);
+}
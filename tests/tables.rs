@@ -0,0 +1,326 @@
+mod common;
+
+use common::*;
+use lua51_vm::{types::{LuaError, value::LuaValue}, vm::VirtualMachine};
+
+// Drive `for i, v in ipairs(t)` from the host, `body` runs with each pair
+fn ipairs(vm: &mut VirtualMachine, t: &LuaValue, mut body: impl FnMut(f64, LuaValue)) -> Vec<f64> {
+    let ipairs = vm.environment.borrow().table_get(&s("ipairs")).unwrap();
+    let state = vm.call_value(&ipairs, vec![t.clone()]).unwrap();
+    let (f, mut control) = (state[0].clone(), state[2].clone());
+    let mut seen = vec![];
+    loop {
+        let results = vm.call_value(&f, vec![state[1].clone(), control]).unwrap();
+        let LuaValue::Number(i) = results[0] else { return seen };
+        seen.push(i.0);
+        body(i.0, results[1].clone());
+        control = results[0].clone();
+    }
+}
+
+#[test]
+fn ipairs_stops_at_the_first_hole() {
+    // {1, 2, nil, 4, [1.5] = "x", [-1] = "y", foo = "z"}
+    let mut t = LuaValue::new_table();
+    for (k, v) in [(n(1.0), n(1.0)), (n(2.0), n(2.0)), (n(4.0), n(4.0)), (n(1.5), s("x")), (n(-1.0), s("y")), (s("foo"), s("z"))] {
+        t.table_set(k, v).unwrap();
+    }
+    let mut values = vec![];
+    assert_eq!(ipairs(&mut vm(), &t, |_, v| values.push(v)), vec![1.0, 2.0]);
+    assert_eq!(values, vec![n(1.0), n(2.0)]);
+}
+
+#[test]
+fn ipairs_sees_writes_made_by_the_loop() {
+    let mut vm = vm();
+    let t = LuaValue::new_table();
+    for i in 1..=5 {
+        t.clone().table_set(n(i as f64), n(i as f64)).unwrap();
+    }
+
+    // t[i + 1] = nil ends the loop early
+    let seen = ipairs(&mut vm, &t, |i, _| t.clone().table_set(n(i + 1.0), LuaValue::Nil).unwrap());
+    assert_eq!(seen, vec![1.0]);
+
+    // Filling the hole joins the rest of the array, values replaced ahead of the loop are read fresh
+    t.clone().table_set(n(2.0), n(2.0)).unwrap();
+    let mut values = vec![];
+    let seen = ipairs(&mut vm, &t, |i, v| {
+        values.push(v);
+        if i < 4.0 {
+            t.clone().table_set(n(i + 1.0), n(10.0 * (i + 1.0))).unwrap();
+        }
+    });
+    assert_eq!(seen, vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+    assert_eq!(values, vec![n(1.0), n(20.0), n(30.0), n(40.0), n(5.0)]);
+
+    // Keys appended past the border the loop started with are visited
+    let seen = ipairs(&mut vm, &t, |i, _| if (5.0..7.0).contains(&i) {
+        t.clone().table_set(n(i + 1.0), n(i + 1.0)).unwrap();
+    });
+    assert_eq!(seen, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+}
+
+#[test]
+fn debug_tree_expands_nested_tables() {
+    // {1, name = "x", inner = {true}}
+    let (mut t, mut inner) = (LuaValue::new_table(), LuaValue::new_table());
+    inner.table_set(n(1.0), LuaValue::from(true)).unwrap();
+    t.table_set(n(1.0), n(1.0)).unwrap();
+    t.table_set(s("name"), s("x")).unwrap();
+    t.table_set(s("inner"), inner).unwrap();
+    let expected = r#"{
+  [1] = 1,
+  ["inner"] = {
+    [1] = true
+  },
+  ["name"] = "x"
+}"#;
+    assert_eq!(t.to_debug_tree(), expected);
+}
+
+#[test]
+fn display_string_matches_print() {
+    assert_eq!(n(3.0).to_display_string(), "3");
+    assert_eq!(n(0.1).to_display_string(), "0.1");
+    assert_eq!(LuaValue::Nil.to_display_string(), "nil");
+    assert_eq!(LuaValue::from(false).to_display_string(), "false");
+    assert_eq!(s("a\"b").to_display_string(), "a\"b");
+    assert!(LuaValue::new_table().to_display_string().starts_with("table: 0x"));
+}
+
+#[test]
+fn value_equal_keys_collide() {
+    // t.key = 1 t[0] = 2 return t["key"], t[-0]
+    // Each constant is its own Rc, the lookups only match by value
+    let chunk = chunk(&[s("key"), n(1.0), n(0.0), n(2.0), s("key"), n(-0.0)], &[
+        abc(NEWTABLE, 0, 0, 0),
+        abc(SETTABLE, 0, k(0), k(1)),
+        abc(SETTABLE, 0, k(2), k(3)),
+        abc(GETTABLE, 1, 0, k(4)),
+        abc(GETTABLE, 2, 0, k(5)),
+        abc(RETURN, 1, 3, 0)
+    ]);
+    assert_eq!(run(chunk).unwrap(), vec![n(1.0), n(2.0)]);
+}
+
+#[test]
+fn next_walks_every_entry_then_returns_nil() {
+    // {10, 20, x = 30}
+    let mut vm = vm();
+    let mut t = LuaValue::new_table();
+    for (k, v) in [(n(1.0), n(10.0)), (n(2.0), n(20.0)), (s("x"), n(30.0))] {
+        t.table_set(k, v).unwrap();
+    }
+
+    let mut key = LuaValue::Nil;
+    let mut pairs = vec![];
+    loop {
+        let results = call(&mut vm, "next", vec![t.clone(), key]).unwrap();
+        if results[0] == LuaValue::Nil {
+            assert_eq!(results.len(), 1);
+            break;
+        }
+        pairs.push((results[0].clone(), results[1].clone()));
+        key = results[0].clone();
+    }
+    assert_eq!(pairs, vec![(n(1.0), n(10.0)), (n(2.0), n(20.0)), (s("x"), n(30.0))]);
+
+    // A key removed during the traversal still works as a cursor
+    t.table_set(n(2.0), LuaValue::Nil).unwrap();
+    assert_eq!(call(&mut vm, "next", vec![t, n(2.0)]).unwrap(), vec![s("x"), n(30.0)]);
+}
+
+#[test]
+fn missing_key_reads_do_not_touch_the_table() {
+    // local t = {} local a = t.x a = 5 local b = t.x t.x = 7 return t, a, b, t.y
+    let chunk = chunk(&[s("x"), n(5.0), n(7.0), s("y")], &[
+        abc(NEWTABLE, 0, 0, 0),
+        abc(GETTABLE, 1, 0, k(0)),
+        abx(LOADK, 1, 1),
+        abc(GETTABLE, 2, 0, k(0)),
+        abc(SETTABLE, 0, k(0), k(2)),
+        abc(GETTABLE, 3, 0, k(3)),
+        abc(RETURN, 0, 5, 0)
+    ]);
+    let results = run(chunk).unwrap();
+    assert_eq!(results[1..], [n(5.0), LuaValue::Nil, LuaValue::Nil]);
+
+    // Only the written key is present
+    let t = &results[0];
+    assert_eq!(t.table_get(&s("x")).unwrap(), n(7.0));
+    let mut vm = vm();
+    assert_eq!(call(&mut vm, "next", vec![t.clone(), s("x")]).unwrap(), vec![LuaValue::Nil]);
+    assert_eq!(call(&mut vm, "next", vec![t.clone(), LuaValue::Nil]).unwrap()[0], s("x"));
+}
+
+#[test]
+fn table_iter_collects_a_table_built_in_lua() {
+    // return {"a", "b", k = "v"}
+    let chunk = chunk(&[s("a"), s("b"), s("k"), s("v")], &[
+        abc(NEWTABLE, 0, 2, 1),
+        abx(LOADK, 1, 0),
+        abx(LOADK, 2, 1),
+        abc(SETLIST, 0, 2, 1),
+        abc(SETTABLE, 0, k(2), k(3)),
+        abc(RETURN, 0, 2, 0)
+    ]);
+    let t = run(chunk).unwrap().remove(0);
+    let pairs: Vec<_> = t.table_iter().collect();
+    assert_eq!(pairs, vec![(n(1.0), s("a")), (n(2.0), s("b")), (s("k"), s("v"))]);
+    assert_eq!(n(1.0).table_iter().count(), 0);
+}
+
+#[test]
+fn deep_eq_compares_nested_contents() {
+    // {1, {x = "y"}} built twice
+    let build = || {
+        let (mut t, mut inner) = (LuaValue::new_table(), LuaValue::new_table());
+        inner.table_set(s("x"), s("y")).unwrap();
+        t.table_set(n(1.0), n(1.0)).unwrap();
+        t.table_set(n(2.0), inner).unwrap();
+        t
+    };
+    let (a, mut b) = (build(), build());
+    assert_ne!(a, b);
+    assert!(a.deep_eq(&b));
+
+    b.table_set(s("extra"), LuaValue::from(true)).unwrap();
+    assert!(!a.deep_eq(&b));
+
+    // Cycles terminate
+    let (mut c, mut d) = (LuaValue::new_table(), LuaValue::new_table());
+    c.table_set(s("self"), c.clone()).unwrap();
+    d.table_set(s("self"), d.clone()).unwrap();
+    assert!(c.deep_eq(&d));
+}
+
+#[test]
+fn setlist_takes_every_result_of_a_trailing_call() {
+    // local function f() return "a", "b", "c" end return {f()}
+    let f = function(&[s("a"), s("b"), s("c")], &[
+        abx(LOADK, 0, 0),
+        abx(LOADK, 1, 1),
+        abx(LOADK, 2, 2),
+        abc(RETURN, 0, 4, 0)
+    ]).build().unwrap();
+    let chunk = function(&[], &[
+        abc(NEWTABLE, 0, 0, 0),
+        abx(CLOSURE, 1, 0),
+        abc(CALL, 1, 1, 0),
+        abc(SETLIST, 0, 0, 1),
+        abc(RETURN, 0, 2, 0)
+    ]).prototype(f).vararg_flags(2).build().unwrap();
+    let t = run(chunk).unwrap().remove(0);
+    let pairs: Vec<_> = t.table_iter().collect();
+    assert_eq!(pairs, vec![(n(1.0), s("a")), (n(2.0), s("b")), (n(3.0), s("c"))]);
+}
+
+#[test]
+fn a_sixty_element_constructor_flushes_in_two_batches() {
+    // return {1, 2, ..., 60}, the first SETLIST stores 50 and the second the remaining 10
+    let constants: Vec<_> = (1..=60).map(|i| n(i as f64)).collect();
+    let mut code: Vec<_> = (0..50).map(|i| abx(LOADK, i + 1, i)).collect();
+    code.push(abc(SETLIST, 0, 50, 1));
+    code.extend((0..10).map(|i| abx(LOADK, i + 1, 50 + i)));
+    code.extend([abc(SETLIST, 0, 10, 2), abc(RETURN, 0, 2, 0)]);
+    code.insert(0, abc(NEWTABLE, 0, 0, 0));
+
+    let chunk = function(&constants, &code).max_stack_size(51).vararg_flags(2).build().unwrap();
+    let t = run(chunk).unwrap().remove(0);
+    for i in 1..=60 {
+        assert_eq!(t.table_get(&n(i as f64)).unwrap(), n(i as f64));
+    }
+    assert_eq!(t.table_iter().count(), 60);
+}
+
+#[test]
+fn integral_float_keys_are_array_indices() {
+    // local t = {"x"} local i = 0.5 + 0.5 local a = t[i] t[1.0] = "y" return a, t[i], #t
+    let chunk = chunk(&[s("x"), n(0.5), n(1.0), s("y")], &[
+        abc(NEWTABLE, 0, 1, 0),
+        abx(LOADK, 1, 0),
+        abc(SETLIST, 0, 1, 1),
+        abc(ADD, 1, k(1), k(1)),
+        abc(GETTABLE, 2, 0, 1),
+        abc(SETTABLE, 0, k(2), k(3)),
+        abc(GETTABLE, 3, 0, 1),
+        abc(LEN, 4, 0, 0),
+        abc(RETURN, 2, 4, 0)
+    ]);
+    assert_eq!(run(chunk).unwrap(), vec![s("x"), s("y"), n(1.0)]);
+}
+
+#[test]
+fn nil_and_nan_keys_are_rejected() {
+    // t[nil] = 1 / t[0/0] = 1
+    for key in [abc(LOADNIL, 1, 1, 0), abc(DIV, 1, k(0), k(0))] {
+        let chunk = chunk(&[n(0.0), n(1.0)], &[
+            abc(NEWTABLE, 0, 0, 0),
+            key,
+            abc(SETTABLE, 0, 1, k(1)),
+            abc(RETURN, 0, 1, 0)
+        ]);
+        assert!(matches!(run(chunk), Err(LuaError::InvalidTableIndex(_))));
+    }
+}
+
+#[test]
+fn host_built_tables_reach_scripts() {
+    // return config.name, config.size * 2
+    let mut config = LuaValue::new_table();
+    config.table_set(s("name"), s("demo")).unwrap();
+    config.table_set(s("size"), n(21.0)).unwrap();
+    assert_eq!(config.table_get(&s("name")).unwrap(), s("demo"));
+    assert_eq!(config.table_get(&s("missing")).unwrap(), LuaValue::Nil);
+
+    let mut vm = vm();
+    vm.environment.borrow_mut().table_set(s("config"), config).unwrap();
+    let chunk = chunk(&[s("config"), s("name"), s("size"), n(2.0)], &[
+        abx(GETGLOBAL, 0, 0),
+        abc(GETTABLE, 1, 0, k(1)),
+        abc(GETTABLE, 2, 0, k(2)),
+        abc(MUL, 2, 2, k(3)),
+        abc(RETURN, 1, 3, 0)
+    ]);
+    assert_eq!(run_in(&mut vm, chunk).unwrap(), vec![s("demo"), n(42.0)]);
+}
+
+#[test]
+fn overwriting_a_missing_read_leaves_the_table_alone() {
+    // local t = {} local v = t.missing v = 1 + 1 return v, t.missing, t
+    let chunk = chunk(&[s("missing"), n(1.0)], &[
+        abc(NEWTABLE, 0, 0, 0),
+        abc(GETTABLE, 1, 0, k(0)),
+        abc(ADD, 1, k(1), k(1)),
+        abc(GETTABLE, 2, 0, k(0)),
+        abc(MOVE, 3, 0, 0),
+        abc(RETURN, 1, 4, 0)
+    ]);
+    let results = run(chunk).unwrap();
+    assert_eq!(results[..2], [n(2.0), LuaValue::Nil]);
+    assert_eq!(results[2].table_iter().count(), 0);
+}
+
+#[test]
+fn next_follows_table_ordered_keys() {
+    // Inserted out of order: {b = 1, [10] = 2, a = 3, [2] = 4, [-1] = 5, [1.5] = 6}
+    let mut vm = vm();
+    let mut t = LuaValue::new_table();
+    for (k, v) in [(s("b"), 1.0), (n(10.0), 2.0), (s("a"), 3.0), (n(2.0), 4.0), (n(-1.0), 5.0), (n(1.5), 6.0)] {
+        t.table_set(k, n(v)).unwrap();
+    }
+    let keys = t.table_ordered_keys();
+    assert_eq!(keys, vec![n(-1.0), n(1.5), n(2.0), n(10.0), s("a"), s("b")]);
+
+    let mut visited = vec![];
+    let mut key = LuaValue::Nil;
+    loop {
+        key = call(&mut vm, "next", vec![t.clone(), key]).unwrap().remove(0);
+        if key == LuaValue::Nil {
+            break;
+        }
+        visited.push(key.clone());
+    }
+    assert_eq!(visited, keys);
+}